@@ -0,0 +1,275 @@
+//! Batches of spans nested in other containers, to exercise more sequence and option headers.
+
+use std::fmt;
+use std::str::FromStr;
+
+use postcard::from_bytes;
+use rand::Rng;
+use serde::{Serialize, Serializer};
+
+use crate::Span;
+
+/// A nested batch is split into at most this many inner batches.
+const MAX_INNER_BATCHES: usize = 8;
+
+/// The type the generated spans are encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    /// `Vec<Span>`.
+    Flat,
+    /// `Vec<Vec<Span>>`.
+    Nested,
+    /// `Option<Vec<Span>>`.
+    Optional,
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(shape: &str) -> Result<Self, Self::Err> {
+        match shape {
+            "flat" => Ok(Shape::Flat),
+            "nested" => Ok(Shape::Nested),
+            "optional" => Ok(Shape::Optional),
+            _ => Err("expected `flat`, `nested` or `optional`".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Shape::Flat => "flat",
+            Shape::Nested => "nested",
+            Shape::Optional => "optional",
+        })
+    }
+}
+
+/// Spans in one of the shapes of [`Shape`], encoded exactly like the wrapped value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Batch {
+    Flat(Vec<Span>),
+    Nested(Vec<Vec<Span>>),
+    Optional(Option<Vec<Span>>),
+}
+
+impl Batch {
+    /// Wraps `spans` in `shape`.
+    ///
+    /// Nested batches are split at random points, so inner batches may be empty. An empty outer
+    /// batch or `None` is drawn now and then, dropping the spans.
+    pub fn from_spans<R: Rng>(rng: &mut R, shape: Shape, spans: Vec<Span>) -> Batch {
+        match shape {
+            Shape::Flat => Batch::Flat(spans),
+            Shape::Nested => {
+                let inner_batches = rng.gen_range(0..=MAX_INNER_BATCHES);
+                if inner_batches == 0 {
+                    return Batch::Nested(Vec::new());
+                }
+                let mut splits: Vec<usize> = (1..inner_batches)
+                    .map(|_| rng.gen_range(0..=spans.len()))
+                    .collect();
+                splits.sort_unstable();
+                let mut rest = spans;
+                let mut nested = Vec::with_capacity(inner_batches);
+                for split in splits.into_iter().rev() {
+                    nested.push(rest.split_off(split));
+                }
+                nested.push(rest);
+                nested.reverse();
+                Batch::Nested(nested)
+            }
+            Shape::Optional => Batch::Optional(rng.gen_ratio(7, 8).then_some(spans)),
+        }
+    }
+
+    pub fn shape(&self) -> Shape {
+        match self {
+            Batch::Flat(_) => Shape::Flat,
+            Batch::Nested(_) => Shape::Nested,
+            Batch::Optional(_) => Shape::Optional,
+        }
+    }
+
+    /// All spans of the batch, in encoding order.
+    pub fn spans(&self) -> Vec<&Span> {
+        match self {
+            Batch::Flat(spans) | Batch::Optional(Some(spans)) => spans.iter().collect(),
+            Batch::Nested(nested) => nested.iter().flatten().collect(),
+            Batch::Optional(None) => Vec::new(),
+        }
+    }
+
+    /// A batch of the same shape holding `spans` instead, e.g. after shrinking.
+    ///
+    /// Inner batches of a nested batch are filled in order up to their current length, the
+    /// last one takes any spans left over. `None` stays `None` and drops the spans.
+    pub fn with_spans(&self, spans: Vec<Span>) -> Batch {
+        match self {
+            Batch::Flat(_) => Batch::Flat(spans),
+            Batch::Nested(nested) => {
+                let mut rest = spans.into_iter();
+                let mut refilled: Vec<Vec<Span>> = nested
+                    .iter()
+                    .map(|inner| rest.by_ref().take(inner.len()).collect())
+                    .collect();
+                if let Some(last) = refilled.last_mut() {
+                    last.extend(rest);
+                }
+                Batch::Nested(refilled)
+            }
+            Batch::Optional(Some(_)) => Batch::Optional(Some(spans)),
+            Batch::Optional(None) => Batch::Optional(None),
+        }
+    }
+
+    /// The lengths of the sequences in the batch, without the spans.
+    pub fn outline(&self) -> String {
+        match self {
+            Batch::Flat(spans) => format!("{} spans", spans.len()),
+            Batch::Nested(nested) => {
+                let lens: Vec<_> = nested.iter().map(Vec::len).collect();
+                format!("{lens:?} spans")
+            }
+            Batch::Optional(Some(spans)) => format!("Some({} spans)", spans.len()),
+            Batch::Optional(None) => "None".to_string(),
+        }
+    }
+
+    /// Decodes the postcard encoding of a batch of `shape`.
+    pub fn decode(shape: Shape, bytes: &[u8]) -> postcard::Result<Batch> {
+        Ok(match shape {
+            Shape::Flat => Batch::Flat(from_bytes(bytes)?),
+            Shape::Nested => Batch::Nested(from_bytes(bytes)?),
+            Shape::Optional => Batch::Optional(from_bytes(bytes)?),
+        })
+    }
+}
+
+impl Serialize for Batch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Batch::Flat(spans) => spans.serialize(serializer),
+            Batch::Nested(nested) => nested.serialize(serializer),
+            Batch::Optional(spans) => spans.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::test_spans;
+
+    const SHAPES: [Shape; 3] = [Shape::Flat, Shape::Nested, Shape::Optional];
+
+    #[track_caller]
+    fn assert_decodes(batch: &Batch) {
+        let encoded = postcard::to_allocvec(batch).unwrap();
+        assert_eq!(Batch::decode(batch.shape(), &encoded).as_ref(), Ok(batch));
+    }
+
+    #[test]
+    fn shapes_parse() {
+        for shape in SHAPES {
+            assert_eq!(shape.to_string().parse(), Ok(shape));
+        }
+        assert_eq!(
+            "list".parse::<Shape>(),
+            Err("expected `flat`, `nested` or `optional`".to_string())
+        );
+    }
+
+    #[test]
+    fn from_spans_keeps_the_spans() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let spans = test_spans(20);
+        for shape in SHAPES {
+            for _ in 0..50 {
+                let batch = Batch::from_spans(&mut rng, shape, spans.clone());
+                assert_eq!(batch.shape(), shape);
+                let kept: Vec<Span> = batch.spans().into_iter().cloned().collect();
+                match &batch {
+                    Batch::Nested(nested) if nested.is_empty() => assert!(kept.is_empty()),
+                    Batch::Nested(nested) => {
+                        assert!(nested.len() <= MAX_INNER_BATCHES);
+                        assert_eq!(kept, spans);
+                    }
+                    Batch::Optional(None) => assert!(kept.is_empty()),
+                    _ => assert_eq!(kept, spans),
+                }
+                assert_decodes(&batch);
+            }
+        }
+    }
+
+    #[test]
+    fn with_spans_keeps_the_shape() {
+        let spans = test_spans(6);
+        let nested = Batch::Nested(vec![spans[..2].to_vec(), Vec::new(), spans[2..].to_vec()]);
+        let fewer = nested.with_spans(spans[..3].to_vec());
+        assert_eq!(
+            fewer,
+            Batch::Nested(vec![spans[..2].to_vec(), Vec::new(), spans[2..3].to_vec()])
+        );
+        assert_eq!(fewer.outline(), "[2, 0, 1] spans");
+        let more = nested.with_spans(test_spans(8));
+        assert_eq!(more.outline(), "[2, 0, 6] spans");
+        assert_decodes(&more);
+
+        assert_eq!(
+            Batch::Flat(spans.clone()).with_spans(spans[..1].to_vec()),
+            Batch::Flat(spans[..1].to_vec())
+        );
+        assert_eq!(
+            Batch::Optional(Some(spans.clone())).with_spans(Vec::new()),
+            Batch::Optional(Some(Vec::new()))
+        );
+        assert_eq!(
+            Batch::Optional(None).with_spans(spans),
+            Batch::Optional(None)
+        );
+        assert_eq!(
+            Batch::Nested(Vec::new()).with_spans(test_spans(2)),
+            Batch::Nested(Vec::new())
+        );
+    }
+
+    #[test]
+    fn outlines() {
+        let spans = test_spans(3);
+        assert_eq!(Batch::Flat(spans.clone()).outline(), "3 spans");
+        assert_eq!(
+            Batch::Nested(vec![spans.clone(), Vec::new()]).outline(),
+            "[3, 0] spans"
+        );
+        assert_eq!(Batch::Optional(Some(spans)).outline(), "Some(3 spans)");
+        assert_eq!(Batch::Optional(None).outline(), "None");
+    }
+
+    #[test]
+    fn decode_checks_the_shape() {
+        let spans = test_spans(3);
+        for batch in [
+            Batch::Flat(spans.clone()),
+            Batch::Nested(vec![spans.clone(), Vec::new()]),
+            Batch::Optional(Some(spans)),
+            Batch::Optional(None),
+        ] {
+            assert_decodes(&batch);
+        }
+        // `None` is a single zero byte, which decodes as an empty flat batch too.
+        assert_eq!(
+            Batch::decode(Shape::Flat, &[0]),
+            Ok(Batch::Flat(Vec::new()))
+        );
+        assert_eq!(
+            Batch::decode(Shape::Optional, &[2]),
+            Err(postcard::Error::DeserializeBadOption)
+        );
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use postcard::{take_from_bytes, to_allocvec};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::mutate::{catch_quietly, panic_message, MutationStats};
+use crate::{varint, Span};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+/// Length of the canonical, raw encoding of a trace ID.
+const TRACE_ID_LEN: usize = 16;
+/// Index of the last base64 character carrying data bits of a legacy trace ID.
+const LAST_DATA_CHAR: usize = 21;
+
+/// Why bytes accepted by `from_bytes` are not what `to_allocvec` produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NonCanonical {
+    /// A varint uses more bytes than needed.
+    OverlongVarint { offset: usize, field: Field },
+    /// A trace ID uses the legacy base64 string instead of the raw bytes.
+    LegacyTraceId { offset: usize },
+    /// `from_bytes` ignored bytes after the sequence.
+    TrailingBytes { offset: usize, len: usize },
+    /// The re-encoded bytes differ for a reason not classified above.
+    Other { offset: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    SequenceLength,
+    TraceIdLength,
+    Timestamp,
+}
+
+impl NonCanonical {
+    /// Short label used to group findings in the run summary.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NonCanonical::OverlongVarint {
+                field: Field::SequenceLength,
+                ..
+            } => "overlong sequence length",
+            NonCanonical::OverlongVarint {
+                field: Field::TraceIdLength,
+                ..
+            } => "overlong trace ID length",
+            NonCanonical::OverlongVarint {
+                field: Field::Timestamp,
+                ..
+            } => "overlong timestamp",
+            NonCanonical::LegacyTraceId { .. } => "legacy base64 trace ID",
+            NonCanonical::TrailingBytes { .. } => "trailing bytes",
+            NonCanonical::Other { .. } => "other",
+        }
+    }
+}
+
+impl fmt::Display for NonCanonical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NonCanonical::OverlongVarint { offset, .. }
+            | NonCanonical::LegacyTraceId { offset }
+            | NonCanonical::Other { offset } => write!(f, "{} at byte {offset}", self.kind()),
+            NonCanonical::TrailingBytes { offset, len } => {
+                write!(f, "{len} trailing bytes at byte {offset}")
+            }
+        }
+    }
+}
+
+/// How `from_bytes` handled a buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanonicalOutcome {
+    Rejected,
+    Canonical,
+    NonCanonical(NonCanonical),
+    /// Decoding panicked, with the panic message.
+    Panicked(String),
+}
+
+/// Decodes `bytes` and checks that re-encoding the value reproduces them exactly.
+pub fn check_canonical(bytes: &[u8]) -> CanonicalOutcome {
+    check_canonical_by::<Vec<Span>>(bytes, classify)
+}
+
+/// Like [`check_canonical`], for an encoded `T` instead of a `Vec<Span>`.
+///
+/// Only trailing bytes are told apart, every other deviation is [`NonCanonical::Other`].
+pub fn check_canonical_of<T: Serialize + DeserializeOwned>(bytes: &[u8]) -> CanonicalOutcome {
+    check_canonical_by::<T>(bytes, |bytes, reencoded| NonCanonical::Other {
+        offset: first_difference(bytes, reencoded),
+    })
+}
+
+fn check_canonical_by<T>(
+    bytes: &[u8],
+    classify: fn(&[u8], &[u8]) -> NonCanonical,
+) -> CanonicalOutcome
+where
+    T: Serialize + DeserializeOwned,
+{
+    let decoded = catch_quietly(|| take_from_bytes::<T>(bytes));
+    let (value, rest) = match decoded {
+        Ok(Ok(decoded)) => decoded,
+        Ok(Err(_)) => return CanonicalOutcome::Rejected,
+        Err(payload) => return CanonicalOutcome::Panicked(panic_message(&*payload)),
+    };
+    let consumed = &bytes[..bytes.len() - rest.len()];
+    let reencoded = to_allocvec(&value).expect("a decoded value must serialize");
+    if reencoded == consumed {
+        if rest.is_empty() {
+            return CanonicalOutcome::Canonical;
+        }
+        return CanonicalOutcome::NonCanonical(NonCanonical::TrailingBytes {
+            offset: consumed.len(),
+            len: rest.len(),
+        });
+    }
+    CanonicalOutcome::NonCanonical(classify(consumed, &reencoded))
+}
+
+/// Offset of the first byte where `bytes` and `reencoded` differ, or where the shorter ends.
+fn first_difference(bytes: &[u8], reencoded: &[u8]) -> usize {
+    bytes
+        .iter()
+        .zip(reencoded)
+        .position(|(byte, canonical)| byte != canonical)
+        .unwrap_or(bytes.len().min(reencoded.len()))
+}
+
+/// Finds the first place where the encoded `Vec<Span>` `bytes` deviates from the canonical
+/// encoding `reencoded`.
+fn classify(bytes: &[u8], reencoded: &[u8]) -> NonCanonical {
+    let first_difference = first_difference(bytes, reencoded);
+    let Some(layout) = BatchLayout::parse(bytes) else {
+        return NonCanonical::Other {
+            offset: first_difference,
+        };
+    };
+    if !is_shortest_varint(bytes, &layout.len_prefix) {
+        return NonCanonical::OverlongVarint {
+            offset: layout.len_prefix.start,
+            field: Field::SequenceLength,
+        };
+    }
+    for span_layout in &layout.spans {
+        if !is_shortest_varint(bytes, &span_layout.trace_id_len) {
+            return NonCanonical::OverlongVarint {
+                offset: span_layout.trace_id_len.start,
+                field: Field::TraceIdLength,
+            };
+        }
+        if span_layout.trace_id.len() != TRACE_ID_LEN {
+            return NonCanonical::LegacyTraceId {
+                offset: span_layout.trace_id_len.start,
+            };
+        }
+        if !is_shortest_varint(bytes, &span_layout.timestamp) {
+            return NonCanonical::OverlongVarint {
+                offset: span_layout.timestamp.start,
+                field: Field::Timestamp,
+            };
+        }
+    }
+    NonCanonical::Other {
+        offset: first_difference,
+    }
+}
+
+fn is_shortest_varint(bytes: &[u8], range: &Range<usize>) -> bool {
+    varint::read(&bytes[range.clone()])
+        .is_some_and(|(value, len)| len == varint::encoded_len(value))
+}
+
+/// Byte ranges of the fields of an encoded `Vec<Span>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchLayout {
+    pub len_prefix: Range<usize>,
+    pub spans: Vec<SpanLayout>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanLayout {
+    pub trace_id_len: Range<usize>,
+    /// The raw bytes, or the legacy base64 characters, of the trace ID.
+    pub trace_id: Range<usize>,
+    pub timestamp: Range<usize>,
+}
+
+impl BatchLayout {
+    /// Walks an encoded `Vec<Span>` without decoding the values.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut offset = 0;
+        let take_varint = |offset: &mut usize| {
+            let (value, len) = varint::read(bytes.get(*offset..)?)?;
+            let range = *offset..*offset + len;
+            *offset += len;
+            Some((value, range))
+        };
+        let (num_spans, len_prefix) = take_varint(&mut offset)?;
+        let mut spans = Vec::new();
+        for _ in 0..num_spans {
+            let (trace_id_len, trace_id_len_range) = take_varint(&mut offset)?;
+            let trace_id = offset..offset.checked_add(usize::try_from(trace_id_len).ok()?)?;
+            if trace_id.end > bytes.len() {
+                return None;
+            }
+            offset = trace_id.end;
+            let (_, timestamp) = take_varint(&mut offset)?;
+            spans.push(SpanLayout {
+                trace_id_len: trace_id_len_range,
+                trace_id,
+                timestamp,
+            });
+        }
+        Some(BatchLayout { len_prefix, spans })
+    }
+}
+
+/// A deliberate deviation from the canonical encoding of a `Vec<Span>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    OverlongLength,
+    OverlongTimestamp {
+        span: usize,
+    },
+    /// The legacy base64 string instead of the raw trace ID.
+    LegacyTraceId {
+        span: usize,
+    },
+    /// A legacy base64 trace ID whose last data character has non-zero trailing bits.
+    LegacyTrailingBits {
+        span: usize,
+        bits: u8,
+    },
+    /// A legacy base64 trace ID with two data characters instead of the `==` padding.
+    LegacyPadding {
+        span: usize,
+        chars: [u8; 2],
+    },
+    TrailingBytes {
+        byte: u8,
+    },
+}
+
+impl Variant {
+    pub fn random<R: Rng>(rng: &mut R, layout: &BatchLayout) -> Self {
+        if layout.spans.is_empty() {
+            return if rng.gen() {
+                Variant::OverlongLength
+            } else {
+                Variant::TrailingBytes { byte: rng.gen() }
+            };
+        }
+        let span = rng.gen_range(0..layout.spans.len());
+        match rng.gen_range(0..6) {
+            0 => Variant::OverlongLength,
+            1 => Variant::OverlongTimestamp { span },
+            2 => Variant::LegacyTraceId { span },
+            3 => Variant::LegacyTrailingBits {
+                span,
+                bits: rng.gen_range(1..16),
+            },
+            4 => Variant::LegacyPadding {
+                span,
+                chars: [
+                    BASE64_ALPHABET[rng.gen_range(0..64)],
+                    BASE64_ALPHABET[rng.gen_range(0..64)],
+                ],
+            },
+            _ => Variant::TrailingBytes { byte: rng.gen() },
+        }
+    }
+
+    /// Whether the variant is malformed rather than just non-canonical, so the strict trace ID
+    /// decoding must reject it.
+    pub fn must_be_rejected(&self) -> bool {
+        matches!(
+            self,
+            Variant::LegacyTrailingBits { .. } | Variant::LegacyPadding { .. }
+        )
+    }
+
+    /// Applies the variant to the canonical `encoded` bytes.
+    ///
+    /// Returns `None` if the variant cannot be expressed, e.g. a varint that is already at its
+    /// maximum length.
+    pub fn apply(&self, encoded: &[u8], layout: &BatchLayout) -> Option<Vec<u8>> {
+        let mut bytes = encoded.to_vec();
+        match *self {
+            Variant::OverlongLength => {
+                replace_with_overlong_varint(&mut bytes, &layout.len_prefix)?
+            }
+            Variant::OverlongTimestamp { span } => {
+                replace_with_overlong_varint(&mut bytes, &layout.spans.get(span)?.timestamp)?
+            }
+            Variant::LegacyTraceId { span } => {
+                replace_with_legacy_trace_id(&mut bytes, layout.spans.get(span)?, |_| Some(()))?
+            }
+            Variant::LegacyTrailingBits { span, bits } => {
+                replace_with_legacy_trace_id(&mut bytes, layout.spans.get(span)?, |b64| {
+                    let sextet = BASE64_ALPHABET
+                        .iter()
+                        .position(|&c| c == b64[LAST_DATA_CHAR])?;
+                    b64[LAST_DATA_CHAR] = BASE64_ALPHABET[sextet | usize::from(bits & 0x0f)];
+                    Some(())
+                })?
+            }
+            Variant::LegacyPadding { span, chars } => {
+                replace_with_legacy_trace_id(&mut bytes, layout.spans.get(span)?, |b64| {
+                    b64[LAST_DATA_CHAR + 1..].copy_from_slice(&chars);
+                    Some(())
+                })?
+            }
+            Variant::TrailingBytes { byte } => bytes.push(byte),
+        }
+        Some(bytes)
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variant::OverlongLength => write!(f, "overlong sequence length"),
+            Variant::OverlongTimestamp { span } => write!(f, "overlong timestamp of span {span}"),
+            Variant::LegacyTraceId { span } => {
+                write!(f, "legacy base64 trace ID of span {span}")
+            }
+            Variant::LegacyTrailingBits { span, bits } => write!(
+                f,
+                "legacy base64 trace ID with trailing bits {bits:#06b} in span {span}"
+            ),
+            Variant::LegacyPadding { span, chars } => write!(
+                f,
+                "legacy base64 trace ID with padding `{}` in span {span}",
+                String::from_utf8_lossy(chars)
+            ),
+            Variant::TrailingBytes { byte } => write!(f, "trailing byte {byte:#04x}"),
+        }
+    }
+}
+
+fn replace_with_overlong_varint(bytes: &mut Vec<u8>, range: &Range<usize>) -> Option<()> {
+    let (value, _) = varint::read(&bytes[range.clone()])?;
+    bytes.splice(range.clone(), varint::write_overlong(value)?);
+    Some(())
+}
+
+/// Replaces the raw trace ID of `span` with its legacy base64 string, modified by `edit`.
+fn replace_with_legacy_trace_id(
+    bytes: &mut Vec<u8>,
+    span: &SpanLayout,
+    edit: impl FnOnce(&mut [u8]) -> Option<()>,
+) -> Option<()> {
+    let mut b64 = BASE64_STANDARD
+        .encode(bytes.get(span.trace_id.clone())?)
+        .into_bytes();
+    edit(&mut b64)?;
+    let mut legacy = varint::write(b64.len() as u64);
+    legacy.extend_from_slice(&b64);
+    bytes.splice(span.trace_id_len.start..span.trace_id.end, legacy);
+    Some(())
+}
+
+/// A non-canonical variant that was not handled as expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariantError {
+    pub variant: Variant,
+    pub bytes: Vec<u8>,
+    pub outcome: CanonicalOutcome,
+}
+
+impl fmt::Display for VariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            CanonicalOutcome::Panicked(message) => write!(f, "decoding panicked: {message}")?,
+            CanonicalOutcome::NonCanonical(non_canonical) if self.variant.must_be_rejected() => {
+                write!(f, "accepted a malformed base64 trace ID: {non_canonical}")?
+            }
+            CanonicalOutcome::NonCanonical(non_canonical) => {
+                write!(f, "accepted a non-canonical encoding: {non_canonical}")?
+            }
+            CanonicalOutcome::Rejected | CanonicalOutcome::Canonical => {
+                write!(f, "unexpected outcome {:?}", self.outcome)?
+            }
+        }
+        write!(
+            f,
+            "\n  variant: {}\n  bytes ({} bytes, base64): {}",
+            self.variant,
+            self.bytes.len(),
+            Base64Display::new(&self.bytes, &BASE64_STANDARD)
+        )
+    }
+}
+
+impl std::error::Error for VariantError {}
+
+/// Decodes `count` random non-canonical variants of the canonical `encoded` bytes.
+///
+/// Variants that are rejected or accepted are counted. Decoding panics, accepted variants that
+/// [must be rejected](Variant::must_be_rejected) and with `require_canonical` any accepted
+/// variant stop at the first offending variant.
+pub fn check_variants<R: Rng>(
+    rng: &mut R,
+    encoded: &[u8],
+    count: usize,
+    require_canonical: bool,
+) -> Result<MutationStats, VariantError> {
+    let layout = BatchLayout::parse(encoded).expect("to_allocvec output must have a valid layout");
+    let mut stats = MutationStats::default();
+    for _ in 0..count {
+        let variant = Variant::random(rng, &layout);
+        let Some(bytes) = variant.apply(encoded, &layout) else {
+            continue;
+        };
+        let outcome = check_canonical(&bytes);
+        match &outcome {
+            CanonicalOutcome::Rejected => stats.rejected += 1,
+            CanonicalOutcome::NonCanonical(_)
+                if !require_canonical && !variant.must_be_rejected() =>
+            {
+                stats.accepted += 1;
+                stats.canonical.record(&outcome);
+            }
+            _ => {
+                return Err(VariantError {
+                    variant,
+                    bytes,
+                    outcome,
+                })
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Non-canonical inputs that were accepted, grouped by `NonCanonical::kind`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanonicalStats {
+    pub checked: u64,
+    pub non_canonical: BTreeMap<&'static str, u64>,
+}
+
+impl CanonicalStats {
+    pub fn record(&mut self, outcome: &CanonicalOutcome) {
+        self.checked += 1;
+        if let CanonicalOutcome::NonCanonical(non_canonical) = outcome {
+            *self.non_canonical.entry(non_canonical.kind()).or_default() += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &CanonicalStats) {
+        self.checked += other.checked;
+        for (kind, count) in &other.non_canonical {
+            *self.non_canonical.entry(kind).or_default() += count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate::to_legacy_allocvec;
+    use crate::{test_span, TraceId};
+
+    fn span_layout(
+        trace_id_len: usize,
+        trace_id_bytes: usize,
+        timestamp_bytes: usize,
+    ) -> SpanLayout {
+        let trace_id = trace_id_len + 1..trace_id_len + 1 + trace_id_bytes;
+        SpanLayout {
+            trace_id_len: trace_id_len..trace_id_len + 1,
+            timestamp: trace_id.end..trace_id.end + timestamp_bytes,
+            trace_id,
+        }
+    }
+
+    #[test]
+    fn parse_empty_batch() {
+        assert_eq!(
+            BatchLayout::parse(&[0]),
+            Some(BatchLayout {
+                len_prefix: 0..1,
+                spans: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_known_encoding() {
+        // -1 zigzag encodes to 1, 64 to 128, which takes two bytes.
+        let encoded = to_allocvec(&vec![test_span(1, -1), test_span(2, 64)]).unwrap();
+        assert_eq!(encoded.len(), 1 + 18 + 19);
+        assert_eq!(
+            BatchLayout::parse(&encoded),
+            Some(BatchLayout {
+                len_prefix: 0..1,
+                spans: vec![span_layout(1, 16, 1), span_layout(19, 16, 2)],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_length_prefix_boundaries() {
+        for (len, prefix_len) in [(127, 1), (128, 2), (16383, 2), (16384, 3)] {
+            let encoded = to_allocvec(&vec![test_span(0, 0); len]).unwrap();
+            let layout = BatchLayout::parse(&encoded).unwrap();
+            assert_eq!(layout.len_prefix, 0..prefix_len);
+            assert_eq!(layout.spans.len(), len);
+            let last = layout.spans.last().unwrap();
+            assert_eq!(last.timestamp.end, encoded.len());
+        }
+    }
+
+    #[test]
+    fn parse_legacy_trace_ids() {
+        let encoded = to_legacy_allocvec(&[test_span(3, 0)]).unwrap();
+        assert_eq!(
+            BatchLayout::parse(&encoded),
+            Some(BatchLayout {
+                len_prefix: 0..1,
+                spans: vec![span_layout(1, TraceId::BASE64_LENGTH, 1)],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_ignores_trailing_bytes() {
+        let layout = BatchLayout::parse(&[0, 0xff, 0xff]).unwrap();
+        assert_eq!(layout.len_prefix, 0..1);
+        assert!(layout.spans.is_empty());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let encoded = to_allocvec(&vec![test_span(1, 300)]).unwrap();
+        assert_eq!(encoded.len(), 20);
+        assert!(BatchLayout::parse(&encoded).is_some());
+        // Cut off in the prefix, the trace ID and the timestamp.
+        assert_eq!(BatchLayout::parse(&[]), None);
+        assert_eq!(BatchLayout::parse(&encoded[..1]), None);
+        assert_eq!(BatchLayout::parse(&encoded[..10]), None);
+        assert_eq!(BatchLayout::parse(&encoded[..19]), None);
+        // More spans than the input holds.
+        assert_eq!(BatchLayout::parse(&[0x80, 0x01]), None);
+        assert_eq!(BatchLayout::parse(&[0xff; 9]), None);
+        // A trace ID longer than the input.
+        assert_eq!(BatchLayout::parse(&[1, 0xff, 0xff, 0xff, 0xff, 0x0f]), None);
+        // A length prefix that is not a valid varint.
+        assert_eq!(BatchLayout::parse(&[0x80; 11]), None);
+    }
+
+    #[test]
+    fn malformed_legacy_trace_ids_are_rejected() {
+        let spans = vec![test_span(0x5a, 1), test_span(0xff, -1)];
+        let encoded = to_allocvec(&spans).unwrap();
+        let layout = BatchLayout::parse(&encoded).unwrap();
+        let mut variants = Vec::new();
+        for span in 0..spans.len() {
+            variants.extend((1..16).map(|bits| Variant::LegacyTrailingBits { span, bits }));
+            variants.extend(BASE64_ALPHABET.iter().map(|&c| Variant::LegacyPadding {
+                span,
+                chars: [b'=', c],
+            }));
+            variants.push(Variant::LegacyPadding {
+                span,
+                chars: [b'A', b'A'],
+            });
+        }
+        for variant in variants {
+            assert!(variant.must_be_rejected());
+            let bytes = variant.apply(&encoded, &layout).unwrap();
+            assert_eq!(bytes.len(), encoded.len() + 8, "{variant}");
+            assert_eq!(
+                check_canonical(&bytes),
+                CanonicalOutcome::Rejected,
+                "{variant}"
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_trace_ids_are_only_non_canonical() {
+        let encoded = to_allocvec(&vec![test_span(0x5a, 1)]).unwrap();
+        let layout = BatchLayout::parse(&encoded).unwrap();
+        let variant = Variant::LegacyTraceId { span: 0 };
+        assert!(!variant.must_be_rejected());
+        let bytes = variant.apply(&encoded, &layout).unwrap();
+        assert!(matches!(
+            check_canonical(&bytes),
+            CanonicalOutcome::NonCanonical(NonCanonical::LegacyTraceId { .. })
+        ));
+    }
+
+    #[test]
+    fn check_variants_counts_every_variant() {
+        let encoded = to_allocvec(&vec![test_span(1, 300), test_span(2, -300)]).unwrap();
+        let mut rng = rand::rngs::mock::StepRng::new(0, 0x9e37_79b9_7f4a_7c15);
+        let stats = check_variants(&mut rng, &encoded, 200, false).unwrap();
+        assert!(stats.rejected > 0, "{stats:?}");
+        assert!(stats.accepted > 0, "{stats:?}");
+        assert!(check_variants(&mut rng, &encoded, 200, true).is_err());
+    }
+}
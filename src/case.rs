@@ -0,0 +1,486 @@
+//! Named round-trip cases, so the harness can check other types than `Vec<Span>`.
+//!
+//! Implement [`RoundTripCase`] for a wire type and [`Registry::register`] it to get the same
+//! generation, shrinking and reporting as the built-in cases.
+//!
+//! Every case supports the roundtrip, mutate and diagnose modes. The canonical, stream and
+//! concat modes, the nested and optional shapes and the corpus know the layout of an encoded
+//! `Vec<Span>` or store spans, they are only supported by the `span` case.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use postcard::{from_bytes, to_allocvec};
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::canonical::check_canonical_of;
+use crate::diagnose::{classify, diagnose, Diagnostic};
+use crate::generators::SpanGenerator;
+use crate::mutate::{fuzz_encoded, DecodeBounds, MutationStats};
+use crate::roundtrip::check_roundtrip_by;
+use crate::shrink::{shrink_values, simplify_datetime, simplify_span, simplify_trace_id};
+use crate::size::serialized_size;
+use crate::trace::{check_shapes, explain};
+use crate::{counting_alloc, data_model};
+use crate::{DateTime, Span, TraceId};
+
+/// Inner collections of the composite cases have at most this many elements.
+const MAX_INNER_LEN: usize = 8;
+
+/// A type checked by encoding random batches of it with postcard and decoding them again.
+pub trait RoundTripCase: Send + Sync + 'static {
+    type Value: Serialize + DeserializeOwned + PartialEq + Clone + fmt::Debug + Send + 'static;
+
+    /// The name the case is selected by with `--case`.
+    fn name(&self) -> &'static str;
+
+    /// Draws a random value, span fields come from `generator`.
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Self::Value;
+
+    /// Whether `actual` decoded from the encoding of `expected` counts as equal.
+    fn compare(&self, expected: &Self::Value, actual: &Self::Value) -> bool {
+        expected == actual
+    }
+
+    /// How a value is shown in failure reports.
+    fn describe(&self, value: &Self::Value) -> String {
+        format!("{value:?}")
+    }
+
+    /// Strictly simpler values to try when shrinking a failing batch, simplest first.
+    fn simplify(&self, _value: &Self::Value) -> Vec<Self::Value> {
+        Vec::new()
+    }
+
+    /// How deep sequences and maps nest in a value, not counting the batch. Decoding a mutated
+    /// batch may preallocate a buffer for every level.
+    fn nested_sequences(&self) -> usize {
+        0
+    }
+
+    /// Whether a value may encode to no bytes at all, which zero-sized types always do. The
+    /// length prefix of a mutated batch of such values is bounded before decoding.
+    fn empty_values(&self) -> bool {
+        std::mem::size_of::<Self::Value>() == 0
+    }
+}
+
+/// Counters of a batch that survived the round trip.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaseOutcome {
+    pub values: usize,
+    pub bytes: usize,
+    /// Allocations of encoding and decoding the batch, if they were counted.
+    pub encode_allocations: u64,
+    pub decode_allocations: u64,
+    /// The part of the allocations above made by the values, see
+    /// [`counting_alloc::measure_values`].
+    pub encode_value_allocations: u64,
+    pub decode_value_allocations: u64,
+    pub mutations: MutationStats,
+}
+
+/// A batch that did not survive the round trip, or uses serde features postcard cannot support.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseFailure {
+    pub error: String,
+    /// Why the batch is incompatible with postcard, if known.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The minimal failing batch and its error, if the batch was shrunk.
+    pub minimal: Option<String>,
+    /// The serde calls of the value that fails, see [`explain`].
+    pub trace: Option<String>,
+}
+
+/// Options of [`DynRoundTripCase::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseRun {
+    pub batch_len: RangeInclusive<usize>,
+    pub count_allocs: bool,
+    pub shrink: bool,
+    /// Also fail batches that round trip but use serde features postcard cannot support.
+    pub diagnose: bool,
+    /// Random mutations of the encoded batch to decode, see [`fuzz_encoded`].
+    pub mutations: usize,
+    pub require_canonical: bool,
+}
+
+/// The object-safe side of [`RoundTripCase`], implemented for every case.
+pub trait DynRoundTripCase: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Generates a batch, round trips it and shrinks it on failure, then decodes mutations of
+    /// its encoding.
+    fn run(
+        &self,
+        rng: &mut StdRng,
+        generator: &mut SpanGenerator,
+        options: &CaseRun,
+    ) -> Result<CaseOutcome, CaseFailure>;
+}
+
+impl<C: RoundTripCase> DynRoundTripCase for C {
+    fn name(&self) -> &'static str {
+        RoundTripCase::name(self)
+    }
+
+    fn run(
+        &self,
+        rng: &mut StdRng,
+        generator: &mut SpanGenerator,
+        options: &CaseRun,
+    ) -> Result<CaseOutcome, CaseFailure> {
+        let len = generator.batch_len(rng, options.batch_len.clone());
+        let values: Vec<C::Value> = (0..len).map(|_| self.generate(rng, generator)).collect();
+        let check = |values: &[C::Value]| {
+            check_roundtrip_by(values, |expected, actual| self.compare(expected, actual))
+        };
+        let encoded = match check(&values) {
+            Ok(encoded) => encoded,
+            Err(error) => {
+                let mut diagnostics = diagnose(&values);
+                diagnostics.extend(error.postcard_error().and_then(classify));
+                let minimal = options.shrink.then(|| {
+                    let minimal = shrink_values(
+                        &values,
+                        |candidate| check(candidate).is_err(),
+                        |value| self.simplify(value),
+                    );
+                    let minimal_error =
+                        check(&minimal).expect_err("shrinking must preserve the failure");
+                    let described: Vec<_> =
+                        minimal.iter().map(|value| self.describe(value)).collect();
+                    format!(
+                        "minimal failing batch ({} of {} values): [{}]\n  {minimal_error}",
+                        minimal.len(),
+                        values.len(),
+                        described.join(", ")
+                    )
+                });
+                // The first value that fails on its own, the whole batch may only fail together.
+                let culprit = values
+                    .iter()
+                    .find(|value| check(std::slice::from_ref(*value)).is_err())
+                    .or(values.first());
+                return Err(CaseFailure {
+                    error: error.to_string(),
+                    diagnostics: diagnostics.into_iter().collect(),
+                    minimal,
+                    trace: culprit.map(explain),
+                });
+            }
+        };
+        match serialized_size(&values) {
+            Ok(size) if size == encoded.len() => {}
+            computed => {
+                return Err(CaseFailure {
+                    error: format!(
+                        "serialized_size returned {computed:?}, to_allocvec encoded {} bytes",
+                        encoded.len()
+                    ),
+                    diagnostics: Vec::new(),
+                    minimal: None,
+                    trace: None,
+                })
+            }
+        }
+        if options.diagnose {
+            let diagnostics = diagnose(&values);
+            if !diagnostics.is_empty() {
+                return Err(CaseFailure {
+                    error: "the batch round trips, but uses serde features postcard cannot \
+                            support"
+                        .to_string(),
+                    diagnostics: diagnostics.into_iter().collect(),
+                    minimal: None,
+                    trace: None,
+                });
+            }
+            if let Some((value, error)) = values
+                .iter()
+                .find_map(|value| check_shapes(value).err().map(|error| (value, error)))
+            {
+                return Err(CaseFailure {
+                    error: format!(
+                        "the batch round trips, but the Deserialize impl requests other shapes \
+                         than the Serialize impl emits: {error}"
+                    ),
+                    diagnostics: Vec::new(),
+                    minimal: None,
+                    trace: Some(explain(value)),
+                });
+            }
+        }
+        let mut outcome = CaseOutcome {
+            values: values.len(),
+            bytes: encoded.len(),
+            ..CaseOutcome::default()
+        };
+        if options.count_allocs {
+            let (_, encode) = counting_alloc::measure(|| to_allocvec(&values));
+            let (_, decode) = counting_alloc::measure(|| from_bytes::<Vec<C::Value>>(&encoded));
+            outcome.encode_allocations = encode.allocations;
+            outcome.decode_allocations = decode.allocations;
+            let (encode, decode) = counting_alloc::measure_values(&values);
+            outcome.encode_value_allocations = encode.allocations;
+            outcome.decode_value_allocations = decode.allocations;
+        }
+        if options.mutations > 0 {
+            outcome.mutations = fuzz_encoded::<C::Value, _>(
+                rng,
+                &encoded,
+                options.mutations,
+                options.require_canonical,
+                check_canonical_of::<Vec<C::Value>>,
+                DecodeBounds {
+                    nested_sequences: self.nested_sequences(),
+                    empty_values: self.empty_values(),
+                },
+            )
+            .map_err(|error| CaseFailure {
+                error: error.to_string(),
+                diagnostics: Vec::new(),
+                minimal: None,
+                trace: None,
+            })?;
+        }
+        Ok(outcome)
+    }
+}
+
+/// The cases selectable with `--case`, by name.
+#[derive(Default)]
+pub struct Registry {
+    cases: BTreeMap<&'static str, Box<dyn DynRoundTripCase>>,
+    /// Names that select several cases at once.
+    groups: BTreeMap<&'static str, Vec<&'static str>>,
+}
+
+impl Registry {
+    /// A registry with the cases of this crate's types.
+    pub fn builtin() -> Self {
+        let mut registry = Registry::default();
+        registry.register(SpanCase);
+        registry.register(TraceIdCase);
+        registry.register(DateTimeCase);
+        registry.register(SpansCase);
+        registry.register(OptionalSpanCase);
+        registry.register(SpanMapCase);
+        data_model::register(&mut registry);
+        registry
+    }
+
+    /// Adds `case`, replacing a case of the same name.
+    pub fn register<C: RoundTripCase>(&mut self, case: C) {
+        self.cases
+            .insert(RoundTripCase::name(&case), Box::new(case));
+    }
+
+    /// Makes `name` select all of `cases`, which must be registered.
+    pub fn register_group(&mut self, name: &'static str, cases: Vec<&'static str>) {
+        self.groups.insert(name, cases);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn DynRoundTripCase> {
+        self.cases.get(name).map(|case| &**case)
+    }
+
+    /// The case named `name`, or the cases of the group named `name`.
+    pub fn resolve(&self, name: &str) -> Option<Vec<&dyn DynRoundTripCase>> {
+        match self.groups.get(name) {
+            Some(cases) => cases.iter().map(|case| self.get(case)).collect(),
+            None => self.get(name).map(|case| vec![case]),
+        }
+    }
+
+    /// The names of all cases.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.cases.keys().copied()
+    }
+
+    /// The names of all groups.
+    pub fn group_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.groups.keys().copied()
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("cases", &self.names().collect::<Vec<_>>())
+            .field("groups", &self.groups)
+            .finish()
+    }
+}
+
+/// A `Span`.
+#[derive(Debug, Clone, Copy)]
+pub struct SpanCase;
+
+impl RoundTripCase for SpanCase {
+    type Value = Span;
+
+    fn name(&self) -> &'static str {
+        "span"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Span {
+        Span::random(rng, generator)
+    }
+
+    fn simplify(&self, span: &Span) -> Vec<Span> {
+        simplify_span(span)
+    }
+}
+
+/// A bare `TraceId`.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceIdCase;
+
+impl RoundTripCase for TraceIdCase {
+    type Value = TraceId;
+
+    fn name(&self) -> &'static str {
+        "trace-id"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> TraceId {
+        generator.trace_id(rng)
+    }
+
+    fn describe(&self, trace_id: &TraceId) -> String {
+        trace_id.base64_display().to_string()
+    }
+
+    fn simplify(&self, trace_id: &TraceId) -> Vec<TraceId> {
+        simplify_trace_id(*trace_id)
+    }
+}
+
+/// A `DateTime` encoded like `Span::span_timestamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamp(#[serde(with = "crate::serde_datetime")] pub DateTime);
+
+/// A bare `DateTime`, encoded like `Span::span_timestamp`.
+#[derive(Debug, Clone, Copy)]
+pub struct DateTimeCase;
+
+impl RoundTripCase for DateTimeCase {
+    type Value = Timestamp;
+
+    fn name(&self) -> &'static str {
+        "date-time"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Timestamp {
+        Timestamp(generator.timestamp(rng))
+    }
+
+    fn describe(&self, timestamp: &Timestamp) -> String {
+        timestamp.0.into_timestamp_nanos().to_string()
+    }
+
+    fn simplify(&self, timestamp: &Timestamp) -> Vec<Timestamp> {
+        simplify_datetime(timestamp.0)
+            .into_iter()
+            .map(Timestamp)
+            .collect()
+    }
+}
+
+/// A `Vec<Span>` of up to `MAX_INNER_LEN` spans, so batches are `Vec<Vec<Span>>`.
+#[derive(Debug, Clone, Copy)]
+pub struct SpansCase;
+
+impl RoundTripCase for SpansCase {
+    type Value = Vec<Span>;
+
+    fn name(&self) -> &'static str {
+        "spans"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Vec<Span> {
+        let len = rng.gen_range(0..=MAX_INNER_LEN);
+        (0..len).map(|_| Span::random(rng, generator)).collect()
+    }
+
+    fn nested_sequences(&self) -> usize {
+        1
+    }
+
+    fn simplify(&self, spans: &Vec<Span>) -> Vec<Vec<Span>> {
+        (0..spans.len())
+            .map(|index| {
+                let mut fewer = spans.clone();
+                fewer.remove(index);
+                fewer
+            })
+            .collect()
+    }
+}
+
+/// An `Option<Span>`, `None` for about a quarter of the values.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionalSpanCase;
+
+impl RoundTripCase for OptionalSpanCase {
+    type Value = Option<Span>;
+
+    fn name(&self) -> &'static str {
+        "optional-span"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Option<Span> {
+        rng.gen_ratio(3, 4).then(|| Span::random(rng, generator))
+    }
+
+    fn simplify(&self, span: &Option<Span>) -> Vec<Option<Span>> {
+        match span {
+            Some(span) => std::iter::once(None)
+                .chain(simplify_span(span).into_iter().map(Some))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A `BTreeMap<TraceId, Span>` of up to `MAX_INNER_LEN` spans keyed by their trace ID.
+#[derive(Debug, Clone, Copy)]
+pub struct SpanMapCase;
+
+impl RoundTripCase for SpanMapCase {
+    type Value = BTreeMap<TraceId, Span>;
+
+    fn name(&self) -> &'static str {
+        "span-map"
+    }
+
+    fn generate(&self, rng: &mut StdRng, generator: &mut SpanGenerator) -> Self::Value {
+        let len = rng.gen_range(0..=MAX_INNER_LEN);
+        (0..len)
+            .map(|_| {
+                let span = Span::random(rng, generator);
+                (span.trace_id, span)
+            })
+            .collect()
+    }
+
+    fn nested_sequences(&self) -> usize {
+        1
+    }
+
+    fn simplify(&self, spans: &Self::Value) -> Vec<Self::Value> {
+        spans
+            .keys()
+            .map(|trace_id| {
+                let mut fewer = spans.clone();
+                fewer.remove(trace_id);
+                fewer
+            })
+            .collect()
+    }
+}
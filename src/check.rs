@@ -0,0 +1,122 @@
+//! The checks the roundtrip mode runs on a batch, with one error type for all of them.
+
+use std::fmt;
+
+use rand::Rng;
+
+use crate::batch::Batch;
+use crate::codec::{check_codecs, check_to_slice, CodecError, SliceError};
+use crate::migrate::{check_legacy_compat, LegacyError};
+use crate::roundtrip::{check_batch, check_roundtrip, RoundTripError};
+use crate::size::{check_sizes, SizeError};
+use crate::span_batch::{check_span_batch, SpanBatchCheckError};
+use crate::Span;
+
+/// The first check a batch failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckError {
+    RoundTrip(RoundTripError),
+    Legacy(LegacyError),
+    Codec(CodecError),
+    Slice(SliceError),
+    SpanBatch(SpanBatchCheckError),
+    Size(SizeError),
+}
+
+impl CheckError {
+    /// The error postcard returned, if the round trip itself failed to encode or decode.
+    pub fn postcard_error(&self) -> Option<&postcard::Error> {
+        match self {
+            CheckError::RoundTrip(error) => error.postcard_error(),
+            _ => None,
+        }
+    }
+
+    /// Describes the failure without the dumps of encoded bytes.
+    pub fn message(&self) -> String {
+        match self {
+            CheckError::RoundTrip(error) => error.message(),
+            // The dumps follow on lines of their own.
+            _ => {
+                let error = self.to_string();
+                error.lines().next().unwrap_or_default().to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::RoundTrip(error) => write!(f, "{error}"),
+            CheckError::Legacy(error) => write!(f, "{error}"),
+            CheckError::Codec(error) => write!(f, "{error}"),
+            CheckError::Slice(error) => write!(f, "{error}"),
+            CheckError::SpanBatch(error) => write!(f, "{error}"),
+            CheckError::Size(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+impl From<RoundTripError> for CheckError {
+    fn from(error: RoundTripError) -> Self {
+        CheckError::RoundTrip(error)
+    }
+}
+
+impl From<LegacyError> for CheckError {
+    fn from(error: LegacyError) -> Self {
+        CheckError::Legacy(error)
+    }
+}
+
+impl From<CodecError> for CheckError {
+    fn from(error: CodecError) -> Self {
+        CheckError::Codec(error)
+    }
+}
+
+impl From<SliceError> for CheckError {
+    fn from(error: SliceError) -> Self {
+        CheckError::Slice(error)
+    }
+}
+
+impl From<SpanBatchCheckError> for CheckError {
+    fn from(error: SpanBatchCheckError) -> Self {
+        CheckError::SpanBatch(error)
+    }
+}
+
+impl From<SizeError> for CheckError {
+    fn from(error: SizeError) -> Self {
+        CheckError::Size(error)
+    }
+}
+
+/// Round trips `spans`, then checks the legacy encoding, every codec, `to_slice` and the span
+/// batch reader and writer.
+///
+/// `rng` only picks the `to_slice` buffer sizes, an RNG seeded the same way repeats the checks
+/// exactly, e.g. while shrinking. Returns the encoded bytes on success.
+pub fn check_spans<R: Rng>(rng: &mut R, spans: &[Span]) -> Result<Vec<u8>, CheckError> {
+    let encoded = check_roundtrip(spans)?;
+    check_legacy_compat(spans)?;
+    check_codecs(spans)?;
+    check_to_slice(rng, spans)?;
+    check_span_batch(spans)?;
+    Ok(encoded)
+}
+
+/// Runs [`check_spans`] on a flat batch and only the round trip on the other shapes, then
+/// checks the computed sizes.
+pub fn check_all<R: Rng>(rng: &mut R, batch: &Batch) -> Result<Vec<u8>, CheckError> {
+    let encoded = match batch {
+        Batch::Flat(spans) => check_spans(rng, spans)?,
+        _ => check_batch(batch)?,
+    };
+    check_sizes(batch, &encoded)?;
+    Ok(encoded)
+}
@@ -0,0 +1,492 @@
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::batch::Shape;
+use crate::generators::{LengthStrategy, TimestampStrategy, TraceIdStrategy};
+
+pub const USAGE: &str = "\
+Usage: force_check_postcard [OPTIONS]
+       force_check_postcard replay <DIR>
+       force_check_postcard migrate <IN> <OUT>
+
+Commands:
+  replay <DIR>       re-run the checks of every corpus entry in DIR and exit
+  migrate <IN> <OUT> rewrite the postcard `Vec<Span>` in IN, which may use base64 trace IDs,
+                     with raw trace IDs to OUT and exit
+
+Options:
+  --iterations <N>   stop after N iterations (default: unbounded)
+  --duration <TIME>  stop after TIME, e.g. `90`, `90s`, `15m`, `2h` (default: unbounded)
+  --seed <SEED>      master seed the per-iteration seeds are derived from (default: random)
+  --replay <SEED>    run the single iteration with this seed and exit
+  --case <NAME>      type to round trip: `span`, `trace-id`, `date-time`, `spans`,
+                     `optional-span`, `span-map`, one of the serde data model types `unsigned`,
+                     `signed`, `f32`, `f64`, `char`, `zero-sized`, `string`, `bytes`, `enum`,
+                     `option`, `nested-seq` and `map`, or `data-model` for all of those. Cases
+                     other than `span` only support the roundtrip, mutate and diagnose modes,
+                     neither --shape nor --corpus, and their roundtrip mode only checks the
+                     round trip and serialized_size (default: span)
+  --min-len <N>      minimum number of values per batch (default: 0)
+  --max-len <N>      maximum number of values per batch (default: 10000)
+  --lengths <STRATEGY>
+                     how batch lengths are drawn: `fixed:<N>`, `uniform` within the bounds above,
+                     `boundary` lengths next to 127/128 and 16383/16384, `log-uniform` within the
+                     bounds above, e.g. up to millions with `--max-len 5000000`, or `mixed`
+                     (default: uniform)
+  --shape <SHAPE>    encode the spans as `flat` `Vec<Span>`, `nested` `Vec<Vec<Span>>` or
+                     `optional` `Option<Vec<Span>>`, only `flat` batches are supported by the
+                     other modes (default: flat)
+  --timestamps <STRATEGY>
+                     how span timestamps are drawn: `uniform` over all of i64,
+                     `varint-boundary`, `near-now`, `edge` values, or `mixed` (default: mixed)
+  --trace-ids <STRATEGY>
+                     how trace IDs are drawn: `uniform`, `zero`, `max`, `sequential`,
+                     `low-entropy`, `w3c`, `base64-heavy`, `one-bit-apart`, `duplicates`,
+                     or `mixed` (default: mixed)
+  --threads <N>      number of worker threads (default: available parallelism)
+  --mode <MODE>      what every iteration checks (default: roundtrip):
+                       roundtrip  the round trip, that every postcard entry point encodes the
+                                  batch alike and serde_json decodes it alike, that `to_slice`
+                                  cleanly rejects too small buffers and that the batch is written
+                                  and read span by span alike
+                       mutate     decoding corrupted encodings of the batch
+                       canonical  decoding non-canonical encodings of the batch, `span` only
+                       diagnose   serde features postcard cannot support, like
+                                  skip_serializing_if, flatten or untagged enums, and that the
+                                  Deserialize impl of every value requests the shapes its
+                                  Serialize impl emits
+                       stream     recovering COBS frames of several batches sent in random
+                                  chunks, some of them corrupted, `span` only
+                       concat     decoding several batches encoded back to back one after the
+                                  other with `take_from_bytes`, `span` only
+  --mutations <N>    mutations or non-canonical variants decoded per batch (default: 100)
+  --require-canonical
+                     fail when a non-canonical encoding is accepted instead of counting it
+  --count-allocs     count the allocations of encoding and decoding every batch, reported
+                     separately for the values and for the sequences around them
+  --max-allocs-per-value <N>
+                     fail when encoding or decoding the values on their own makes more than N
+                     allocations per value over the whole run, the sequences around them do not
+                     count, implies --count-allocs
+  --keep-going       keep running after a failure instead of stopping at the first one, except
+                     after a decoding hang
+  --no-shrink        do not shrink failing batches to a minimal failing batch
+  --corpus <DIR>     write every failing batch to DIR
+  -h, --help         print this help";
+
+/// What every iteration checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Round trip the generated batch.
+    RoundTrip,
+    /// Decode randomly corrupted encodings of the generated batch.
+    Mutate,
+    /// Decode deliberately non-canonical encodings of the generated batch.
+    Canonical,
+    /// Round trip the generated batch and report serde features postcard cannot support.
+    Diagnose,
+    /// Send the generated batch and more in a chunked, partly corrupted COBS stream.
+    Stream,
+    /// Decode the generated batch and more from one buffer, one after the other.
+    Concat,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "roundtrip" => Ok(Mode::RoundTrip),
+            "mutate" => Ok(Mode::Mutate),
+            "canonical" => Ok(Mode::Canonical),
+            "diagnose" => Ok(Mode::Diagnose),
+            "stream" => Ok(Mode::Stream),
+            "concat" => Ok(Mode::Concat),
+            _ => Err(
+                "expected `roundtrip`, `mutate`, `canonical`, `diagnose`, `stream` or `concat`"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mode::RoundTrip => "roundtrip",
+            Mode::Mutate => "mutate",
+            Mode::Canonical => "canonical",
+            Mode::Diagnose => "diagnose",
+            Mode::Stream => "stream",
+            Mode::Concat => "concat",
+        })
+    }
+}
+
+/// Options of the stress loop.
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub iterations: Option<u64>,
+    pub duration: Option<Duration>,
+    pub seed: Option<u64>,
+    pub replay: Option<u64>,
+    /// Name of the `RoundTripCase` to check.
+    pub case: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub lengths: LengthStrategy,
+    pub shape: Shape,
+    pub timestamps: TimestampStrategy,
+    pub trace_ids: TraceIdStrategy,
+    pub threads: NonZeroUsize,
+    pub mode: Mode,
+    pub mutations: usize,
+    pub require_canonical: bool,
+    pub count_allocs: bool,
+    pub max_allocs_per_value: Option<f64>,
+    pub keep_going: bool,
+    pub no_shrink: bool,
+    pub corpus: Option<PathBuf>,
+    pub replay_corpus: Option<PathBuf>,
+    /// Input and output file of the `migrate` command.
+    pub migrate: Option<(PathBuf, PathBuf)>,
+    pub help: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            iterations: None,
+            duration: None,
+            seed: None,
+            replay: None,
+            case: "span".to_string(),
+            min_len: 0,
+            max_len: 10000,
+            lengths: LengthStrategy::Uniform,
+            shape: Shape::Flat,
+            timestamps: TimestampStrategy::Mixed,
+            trace_ids: TraceIdStrategy::Mixed,
+            threads: std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            mode: Mode::RoundTrip,
+            mutations: 100,
+            require_canonical: false,
+            count_allocs: false,
+            max_allocs_per_value: None,
+            keep_going: false,
+            no_shrink: false,
+            corpus: None,
+            replay_corpus: None,
+            migrate: None,
+            help: false,
+        }
+    }
+}
+
+impl Args {
+    pub fn batch_len(&self) -> RangeInclusive<usize> {
+        self.min_len..=self.max_len
+    }
+
+    /// The flags needed to regenerate the batch of `seed` bit-for-bit.
+    pub fn replay_flags(&self, seed: u64) -> String {
+        let mut flags = format!(
+            "--replay {seed} --case {} --min-len {} --max-len {} --lengths {} --shape {} \
+             --timestamps {} --trace-ids {} --mode {}",
+            self.case,
+            self.min_len,
+            self.max_len,
+            self.lengths,
+            self.shape,
+            self.timestamps,
+            self.trace_ids,
+            self.mode
+        );
+        if matches!(self.mode, Mode::Mutate | Mode::Canonical) {
+            flags.push_str(&format!(" --mutations {}", self.mutations));
+        }
+        if self.require_canonical {
+            flags.push_str(" --require-canonical");
+        }
+        flags
+    }
+
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ArgsError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ArgsError::MissingValue(arg.clone()))
+            };
+            match arg.as_str() {
+                "--iterations" => parsed.iterations = Some(parse_value(&arg, &value()?)?),
+                "--duration" => parsed.duration = Some(parse_duration(&arg, &value()?)?),
+                "--seed" => parsed.seed = Some(parse_value(&arg, &value()?)?),
+                "--replay" => parsed.replay = Some(parse_value(&arg, &value()?)?),
+                "--case" => parsed.case = value()?,
+                "--min-len" => parsed.min_len = parse_value(&arg, &value()?)?,
+                "--max-len" => parsed.max_len = parse_value(&arg, &value()?)?,
+                "--lengths" => parsed.lengths = parse_value(&arg, &value()?)?,
+                "--shape" => parsed.shape = parse_value(&arg, &value()?)?,
+                "--timestamps" => parsed.timestamps = parse_value(&arg, &value()?)?,
+                "--trace-ids" => parsed.trace_ids = parse_value(&arg, &value()?)?,
+                "--threads" => parsed.threads = parse_value(&arg, &value()?)?,
+                "--mode" => parsed.mode = parse_value(&arg, &value()?)?,
+                "--mutations" => parsed.mutations = parse_value(&arg, &value()?)?,
+                "--require-canonical" => parsed.require_canonical = true,
+                "--count-allocs" => parsed.count_allocs = true,
+                "--max-allocs-per-value" => {
+                    parsed.max_allocs_per_value = Some(parse_value(&arg, &value()?)?);
+                    parsed.count_allocs = true;
+                }
+                "--keep-going" => parsed.keep_going = true,
+                "--no-shrink" => parsed.no_shrink = true,
+                "--corpus" => parsed.corpus = Some(value()?.into()),
+                "replay" => parsed.replay_corpus = Some(value()?.into()),
+                "migrate" => parsed.migrate = Some((value()?.into(), value()?.into())),
+                "-h" | "--help" => parsed.help = true,
+                _ => return Err(ArgsError::UnknownArgument(arg)),
+            }
+        }
+        if parsed.min_len > parsed.max_len {
+            return Err(ArgsError::InvalidValue {
+                flag: "--min-len".to_string(),
+                value: parsed.min_len.to_string(),
+                reason: format!("must not exceed --max-len {}", parsed.max_len),
+            });
+        }
+        if parsed.case != "span" {
+            // These walk the layout of an encoded `Vec<Span>` or store spans.
+            if matches!(parsed.mode, Mode::Canonical | Mode::Stream | Mode::Concat) {
+                return Err(ArgsError::InvalidValue {
+                    flag: "--mode".to_string(),
+                    value: parsed.mode.to_string(),
+                    reason: format!("only supported by the `span` case, not `{}`", parsed.case),
+                });
+            }
+            if parsed.shape != Shape::Flat {
+                return Err(ArgsError::InvalidValue {
+                    flag: "--shape".to_string(),
+                    value: parsed.shape.to_string(),
+                    reason: format!("only supported by the `span` case, not `{}`", parsed.case),
+                });
+            }
+            if let Some(corpus) = &parsed.corpus {
+                return Err(ArgsError::InvalidValue {
+                    flag: "--corpus".to_string(),
+                    value: corpus.display().to_string(),
+                    reason: format!(
+                        "the corpus only records batches of the `span` case, not `{}`",
+                        parsed.case
+                    ),
+                });
+            }
+        }
+        if parsed.shape != Shape::Flat && parsed.mode != Mode::RoundTrip {
+            return Err(ArgsError::InvalidValue {
+                flag: "--shape".to_string(),
+                value: parsed.shape.to_string(),
+                reason: format!("not supported by the {} mode", parsed.mode),
+            });
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, ArgsError>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error: T::Err| ArgsError::InvalidValue {
+            flag: flag.to_string(),
+            value: value.to_string(),
+            reason: error.to_string(),
+        })
+}
+
+fn parse_duration(flag: &str, value: &str) -> Result<Duration, ArgsError> {
+    let (number, unit_secs) = match value.as_bytes().last() {
+        Some(b's') => (&value[..value.len() - 1], 1),
+        Some(b'm') => (&value[..value.len() - 1], 60),
+        Some(b'h') => (&value[..value.len() - 1], 60 * 60),
+        _ => (value, 1),
+    };
+    let number: u64 = parse_value(flag, number)?;
+    Ok(Duration::from_secs(number.saturating_mul(unit_secs)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue {
+        flag: String,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::UnknownArgument(arg) => write!(f, "unknown argument `{arg}`"),
+            ArgsError::MissingValue(flag) => write!(f, "missing value for `{flag}`"),
+            ArgsError::InvalidValue {
+                flag,
+                value,
+                reason,
+            } => write!(f, "invalid value `{value}` for `{flag}`: {reason}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn invalid(flag: &str, value: &str, reason: &str) -> ArgsError {
+        ArgsError::InvalidValue {
+            flag: flag.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        for (value, secs) in [
+            ("90", 90),
+            ("90s", 90),
+            ("15m", 900),
+            ("2h", 7200),
+            ("0", 0),
+        ] {
+            assert_eq!(
+                parse_duration("--duration", value),
+                Ok(Duration::from_secs(secs)),
+                "{value}"
+            );
+        }
+        assert_eq!(
+            parse_duration("--duration", &format!("{}h", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+        for value in ["", "s", "1d", "-1s", "1.5h"] {
+            assert!(
+                matches!(
+                    parse_duration("--duration", value),
+                    Err(ArgsError::InvalidValue { .. })
+                ),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn defaults_and_flags() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.case, "span");
+        assert_eq!(args.batch_len(), 0..=10000);
+        assert_eq!(args.mode, Mode::RoundTrip);
+
+        let args = parse(&[
+            "--duration",
+            "15m",
+            "--min-len",
+            "3",
+            "--max-len",
+            "3",
+            "--lengths",
+            "fixed:7",
+            "--mode",
+            "mutate",
+            "--max-allocs-per-value",
+            "2.5",
+        ])
+        .unwrap();
+        assert_eq!(args.duration, Some(Duration::from_secs(900)));
+        assert_eq!(args.batch_len(), 3..=3);
+        assert_eq!(args.lengths, LengthStrategy::Fixed(7));
+        assert_eq!(args.mode, Mode::Mutate);
+        assert_eq!(args.max_allocs_per_value, Some(2.5));
+        assert!(args.count_allocs);
+    }
+
+    #[test]
+    fn unknown_and_incomplete_flags() {
+        assert_eq!(
+            parse(&["--iterations", "1", "--verbose"]).err(),
+            Some(ArgsError::UnknownArgument("--verbose".to_string()))
+        );
+        assert_eq!(
+            parse(&["--seed"]).err(),
+            Some(ArgsError::MissingValue("--seed".to_string()))
+        );
+        assert_eq!(
+            parse(&["migrate", "in"]).err(),
+            Some(ArgsError::MissingValue("migrate".to_string()))
+        );
+        assert_eq!(
+            parse(&["--mode", "fuzz"]).err(),
+            Some(invalid(
+                "--mode",
+                "fuzz",
+                "expected `roundtrip`, `mutate`, `canonical`, `diagnose`, `stream` or `concat`"
+            ))
+        );
+    }
+
+    #[test]
+    fn min_len_above_max_len() {
+        assert_eq!(
+            parse(&["--min-len", "11", "--max-len", "10"]).err(),
+            Some(invalid("--min-len", "11", "must not exceed --max-len 10"))
+        );
+        assert!(parse(&["--min-len", "10", "--max-len", "10"]).is_ok());
+    }
+
+    #[test]
+    fn span_only_flags() {
+        let only_span = "only supported by the `span` case, not `string`";
+        for mode in ["canonical", "stream", "concat"] {
+            assert_eq!(
+                parse(&["--case", "string", "--mode", mode]).err(),
+                Some(invalid("--mode", mode, only_span))
+            );
+        }
+        assert!(parse(&["--case", "string", "--mode", "mutate"]).is_ok());
+        assert_eq!(
+            parse(&["--case", "string", "--shape", "nested"]).err(),
+            Some(invalid("--shape", "nested", only_span))
+        );
+        assert_eq!(
+            parse(&["--case", "string", "--corpus", "failures"]).err(),
+            Some(invalid(
+                "--corpus",
+                "failures",
+                "the corpus only records batches of the `span` case, not `string`"
+            ))
+        );
+        assert_eq!(
+            parse(&["--shape", "optional", "--mode", "mutate"]).err(),
+            Some(invalid(
+                "--shape",
+                "optional",
+                "not supported by the mutate mode"
+            ))
+        );
+        assert!(parse(&["--shape", "optional", "--corpus", "failures"]).is_ok());
+    }
+}
@@ -0,0 +1,827 @@
+//! The postcard entry points a batch can be encoded and decoded with, a cross-check that all
+//! of them agree, and a check of `to_slice` with exactly sized and too small buffers.
+//!
+//! [`JsonCodec`] takes part in the cross-check as the human-readable format, whose values must
+//! decode again but whose bytes are not comparable to postcard's.
+//!
+//! postcard 1.0.4 has no `to_extend`, `to_io` or `from_io` yet, they are implemented here on
+//! top of its public flavor traits, with the signatures later postcard versions use.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::thread;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{de_flavors, ser_flavors, serialize_with_flavor};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::mutate::{catch_quietly, panic_message};
+use crate::size::serialized_size;
+
+/// Fills the buffers handed to `to_slice`. A varint that does not fit is not written at all, and
+/// unwritten zero bytes would end the varint, making the prefix decode.
+const FILL: u8 = 0xa5;
+
+/// A way of encoding values to bytes and decoding them again.
+pub trait Codec {
+    fn name(&self) -> &'static str;
+
+    /// Whether the format is human-readable, which changes how e.g. `TraceId` serializes.
+    fn is_human_readable(&self) -> bool;
+
+    /// Appends the encoding of `value` to `buf`.
+    fn encode<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), FormatError>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FormatError>;
+
+    /// The payload inside an encoding, without any framing, or `None` if the framing is broken.
+    fn payload<'a>(&self, encoded: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        Some(Cow::Borrowed(encoded))
+    }
+}
+
+/// Why a [`Codec`] failed to encode or decode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    Postcard(postcard::Error),
+    /// An error of another format, with its message.
+    Other(String),
+}
+
+impl From<postcard::Error> for FormatError {
+    fn from(error: postcard::Error) -> Self {
+        FormatError::Postcard(error)
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Postcard(error) => write!(f, "{error}"),
+            FormatError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+/// The postcard entry points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PostcardCodec {
+    /// `to_allocvec` and `from_bytes`, what the rest of the harness uses.
+    AllocVec,
+    /// `to_stdvec`, an alias of `to_allocvec` with the `use-std` feature.
+    StdVec,
+    /// `to_slice` into a buffer of exactly `serialized_size` bytes.
+    Slice,
+    /// `to_slice_cobs` and `from_bytes_cobs`, framed with COBS and a trailing zero.
+    Cobs,
+    /// [`to_extend`] into a `Vec`.
+    Extend,
+    /// [`to_io`] into a `Vec` and [`from_io`] from a slice.
+    Io,
+}
+
+impl PostcardCodec {
+    /// Every codec, the reference `to_allocvec` first.
+    pub const ALL: [PostcardCodec; 6] = [
+        PostcardCodec::AllocVec,
+        PostcardCodec::StdVec,
+        PostcardCodec::Slice,
+        PostcardCodec::Cobs,
+        PostcardCodec::Extend,
+        PostcardCodec::Io,
+    ];
+}
+
+impl Codec for PostcardCodec {
+    fn name(&self) -> &'static str {
+        match self {
+            PostcardCodec::AllocVec => "to_allocvec",
+            PostcardCodec::StdVec => "to_stdvec",
+            PostcardCodec::Slice => "to_slice",
+            PostcardCodec::Cobs => "to_slice_cobs",
+            PostcardCodec::Extend => "to_extend",
+            PostcardCodec::Io => "to_io",
+        }
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn encode<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), FormatError> {
+        match self {
+            PostcardCodec::AllocVec => buf.extend(postcard::to_allocvec(value)?),
+            PostcardCodec::StdVec => buf.extend(postcard::to_stdvec(value)?),
+            PostcardCodec::Slice => {
+                let start = buf.len();
+                buf.resize(start + serialized_size(value)?, 0);
+                let used = postcard::to_slice(value, &mut buf[start..])?.len();
+                buf.truncate(start + used);
+            }
+            PostcardCodec::Cobs => {
+                let start = buf.len();
+                let max_len = cobs::max_encoding_length(serialized_size(value)?) + 1;
+                buf.resize(start + max_len, 0);
+                let used = postcard::to_slice_cobs(value, &mut buf[start..])?.len();
+                buf.truncate(start + used);
+            }
+            PostcardCodec::Extend => *buf = to_extend(value, std::mem::take(buf))?,
+            PostcardCodec::Io => {
+                to_io(value, buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FormatError> {
+        let value = match self {
+            PostcardCodec::Cobs => postcard::from_bytes_cobs(&mut bytes.to_vec())?,
+            PostcardCodec::Io => {
+                // Borrowed strings and bytes of the value are read into the scratch buffer, which
+                // never needs to be larger than the input.
+                let mut scratch = vec![0; bytes.len()];
+                from_io(bytes, &mut scratch)?.0
+            }
+            _ => postcard::from_bytes(bytes)?,
+        };
+        Ok(value)
+    }
+
+    fn payload<'a>(&self, encoded: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            PostcardCodec::Cobs => cobs::decode_vec(encoded).ok().map(Cow::Owned),
+            _ => Some(Cow::Borrowed(encoded)),
+        }
+    }
+}
+
+impl fmt::Display for PostcardCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// `serde_json`, a human-readable format, so `TraceId` goes through its base64 string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "serde_json"
+    }
+
+    fn is_human_readable(&self) -> bool {
+        true
+    }
+
+    fn encode<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), FormatError> {
+        serde_json::to_writer(buf, value).map_err(|e| FormatError::Other(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, FormatError> {
+        serde_json::from_slice(bytes).map_err(|e| FormatError::Other(e.to_string()))
+    }
+}
+
+/// A serialization flavor appending to anything that implements `Extend<u8>`.
+pub struct ExtendFlavor<E> {
+    extend: E,
+}
+
+impl<E: Extend<u8>> ser_flavors::Flavor for ExtendFlavor<E> {
+    type Output = E;
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.extend.extend(data.iter().copied());
+        Ok(())
+    }
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.extend.extend([data]);
+        Ok(())
+    }
+
+    fn finalize(self) -> postcard::Result<E> {
+        Ok(self.extend)
+    }
+}
+
+/// Serializes `value` into `extend`, like `to_extend` of later postcard versions.
+pub fn to_extend<T, E>(value: &T, extend: E) -> postcard::Result<E>
+where
+    T: Serialize + ?Sized,
+    E: Extend<u8>,
+{
+    serialize_with_flavor(value, ExtendFlavor { extend })
+}
+
+/// A serialization flavor writing to an `io::Write`.
+pub struct WriteFlavor<W> {
+    writer: W,
+}
+
+impl<W: io::Write> ser_flavors::Flavor for WriteFlavor<W> {
+    type Output = W;
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.writer
+            .write_all(data)
+            .map_err(|_| postcard::Error::SerializeBufferFull)
+    }
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.try_extend(&[data])
+    }
+
+    fn finalize(mut self) -> postcard::Result<W> {
+        self.writer
+            .flush()
+            .map_err(|_| postcard::Error::SerializeBufferFull)?;
+        Ok(self.writer)
+    }
+}
+
+/// Serializes `value` into `writer`, like `to_io` of later postcard versions.
+///
+/// Write errors are reported as `SerializeBufferFull`.
+pub fn to_io<T, W>(value: &T, writer: W) -> postcard::Result<W>
+where
+    T: Serialize + ?Sized,
+    W: io::Write,
+{
+    serialize_with_flavor(value, WriteFlavor { writer })
+}
+
+/// A deserialization flavor reading from an `io::Read`.
+///
+/// Bytes the value borrows are read into `scratch`, so it must be large enough for all borrowed
+/// strings and byte slices of the value together.
+pub struct IoReader<'de, R> {
+    reader: R,
+    scratch: &'de mut [u8],
+}
+
+impl<'de, R: io::Read + 'de> de_flavors::Flavor<'de> for IoReader<'de, R> {
+    type Remainder = (R, &'de mut [u8]);
+    type Source = &'de [u8];
+
+    fn pop(&mut self) -> postcard::Result<u8> {
+        let mut byte = [0u8];
+        self.reader
+            .read_exact(&mut byte)
+            .map_err(|_| postcard::Error::DeserializeUnexpectedEnd)?;
+        Ok(byte[0])
+    }
+
+    fn try_take_n(&mut self, ct: usize) -> postcard::Result<&'de [u8]> {
+        if self.scratch.len() < ct {
+            return Err(postcard::Error::DeserializeUnexpectedEnd);
+        }
+        let (taken, rest) = std::mem::take(&mut self.scratch).split_at_mut(ct);
+        self.scratch = rest;
+        self.reader
+            .read_exact(taken)
+            .map_err(|_| postcard::Error::DeserializeUnexpectedEnd)?;
+        Ok(taken)
+    }
+
+    fn finalize(self) -> postcard::Result<(R, &'de mut [u8])> {
+        Ok((self.reader, self.scratch))
+    }
+}
+
+/// Deserializes a `T` from `reader`, like `from_io` of later postcard versions.
+///
+/// Returns the reader and the unused part of `scratch`. Read errors and a too small `scratch`
+/// are reported as `DeserializeUnexpectedEnd`.
+pub fn from_io<'a, T, R>(
+    reader: R,
+    scratch: &'a mut [u8],
+) -> postcard::Result<(T, (R, &'a mut [u8]))>
+where
+    T: Deserialize<'a>,
+    R: io::Read + 'a,
+{
+    let mut deserializer = postcard::Deserializer::from_flavor(IoReader { reader, scratch });
+    let value = T::deserialize(&mut deserializer)?;
+    Ok((value, deserializer.finalize()?))
+}
+
+/// A codec that disagrees with `to_allocvec` and `from_bytes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError {
+    /// The [`Codec::name`].
+    pub codec: &'static str,
+    pub kind: CodecErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecErrorKind {
+    Encode(FormatError),
+    Decode {
+        error: FormatError,
+        encoded: Vec<u8>,
+    },
+    /// The framing around the payload could not be removed.
+    BrokenFraming {
+        encoded: Vec<u8>,
+    },
+    /// The payload differs from the encoding of `to_allocvec`, only checked for binary codecs.
+    PayloadMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// The decoded values differ from the encoded ones.
+    ValueMismatch,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let codec = self.codec;
+        match &self.kind {
+            CodecErrorKind::Encode(error) => write!(f, "{codec} failed to serialize: {error}"),
+            CodecErrorKind::Decode { error, encoded } => write!(
+                f,
+                "{codec} failed to deserialize: {error}\n  encoded ({} bytes, base64): {}",
+                encoded.len(),
+                Base64Display::new(encoded, &BASE64_STANDARD)
+            ),
+            CodecErrorKind::BrokenFraming { encoded } => write!(
+                f,
+                "{codec} produced broken framing\n  encoded ({} bytes, base64): {}",
+                encoded.len(),
+                Base64Display::new(encoded, &BASE64_STANDARD)
+            ),
+            CodecErrorKind::PayloadMismatch { expected, actual } => write!(
+                f,
+                "{codec} encodes other bytes than to_allocvec\n  to_allocvec ({} bytes, \
+                 base64): {}\n  {codec} ({} bytes, base64): {}",
+                expected.len(),
+                Base64Display::new(expected, &BASE64_STANDARD),
+                actual.len(),
+                Base64Display::new(actual, &BASE64_STANDARD)
+            ),
+            CodecErrorKind::ValueMismatch => write!(f, "{codec} decodes to different values"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Encodes `values` with every codec and checks that they decode to `values` again, and that
+/// the payloads of all binary codecs are the bytes of `to_allocvec`.
+pub fn check_codecs<T>(values: &[T]) -> Result<(), CodecError>
+where
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let mut reference = None;
+    for codec in PostcardCodec::ALL {
+        check_codec(&codec, values, &mut reference)?;
+    }
+    check_codec(&JsonCodec, values, &mut reference)
+}
+
+/// Checks a single codec, the payload of the first binary codec becomes the `reference`.
+fn check_codec<C, T>(
+    codec: &C,
+    values: &[T],
+    reference: &mut Option<Vec<u8>>,
+) -> Result<(), CodecError>
+where
+    C: Codec,
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let error = |kind| CodecError {
+        codec: codec.name(),
+        kind,
+    };
+    let mut encoded = Vec::new();
+    codec
+        .encode(values, &mut encoded)
+        .map_err(|e| error(CodecErrorKind::Encode(e)))?;
+    if !codec.is_human_readable() {
+        let payload = codec.payload(&encoded).ok_or_else(|| {
+            error(CodecErrorKind::BrokenFraming {
+                encoded: encoded.clone(),
+            })
+        })?;
+        let expected = reference.get_or_insert_with(|| payload.to_vec());
+        if *payload != **expected {
+            return Err(error(CodecErrorKind::PayloadMismatch {
+                expected: expected.clone(),
+                actual: payload.into_owned(),
+            }));
+        }
+    }
+    let decoded: Vec<T> = codec.decode(&encoded).map_err(|e| {
+        error(CodecErrorKind::Decode {
+            error: e,
+            encoded: encoded.clone(),
+        })
+    })?;
+    if decoded != values {
+        return Err(error(CodecErrorKind::ValueMismatch));
+    }
+    Ok(())
+}
+
+/// How `to_slice` mishandled a buffer of `len` bytes for an encoding of `size` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceError {
+    pub len: usize,
+    pub size: usize,
+    pub kind: SliceErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliceErrorKind {
+    /// `serialized_size` failed, so there is no size to check.
+    Size(postcard::Error),
+    /// `to_allocvec` failed, so there are no bytes to compare with.
+    Serialize(postcard::Error),
+    /// The computed size differs from the length of `to_allocvec`.
+    SizeMismatch {
+        allocvec_len: usize,
+    },
+    /// A buffer of exactly the encoded size was rejected.
+    Rejected(postcard::Error),
+    /// A buffer of exactly the encoded size got other bytes than `to_allocvec`.
+    WrongBytes {
+        written: Vec<u8>,
+    },
+    Panicked(String),
+    /// A too small buffer was accepted.
+    Accepted,
+    /// A too small buffer was rejected with another error than `SerializeBufferFull`.
+    WrongError(postcard::Error),
+    /// `from_bytes` decoded the prefix written into a too small buffer.
+    PrefixDecoded {
+        prefix: Vec<u8>,
+    },
+}
+
+impl fmt::Display for SliceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let run = format!(
+            "to_slice into {} bytes for {} encoded bytes",
+            self.len, self.size
+        );
+        match &self.kind {
+            SliceErrorKind::Size(error) => {
+                write!(f, "to_slice was not run, serialized_size failed: {error}")
+            }
+            SliceErrorKind::Serialize(error) => {
+                write!(f, "to_slice was not run, to_allocvec failed: {error}")
+            }
+            SliceErrorKind::SizeMismatch { allocvec_len } => {
+                write!(
+                    f,
+                    "{run} was not run, to_allocvec encodes {allocvec_len} bytes"
+                )
+            }
+            SliceErrorKind::Rejected(error) => write!(f, "{run} failed: {error}"),
+            SliceErrorKind::WrongBytes { written } => write!(
+                f,
+                "{run} wrote other bytes than to_allocvec\n  written (base64): {}",
+                Base64Display::new(written, &BASE64_STANDARD)
+            ),
+            SliceErrorKind::Panicked(message) => write!(f, "{run} panicked: {message}"),
+            SliceErrorKind::Accepted => write!(f, "{run} succeeded"),
+            SliceErrorKind::WrongError(error) => {
+                write!(
+                    f,
+                    "{run} failed with {error:?} instead of SerializeBufferFull"
+                )
+            }
+            SliceErrorKind::PrefixDecoded { prefix } => write!(
+                f,
+                "{run} left a prefix that from_bytes decodes\n  prefix (base64): {}",
+                Base64Display::new(prefix, &BASE64_STANDARD)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SliceError {}
+
+/// Runs `to_slice` on a buffer of `len` bytes filled with [`FILL`], catching panics.
+///
+/// Returns the result with the length written, and the buffer. postcard only gets the safe
+/// slice, so it cannot write past its end.
+fn to_slice_of_len<T>(value: &T, len: usize) -> (thread::Result<postcard::Result<usize>>, Vec<u8>)
+where
+    T: Serialize + ?Sized,
+{
+    let mut buf = vec![FILL; len];
+    let result = catch_quietly(|| postcard::to_slice(value, &mut buf).map(|written| written.len()));
+    (result, buf)
+}
+
+/// Checks `to_slice` with a buffer of exactly the encoded size of `values`, which must succeed,
+/// and with a buffer one byte smaller and one of random smaller size.
+///
+/// The smaller buffers must be rejected with `SerializeBufferFull`, and what was written into
+/// them must not decode.
+pub fn check_to_slice<R, T>(rng: &mut R, values: &[T]) -> Result<(), SliceError>
+where
+    R: Rng,
+    T: Serialize + DeserializeOwned,
+{
+    let size = serialized_size(values).map_err(|e| SliceError {
+        len: 0,
+        size: 0,
+        kind: SliceErrorKind::Size(e),
+    })?;
+    let error = |len, kind| SliceError { len, size, kind };
+    let reference =
+        postcard::to_allocvec(values).map_err(|e| error(0, SliceErrorKind::Serialize(e)))?;
+    if size != reference.len() {
+        return Err(error(
+            size,
+            SliceErrorKind::SizeMismatch {
+                allocvec_len: reference.len(),
+            },
+        ));
+    }
+
+    let (result, buf) = to_slice_of_len(values, size);
+    let result = result
+        .map_err(|payload| error(size, SliceErrorKind::Panicked(panic_message(&*payload))))?;
+    let written = result.map_err(|e| error(size, SliceErrorKind::Rejected(e)))?;
+    if buf[..written] != reference[..] {
+        return Err(error(
+            size,
+            SliceErrorKind::WrongBytes {
+                written: buf[..written].to_vec(),
+            },
+        ));
+    }
+
+    // Every encoding has at least the length of the sequence, so `size` is at least 1.
+    for len in [size - 1, rng.gen_range(0..size)] {
+        let (result, buf) = to_slice_of_len(values, len);
+        let result = result
+            .map_err(|payload| error(len, SliceErrorKind::Panicked(panic_message(&*payload))))?;
+        match result {
+            Err(postcard::Error::SerializeBufferFull) => {}
+            Err(e) => return Err(error(len, SliceErrorKind::WrongError(e))),
+            Ok(_) => return Err(error(len, SliceErrorKind::Accepted)),
+        }
+        let prefix = &buf[..len];
+        if postcard::from_bytes::<Vec<T>>(prefix).is_ok() {
+            return Err(error(
+                len,
+                SliceErrorKind::PrefixDecoded {
+                    prefix: prefix.to_vec(),
+                },
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_spans, Span, BOUNDARY_LENS};
+
+    #[test]
+    fn to_slice_of_exact_size() {
+        let spans = test_spans(3);
+        let encoded = postcard::to_allocvec(&spans).unwrap();
+        let (result, buf) = to_slice_of_len(&spans, encoded.len());
+        assert_eq!(result.unwrap(), Ok(encoded.len()));
+        assert_eq!(buf, encoded);
+    }
+
+    #[test]
+    fn to_slice_one_byte_short() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let size = serialized_size(&spans).unwrap();
+            let (result, _) = to_slice_of_len(&spans, size - 1);
+            assert_eq!(
+                result.unwrap(),
+                Err(postcard::Error::SerializeBufferFull),
+                "{len} spans"
+            );
+        }
+    }
+
+    #[test]
+    fn to_slice_of_empty_batch() {
+        let empty: Vec<Span> = Vec::new();
+        assert_eq!(serialized_size(&empty), Ok(1));
+        let (result, buf) = to_slice_of_len(&empty, 1);
+        assert_eq!(result.unwrap(), Ok(1));
+        assert_eq!(buf, [0]);
+        let (result, buf) = to_slice_of_len(&empty, 0);
+        assert_eq!(result.unwrap(), Err(postcard::Error::SerializeBufferFull));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn check_to_slice_at_length_boundaries() {
+        let mut rng = rand::rngs::mock::StepRng::new(0, 0x9e37_79b9_7f4a_7c15);
+        for len in BOUNDARY_LENS {
+            assert_eq!(
+                check_to_slice(&mut rng, &test_spans(len)),
+                Ok(()),
+                "{len} spans"
+            );
+        }
+        assert_eq!(check_to_slice(&mut rng, &[0u8; 0]), Ok(()));
+        assert_eq!(
+            check_to_slice(&mut rng, &[String::new(), "x".to_string()]),
+            Ok(())
+        );
+    }
+
+    /// Accepts `room` bytes, then fails every write.
+    struct FailingWriter {
+        room: usize,
+        written: Vec<u8>,
+    }
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::other("no room"));
+            }
+            let len = buf.len().min(self.room);
+            self.room -= len;
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Returns at most one byte per read.
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl io::Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(slot)) => {
+                    *slot = byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn to_extend_matches_to_allocvec() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let expected = postcard::to_allocvec(&spans).unwrap();
+            assert_eq!(
+                to_extend(&spans, Vec::new()).unwrap(),
+                expected,
+                "{len} spans"
+            );
+            let appended = to_extend(&spans, vec![0xff]).unwrap();
+            assert_eq!(appended[0], 0xff);
+            assert_eq!(appended[1..], expected[..], "{len} spans");
+        }
+    }
+
+    #[test]
+    fn to_io_matches_to_allocvec() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let expected = postcard::to_allocvec(&spans).unwrap();
+            assert_eq!(to_io(&spans, Vec::new()).unwrap(), expected, "{len} spans");
+        }
+    }
+
+    #[test]
+    fn to_io_into_failing_writer() {
+        let spans = test_spans(3);
+        let expected = postcard::to_allocvec(&spans).unwrap();
+        for room in [0, 1, expected.len() - 1] {
+            let writer = FailingWriter {
+                room,
+                written: Vec::new(),
+            };
+            assert_eq!(
+                to_io(&spans, writer).err(),
+                Some(postcard::Error::SerializeBufferFull),
+                "room for {room} bytes"
+            );
+        }
+        let writer = FailingWriter {
+            room: expected.len(),
+            written: Vec::new(),
+        };
+        assert_eq!(to_io(&spans, writer).unwrap().written, expected);
+    }
+
+    #[test]
+    fn from_io_round_trips() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let encoded = postcard::to_allocvec(&spans).unwrap();
+            let mut scratch = vec![0; encoded.len()];
+            let (decoded, _) = from_io::<Vec<Span>, _>(&encoded[..], &mut scratch).unwrap();
+            assert_eq!(decoded, spans, "{len} spans");
+            let mut scratch = vec![0; encoded.len()];
+            let (decoded, _) = from_io::<Vec<Span>, _>(ByteReader(&encoded), &mut scratch).unwrap();
+            assert_eq!(decoded, spans, "{len} spans read bytewise");
+        }
+    }
+
+    #[test]
+    fn from_io_returns_the_remainder() {
+        let mut encoded = postcard::to_allocvec(&(7u8, "borrowed")).unwrap();
+        encoded.extend_from_slice(b"rest");
+        let mut scratch = [0; 12];
+        let ((number, text), (reader, unused)) =
+            from_io::<(u8, &str), _>(&encoded[..], &mut scratch).unwrap();
+        assert_eq!((number, text), (7, "borrowed"));
+        assert_eq!(reader, b"rest");
+        assert_eq!(unused.len(), 4);
+    }
+
+    #[test]
+    fn from_io_of_short_input() {
+        let encoded = postcard::to_allocvec(&test_spans(3)).unwrap();
+        for len in [0, 1, encoded.len() - 1] {
+            let mut scratch = vec![0; encoded.len()];
+            assert_eq!(
+                from_io::<Vec<Span>, _>(ByteReader(&encoded[..len]), &mut scratch).err(),
+                Some(postcard::Error::DeserializeUnexpectedEnd),
+                "{len} of {} bytes",
+                encoded.len()
+            );
+        }
+    }
+
+    #[test]
+    fn from_io_with_small_scratch() {
+        let encoded = postcard::to_allocvec("borrowed").unwrap();
+        let mut scratch = [0; 7];
+        assert_eq!(
+            from_io::<&str, _>(&encoded[..], &mut scratch).err(),
+            Some(postcard::Error::DeserializeUnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn io_reader_borrows_from_scratch() {
+        use de_flavors::Flavor;
+
+        let mut scratch = [0; 4];
+        let mut reader = IoReader {
+            reader: &[1, 2, 3, 4, 5][..],
+            scratch: &mut scratch,
+        };
+        assert_eq!(reader.pop(), Ok(1));
+        assert_eq!(reader.try_take_n(3), Ok(&[2, 3, 4][..]));
+        assert_eq!(
+            reader.try_take_n(2),
+            Err(postcard::Error::DeserializeUnexpectedEnd)
+        );
+        let (rest, unused) = reader.finalize().unwrap();
+        assert_eq!(rest, [5]);
+        assert_eq!(unused.len(), 1);
+    }
+
+    #[test]
+    fn json_errors_keep_their_message() {
+        let error = JsonCodec.decode::<Vec<Span>>(b"[{").unwrap_err();
+        let FormatError::Other(message) = error.clone() else {
+            panic!("not a JSON error: {error:?}");
+        };
+        assert!(message.contains("line 1"), "{message}");
+        let error = CodecError {
+            codec: JsonCodec.name(),
+            kind: CodecErrorKind::Decode {
+                error,
+                encoded: b"[{".to_vec(),
+            },
+        };
+        assert!(error.to_string().contains(&message), "{error}");
+    }
+}
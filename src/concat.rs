@@ -0,0 +1,309 @@
+//! Decoding postcard messages from the front of a buffer.
+//!
+//! `from_bytes` ignores whatever follows the message, so a buffer holding a message and junk,
+//! or several messages, decodes without complaint. [`from_bytes_strict`] rejects trailing bytes,
+//! and [`check_concatenated`] decodes back-to-back messages one after the other with
+//! `take_from_bytes`.
+
+use std::fmt;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{from_bytes, take_from_bytes, to_allocvec};
+use serde::Deserialize;
+
+use crate::Span;
+
+/// Most batches concatenated into one buffer.
+pub const MAX_MESSAGES: usize = 8;
+
+/// Why [`from_bytes_strict`] rejected a buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StrictError {
+    Deserialize(postcard::Error),
+    /// The message was followed by `len` more bytes.
+    TrailingBytes {
+        len: usize,
+    },
+}
+
+impl fmt::Display for StrictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StrictError::Deserialize(error) => write!(f, "failed to deserialize: {error}"),
+            StrictError::TrailingBytes { len } => {
+                write!(f, "{len} trailing bytes after the message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StrictError {}
+
+impl From<postcard::Error> for StrictError {
+    fn from(error: postcard::Error) -> Self {
+        StrictError::Deserialize(error)
+    }
+}
+
+/// Like `from_bytes`, but fails unless the message spans all of `bytes`.
+pub fn from_bytes_strict<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, StrictError> {
+    let (value, rest) = take_from_bytes(bytes)?;
+    if !rest.is_empty() {
+        return Err(StrictError::TrailingBytes { len: rest.len() });
+    }
+    Ok(value)
+}
+
+/// Counters of concatenated batches that were decoded.
+#[derive(Debug, Clone, Default)]
+pub struct ConcatStats {
+    pub buffers: u64,
+    pub messages: u64,
+}
+
+impl ConcatStats {
+    pub fn merge(&mut self, other: &ConcatStats) {
+        self.buffers += other.buffers;
+        self.messages += other.messages;
+    }
+}
+
+/// A buffer of concatenated batches that did not decode message by message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConcatError {
+    /// The batch being decoded.
+    pub message: usize,
+    /// Where each batch starts in `concatenated`, and where the last one ends.
+    pub boundaries: Vec<usize>,
+    pub concatenated: Vec<u8>,
+    pub kind: ConcatErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConcatErrorKind {
+    Serialize(postcard::Error),
+    Deserialize(postcard::Error),
+    /// The batch decoded to different spans.
+    Mismatch,
+    /// The remainder is not the rest of the buffer after the batch.
+    Remainder {
+        offset: usize,
+        len: usize,
+    },
+    /// `from_bytes_strict` accepted the batch with the following ones as trailing bytes, or
+    /// rejected it for another reason than those trailing bytes.
+    Strict(Result<(), StrictError>),
+    /// `from_bytes` did not decode the first batch and ignore the rest.
+    Lenient(Result<(), postcard::Error>),
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.message;
+        match &self.kind {
+            ConcatErrorKind::Serialize(error) => {
+                write!(f, "failed to serialize batch {message}: {error}")?
+            }
+            ConcatErrorKind::Deserialize(error) => {
+                write!(f, "take_from_bytes failed on batch {message}: {error}")?
+            }
+            ConcatErrorKind::Mismatch => write!(
+                f,
+                "take_from_bytes decoded batch {message} to different spans"
+            )?,
+            ConcatErrorKind::Remainder { offset, len } => write!(
+                f,
+                "take_from_bytes left a remainder of {len} bytes at offset {offset} after batch \
+                 {message}, expected {} bytes at offset {}",
+                self.concatenated.len() - self.boundaries[message + 1],
+                self.boundaries[message + 1]
+            )?,
+            ConcatErrorKind::Strict(Ok(())) => write!(
+                f,
+                "from_bytes_strict accepted batch {message} followed by {} bytes",
+                self.concatenated.len() - self.boundaries[message + 1]
+            )?,
+            ConcatErrorKind::Strict(Err(error)) => {
+                write!(f, "from_bytes_strict rejected batch {message}: {error}")?
+            }
+            ConcatErrorKind::Lenient(Ok(())) => write!(
+                f,
+                "from_bytes decoded batch {message} with the following bytes to different spans"
+            )?,
+            ConcatErrorKind::Lenient(Err(error)) => write!(
+                f,
+                "from_bytes rejected batch {message} with the following bytes: {error}"
+            )?,
+        }
+        write!(
+            f,
+            "\n  batches start at {:?}\n  concatenated ({} bytes, base64): {}",
+            self.boundaries,
+            self.concatenated.len(),
+            Base64Display::new(&self.concatenated, &BASE64_STANDARD)
+        )
+    }
+}
+
+impl std::error::Error for ConcatError {}
+
+/// Concatenates the encodings of `batches` and decodes them one after the other with
+/// `take_from_bytes`.
+///
+/// Every remainder must be exactly the rest of the buffer, ending with an empty one. Each
+/// batch is also decoded with the following ones as trailing bytes: `from_bytes_strict` must
+/// reject it only for those bytes, `from_bytes` must ignore them.
+pub fn check_concatenated(batches: &[Vec<Span>]) -> Result<ConcatStats, ConcatError> {
+    let mut concatenated = Vec::new();
+    let mut boundaries = vec![0];
+    for (message, batch) in batches.iter().enumerate() {
+        let encoded = to_allocvec(batch).map_err(|error| ConcatError {
+            message,
+            boundaries: boundaries.clone(),
+            concatenated: concatenated.clone(),
+            kind: ConcatErrorKind::Serialize(error),
+        })?;
+        concatenated.extend_from_slice(&encoded);
+        boundaries.push(concatenated.len());
+    }
+    let error = |message, kind| ConcatError {
+        message,
+        boundaries: boundaries.clone(),
+        concatenated: concatenated.clone(),
+        kind,
+    };
+
+    let mut remaining = concatenated.as_slice();
+    for (message, batch) in batches.iter().enumerate() {
+        let start = boundaries[message];
+        let end = boundaries[message + 1];
+        let (decoded, rest) = take_from_bytes::<Vec<Span>>(remaining)
+            .map_err(|e| error(message, ConcatErrorKind::Deserialize(e)))?;
+        if decoded != *batch {
+            return Err(error(message, ConcatErrorKind::Mismatch));
+        }
+        // The remainder must be the very same bytes, not just equal ones.
+        let offset = rest.as_ptr() as usize - concatenated.as_ptr() as usize;
+        if offset != end || rest.len() != concatenated.len() - end {
+            return Err(error(
+                message,
+                ConcatErrorKind::Remainder {
+                    offset,
+                    len: rest.len(),
+                },
+            ));
+        }
+        remaining = rest;
+
+        let trailing = concatenated.len() - end;
+        match from_bytes_strict::<Vec<Span>>(&concatenated[start..]) {
+            Err(StrictError::TrailingBytes { len }) if len == trailing && trailing > 0 => {}
+            Ok(spans) if trailing == 0 && spans == *batch => {}
+            strict => return Err(error(message, ConcatErrorKind::Strict(strict.map(|_| ())))),
+        }
+        match from_bytes::<Vec<Span>>(&concatenated[start..]) {
+            Ok(spans) if spans == *batch => {}
+            lenient => {
+                return Err(error(
+                    message,
+                    ConcatErrorKind::Lenient(lenient.map(|_| ())),
+                ))
+            }
+        }
+    }
+    Ok(ConcatStats {
+        buffers: 1,
+        messages: batches.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use postcard::Error;
+
+    use crate::{test_span, test_spans, BOUNDARY_LENS};
+
+    use super::*;
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(from_bytes_strict::<u32>(&[0xac, 0x02]), Ok(300));
+        assert_eq!(from_bytes_strict::<Vec<u8>>(&[0]), Ok(Vec::new()));
+        assert_eq!(from_bytes_strict::<&str>(&[2, b'h', b'i']), Ok("hi"));
+        assert_eq!(from_bytes_strict::<()>(&[]), Ok(()));
+    }
+
+    #[test]
+    fn whole_batches_at_length_boundaries() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let encoded = to_allocvec(&spans).unwrap();
+            assert_eq!(
+                from_bytes_strict::<Vec<Span>>(&encoded),
+                Ok(spans),
+                "{len} spans"
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        assert_eq!(
+            from_bytes_strict::<u32>(&[0xac, 0x02, 0x00]),
+            Err(StrictError::TrailingBytes { len: 1 })
+        );
+        assert_eq!(
+            from_bytes_strict::<()>(&[0; 3]),
+            Err(StrictError::TrailingBytes { len: 3 })
+        );
+        // Two messages back to back, which `from_bytes` takes for the first one.
+        let encoded = to_allocvec(&vec![test_span(1, 1)]).unwrap();
+        let twice = [encoded.clone(), encoded.clone()].concat();
+        assert_eq!(from_bytes::<Vec<Span>>(&twice), Ok(vec![test_span(1, 1)]));
+        assert_eq!(
+            from_bytes_strict::<Vec<Span>>(&twice),
+            Err(StrictError::TrailingBytes { len: encoded.len() })
+        );
+    }
+
+    #[test]
+    fn malformed_input() {
+        assert_eq!(
+            from_bytes_strict::<u32>(&[]),
+            Err(StrictError::Deserialize(Error::DeserializeUnexpectedEnd))
+        );
+        assert_eq!(
+            from_bytes_strict::<u32>(&[0x80]),
+            Err(StrictError::Deserialize(Error::DeserializeUnexpectedEnd))
+        );
+        assert_eq!(
+            from_bytes_strict::<u8>(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x00]),
+            Err(StrictError::TrailingBytes { len: 5 })
+        );
+        assert_eq!(
+            from_bytes_strict::<u16>(&[0xff, 0xff, 0xff, 0x00]),
+            Err(StrictError::Deserialize(Error::DeserializeBadVarint))
+        );
+        assert_eq!(
+            from_bytes_strict::<bool>(&[2]),
+            Err(StrictError::Deserialize(Error::DeserializeBadBool))
+        );
+        let encoded = to_allocvec(&vec![test_span(2, 2)]).unwrap();
+        assert_eq!(
+            from_bytes_strict::<Vec<Span>>(&encoded[..encoded.len() - 1]),
+            Err(StrictError::Deserialize(Error::DeserializeUnexpectedEnd))
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            StrictError::TrailingBytes { len: 2 }.to_string(),
+            "2 trailing bytes after the message"
+        );
+        assert!(StrictError::Deserialize(Error::DeserializeBadBool)
+            .to_string()
+            .starts_with("failed to deserialize: "));
+    }
+}
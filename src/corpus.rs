@@ -0,0 +1,442 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use postcard::to_allocvec;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::batch::Batch;
+use crate::check::{check_all, CheckError};
+use crate::{DateTime, Span, TraceId};
+
+const HEADER: &str = "force_check_postcard corpus v2";
+/// Entries without the `shape` field, all of them flat batches.
+const HEADER_V1: &str = "force_check_postcard corpus v1";
+const EXTENSION: &str = "corpus";
+/// Keep in sync with the `postcard` pin in Cargo.toml.
+const POSTCARD_VERSION: &str = "1.0.4";
+
+/// A failing batch persisted to the corpus directory.
+///
+/// Stored as a line-based text file, e.g.
+///
+/// ```text
+/// force_check_postcard corpus v2
+/// postcard: 1.0.4
+/// seed: 42
+/// error: failed to deserialize: Hit the end of buffer, expected more data
+/// encoded: <base64 of the postcard bytes, empty if serialization failed>
+/// shape: nested 0 1
+/// spans: 1
+/// AAAAAAAAAAAAAAAAAAAAAA== 0
+/// ```
+///
+/// The shape is `flat`, `nested` followed by the lengths of the inner batches, `optional some`
+/// or `optional none`. Entries of version 1 have no shape and are flat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusEntry {
+    pub seed: u64,
+    /// The postcard version that produced `encoded`.
+    pub postcard_version: String,
+    /// The failure that was observed when the entry was recorded.
+    pub error: String,
+    /// The bytes `to_allocvec` produced, if serialization succeeded.
+    pub encoded: Option<Vec<u8>>,
+    pub batch: Batch,
+}
+
+impl CorpusEntry {
+    pub fn new(seed: u64, batch: &Batch, error: &CheckError) -> Self {
+        CorpusEntry {
+            seed,
+            postcard_version: POSTCARD_VERSION.to_string(),
+            error: error.message().replace("\n ", ";"),
+            encoded: to_allocvec(batch).ok(),
+            batch: batch.clone(),
+        }
+    }
+
+    /// Writes the entry to `dir`, creating the directory if needed.
+    pub fn write_to_dir(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{:016x}.{EXTENSION}", self.seed));
+        fs::write(&path, self.to_text())?;
+        Ok(path)
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::from_text(&text).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {message}", path.display()),
+            )
+        })
+    }
+
+    /// Re-runs the checks of the batch with the recorded seed and decodes the recorded bytes.
+    pub fn replay(&self) -> Result<(), String> {
+        check_all(&mut StdRng::seed_from_u64(self.seed), &self.batch)
+            .map_err(|error| error.to_string())?;
+        if let Some(encoded) = &self.encoded {
+            let decoded = Batch::decode(self.batch.shape(), encoded)
+                .map_err(|error| format!("failed to decode the recorded bytes: {error}"))?;
+            if decoded != self.batch {
+                return Err("the recorded bytes decode to a different batch".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn to_text(&self) -> String {
+        let spans = self.batch.spans();
+        let mut text = format!(
+            "{HEADER}\npostcard: {}\nseed: {}\nerror: {}\nencoded: {}\nshape: {}\nspans: {}\n",
+            self.postcard_version,
+            self.seed,
+            self.error,
+            self.encoded
+                .as_ref()
+                .map(|encoded| BASE64_STANDARD.encode(encoded))
+                .unwrap_or_default(),
+            shape_to_text(&self.batch),
+            spans.len()
+        );
+        for span in spans {
+            text.push_str(&format!(
+                "{} {}\n",
+                span.trace_id.base64_display(),
+                span.span_timestamp.into_timestamp_nanos()
+            ));
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let has_shape = match lines.next() {
+            Some(HEADER) => true,
+            Some(HEADER_V1) => false,
+            _ => return Err(format!("missing `{HEADER}` header")),
+        };
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .ok_or_else(|| format!("missing `{name}` field"))
+        };
+        let postcard_version = field("postcard")?.to_string();
+        let seed = field("seed")?
+            .parse()
+            .map_err(|error| format!("invalid seed: {error}"))?;
+        let error = field("error")?.to_string();
+        let encoded = match field("encoded")? {
+            "" => None,
+            encoded => Some(
+                BASE64_STANDARD
+                    .decode(encoded)
+                    .map_err(|error| format!("invalid encoded bytes: {error}"))?,
+            ),
+        };
+        let shape = if has_shape {
+            Some(field("shape")?.to_string())
+        } else {
+            None
+        };
+        let num_spans: usize = field("spans")?
+            .parse()
+            .map_err(|error| format!("invalid span count: {error}"))?;
+        let spans = lines
+            .map(parse_span)
+            .collect::<Result<Vec<Span>, String>>()?;
+        if spans.len() != num_spans {
+            return Err(format!("expected {num_spans} spans, found {}", spans.len()));
+        }
+        let batch = match shape {
+            Some(shape) => shape_from_text(&shape, spans)?,
+            None => Batch::Flat(spans),
+        };
+        Ok(CorpusEntry {
+            seed,
+            postcard_version,
+            error,
+            encoded,
+            batch,
+        })
+    }
+}
+
+fn shape_to_text(batch: &Batch) -> String {
+    match batch {
+        Batch::Flat(_) => "flat".to_string(),
+        Batch::Nested(nested) => nested.iter().fold("nested".to_string(), |text, inner| {
+            format!("{text} {}", inner.len())
+        }),
+        Batch::Optional(Some(_)) => "optional some".to_string(),
+        Batch::Optional(None) => "optional none".to_string(),
+    }
+}
+
+/// Puts `spans` into the batch structure described by `shape`.
+fn shape_from_text(shape: &str, spans: Vec<Span>) -> Result<Batch, String> {
+    let mut words = shape.split(' ');
+    let batch = match (words.next(), words.next()) {
+        (Some("flat"), None) => Batch::Flat(spans),
+        (Some("optional"), Some("some")) => Batch::Optional(Some(spans)),
+        (Some("optional"), Some("none")) if spans.is_empty() => Batch::Optional(None),
+        (Some("nested"), first) => {
+            let lens = first
+                .into_iter()
+                .chain(words.by_ref())
+                .map(|len| len.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| format!("invalid inner batch length: {error}"))?;
+            if lens.iter().sum::<usize>() != spans.len() {
+                return Err(format!(
+                    "inner batch lengths {lens:?} do not add up to {} spans",
+                    spans.len()
+                ));
+            }
+            let mut rest = spans.into_iter();
+            Batch::Nested(
+                lens.into_iter()
+                    .map(|len| rest.by_ref().take(len).collect())
+                    .collect(),
+            )
+        }
+        _ => return Err(format!("invalid shape `{shape}`")),
+    };
+    if words.next().is_some() {
+        return Err(format!("invalid shape `{shape}`"));
+    }
+    Ok(batch)
+}
+
+fn parse_span(line: &str) -> Result<Span, String> {
+    let (trace_id, timestamp) = line
+        .split_once(' ')
+        .ok_or_else(|| format!("invalid span line `{line}`"))?;
+    let trace_id = TraceId::from_base64(trace_id)
+        .map_err(|error| format!("invalid trace ID `{trace_id}`: {error}"))?;
+    let timestamp = timestamp
+        .parse()
+        .map_err(|error| format!("invalid timestamp `{timestamp}`: {error}"))?;
+    Ok(Span {
+        trace_id,
+        span_timestamp: DateTime::from_timestamp_nanos(timestamp),
+    })
+}
+
+/// Reads every entry in `dir`, sorted by file name.
+pub fn read_dir(dir: &Path) -> io::Result<Vec<(PathBuf, CorpusEntry)>> {
+    let mut paths = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == EXTENSION)
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let entry = CorpusEntry::read(&path)?;
+            Ok((path, entry))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_span;
+
+    fn entry(batch: Batch) -> CorpusEntry {
+        CorpusEntry {
+            seed: 42,
+            postcard_version: POSTCARD_VERSION.to_string(),
+            error: "failed to deserialize: Hit the end of buffer, expected more data".to_string(),
+            encoded: to_allocvec(&batch).ok(),
+            batch,
+        }
+    }
+
+    #[test]
+    fn known_text() {
+        let entry = entry(Batch::Nested(vec![vec![], vec![test_span(0, 0)]]));
+        assert_eq!(
+            entry.to_text(),
+            "force_check_postcard corpus v2\n\
+             postcard: 1.0.4\n\
+             seed: 42\n\
+             error: failed to deserialize: Hit the end of buffer, expected more data\n\
+             encoded: AgABEAAAAAAAAAAAAAAAAAAAAAAA\n\
+             shape: nested 0 1\n\
+             spans: 1\n\
+             AAAAAAAAAAAAAAAAAAAAAA== 0\n"
+        );
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let spans = vec![
+            test_span(1, -1),
+            test_span(0xff, i64::MAX),
+            test_span(7, i64::MIN),
+        ];
+        let batches = [
+            Batch::Flat(Vec::new()),
+            Batch::Flat(spans.clone()),
+            Batch::Nested(Vec::new()),
+            Batch::Nested(vec![Vec::new()]),
+            Batch::Nested(vec![spans[..1].to_vec(), Vec::new(), spans[1..].to_vec()]),
+            Batch::Optional(None),
+            Batch::Optional(Some(Vec::new())),
+            Batch::Optional(Some(spans)),
+        ];
+        for batch in batches {
+            let entry = entry(batch);
+            assert_eq!(CorpusEntry::from_text(&entry.to_text()), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn text_round_trip_without_encoded_bytes() {
+        let entry = CorpusEntry {
+            encoded: None,
+            ..entry(Batch::Flat(vec![test_span(3, 3)]))
+        };
+        let text = entry.to_text();
+        assert!(text.contains("\nencoded: \n"));
+        assert_eq!(CorpusEntry::from_text(&text), Ok(entry));
+    }
+
+    #[test]
+    fn version_1_entries_are_flat() {
+        let text = "force_check_postcard corpus v1\n\
+                    postcard: 1.0.4\n\
+                    seed: 7\n\
+                    error: spans differ\n\
+                    encoded: \n\
+                    spans: 1\n\
+                    AQEBAQEBAQEBAQEBAQEBAQ== 5\n";
+        let entry = CorpusEntry::from_text(text).unwrap();
+        assert_eq!(entry.seed, 7);
+        assert_eq!(entry.error, "spans differ");
+        assert_eq!(entry.encoded, None);
+        assert_eq!(entry.batch, Batch::Flat(vec![test_span(1, 5)]));
+    }
+
+    #[test]
+    fn malformed_text() {
+        assert_eq!(
+            CorpusEntry::from_text(""),
+            Err("missing `force_check_postcard corpus v2` header".to_string())
+        );
+        assert_eq!(
+            CorpusEntry::from_text("force_check_postcard corpus v2\n"),
+            Err("missing `postcard` field".to_string())
+        );
+
+        let valid = entry(Batch::Nested(vec![
+            vec![test_span(0, 0)],
+            vec![test_span(1, 1)],
+        ]))
+        .to_text();
+        assert!(CorpusEntry::from_text(&valid).is_ok());
+        let cases = [
+            ("corpus v2", "corpus v3", "header"),
+            ("postcard: ", "version: ", "missing `postcard` field"),
+            ("seed: 42", "seed: -1", "invalid seed"),
+            ("encoded: ", "encoded: ?", "invalid encoded bytes"),
+            ("shape: nested 1 1\n", "", "missing `shape` field"),
+            ("shape: nested 1 1", "shape: flat 1", "invalid shape"),
+            ("shape: nested 1 1", "shape: nest 1 1", "invalid shape"),
+            ("shape: nested 1 1", "shape: nested 2 1", "do not add up"),
+            (
+                "shape: nested 1 1",
+                "shape: nested 1 x",
+                "invalid inner batch length",
+            ),
+            ("spans: 2", "spans: 3", "expected 3 spans, found 2"),
+            ("spans: 2", "spans: two", "invalid span count"),
+            (
+                "AAAAAAAAAAAAAAAAAAAAAA== 0",
+                "AAAAAAAAAAAAAAAAAAAAAA==",
+                "invalid span line",
+            ),
+            (
+                "AAAAAAAAAAAAAAAAAAAAAA== 0",
+                "AAAAAAAAAAAAAAAAAAAAAA 0",
+                "invalid trace ID",
+            ),
+            (
+                "AAAAAAAAAAAAAAAAAAAAAA== 0",
+                "AAAAAAAAAAAAAAAAAAAAAA== 1.5",
+                "invalid timestamp",
+            ),
+        ];
+        for (line, replacement, expected) in cases {
+            assert!(valid.contains(line), "{line:?}");
+            let error = CorpusEntry::from_text(&valid.replacen(line, replacement, 1)).unwrap_err();
+            assert!(error.contains(expected), "`{error}` for {replacement:?}");
+        }
+    }
+
+    #[test]
+    fn optional_none_has_no_spans() {
+        let text = entry(Batch::Optional(Some(vec![test_span(0, 0)])))
+            .to_text()
+            .replace("optional some", "optional none");
+        assert_eq!(
+            CorpusEntry::from_text(&text),
+            Err("invalid shape `optional none`".to_string())
+        );
+    }
+
+    #[test]
+    fn write_and_read_dir() {
+        let dir = std::env::temp_dir().join(format!("corpus-test-{}", std::process::id()));
+        let first = CorpusEntry {
+            seed: 1,
+            ..entry(Batch::Optional(Some(vec![test_span(2, 2)])))
+        };
+        let second = CorpusEntry {
+            seed: 0x10,
+            ..entry(Batch::Flat(Vec::new()))
+        };
+        let path = second.write_to_dir(&dir).unwrap();
+        assert_eq!(path, dir.join("0000000000000010.corpus"));
+        first.write_to_dir(&dir).unwrap();
+        fs::write(dir.join("notes.txt"), "not an entry").unwrap();
+
+        let entries = read_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let entries: Vec<_> = entries
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        assert_eq!(entries, [first, second]);
+    }
+
+    #[test]
+    fn replay_passes_for_a_fixed_batch() {
+        let entry = entry(Batch::Nested(vec![vec![test_span(5, 5)], Vec::new()]));
+        assert_eq!(entry.replay(), Ok(()));
+
+        let tampered = CorpusEntry {
+            encoded: Some(vec![1, 0]),
+            ..entry
+        };
+        assert_eq!(
+            tampered.replay(),
+            Err("the recorded bytes decode to a different batch".to_string())
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use postcard::{from_bytes, to_allocvec, to_slice};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Global allocator that counts the allocations of the current thread.
+///
+/// The counters are thread-local so concurrent workers do not see each other's allocations.
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+    static PEAK_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+fn record(delta: isize, new_allocation: bool) {
+    // `try_with` fails during thread teardown, those allocations are not interesting.
+    let _ = LIVE_BYTES.try_with(|live| {
+        let now = live.get() + delta;
+        live.set(now);
+        let _ = PEAK_BYTES.try_with(|peak| peak.set(peak.get().max(now)));
+    });
+    if new_allocation {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record(layout.size() as isize, true);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record(layout.size() as isize, true);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record(-(layout.size() as isize), false);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record(new_size as isize - layout.size() as isize, true);
+        }
+        new_ptr
+    }
+}
+
+/// Allocations made by the current thread while running a closure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Number of calls to `alloc`, `alloc_zeroed` and `realloc`.
+    pub allocations: u64,
+    /// Highest number of bytes live at once, relative to the start of the closure.
+    pub peak_bytes: usize,
+}
+
+/// Runs `f` and reports the allocations it made on the current thread.
+///
+/// Only counts when `CountingAllocator` is the `#[global_allocator]` of the binary, otherwise
+/// the stats are all zero.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, AllocStats) {
+    let allocations_before = ALLOCATIONS.with(Cell::get);
+    let live_before = LIVE_BYTES.with(Cell::get);
+    PEAK_BYTES.with(|peak| peak.set(live_before));
+    let value = f();
+    let stats = AllocStats {
+        allocations: ALLOCATIONS.with(Cell::get) - allocations_before,
+        peak_bytes: (PEAK_BYTES.with(Cell::get) - live_before) as usize,
+    };
+    (value, stats)
+}
+
+/// Allocations of encoding and of decoding every value of a batch on its own.
+///
+/// The values are encoded into a buffer that is large enough, and decoded from encodings of
+/// their own, so the counts leave out the allocations of the sequences around them: the output
+/// `Vec` of `to_allocvec` and the decoded `Vec`s, which grow by reallocating.
+///
+/// # Panics
+///
+/// If a value does not encode, only measure batches that passed the round trip.
+pub fn measure_values<'a, T, I>(values: I) -> (AllocStats, AllocStats)
+where
+    T: Serialize + DeserializeOwned + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    let values: Vec<&T> = values.into_iter().collect();
+    let encoded: Vec<Vec<u8>> = values
+        .iter()
+        .map(|value| to_allocvec(value).expect("a checked value must encode"))
+        .collect();
+    let mut buffer = vec![0; encoded.iter().map(Vec::len).max().unwrap_or(0)];
+    let ((), encode) = measure(|| {
+        for value in &values {
+            let _ = to_slice(value, &mut buffer);
+        }
+    });
+    let ((), decode) = measure(|| {
+        for bytes in &encoded {
+            let _ = from_bytes::<T>(bytes);
+        }
+    });
+    (encode, decode)
+}
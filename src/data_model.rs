@@ -0,0 +1,380 @@
+//! Cases covering the serde data model as postcard encodes it.
+//!
+//! Every case is registered on its own, so failures name the type, and all of them together
+//! as the [`GROUP`] case.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::case::{Registry, RoundTripCase};
+use crate::generators::SpanGenerator;
+use crate::impl_random_value;
+use crate::random_value::{Budget, RandomCase, RandomValue};
+
+/// Name of the case that runs every data-model case.
+pub const GROUP: &str = "data-model";
+
+/// Characters next to the boundaries of the UTF-8 encoded lengths and around the surrogates.
+const EDGE_CHARS: [char; 10] = [
+    '\0',
+    '\u{7f}',
+    '\u{80}',
+    '\u{7ff}',
+    '\u{800}',
+    '\u{d7ff}',
+    '\u{e000}',
+    '\u{ffff}',
+    '\u{10000}',
+    char::MAX,
+];
+
+/// Registers the data-model cases and the group running all of them.
+pub fn register(registry: &mut Registry) {
+    let before: Vec<_> = registry.names().collect();
+    registry.register(UnsignedCase);
+    registry.register(SignedCase);
+    registry.register(F32Case);
+    registry.register(F64Case);
+    registry.register(CharCase);
+    registry.register(ZeroSizedCase);
+    registry.register(RandomCase::<String>::new("string", Budget::new(64, 1)));
+    registry.register(RandomCase::<Bytes>::new("bytes", Budget::new(64, 1)));
+    registry.register(RandomCase::<Variants>::new("enum", Budget::new(8, 1)));
+    registry.register(RandomCase::<(Option<Option<u32>>, Option<()>)>::new(
+        "option",
+        Budget::new(0, 2),
+    ));
+    registry.register(RandomCase::<Vec<Vec<Vec<u16>>>>::new(
+        "nested-seq",
+        Budget::new(4, 3),
+    ));
+    registry.register(
+        RandomCase::<(BTreeMap<String, Vec<i32>>, HashMap<u64, bool>)>::new(
+            "map",
+            Budget::new(8, 2),
+        ),
+    );
+    let added = registry
+        .names()
+        .filter(|name| !before.contains(name))
+        .collect();
+    registry.register_group(GROUP, added);
+}
+
+/// Uniform values, values next to the varint length boundaries and the extremes of a `bits`
+/// wide integer, in its lowest bits.
+///
+/// With `signed`, the varint boundaries are the ones of the zigzag encoding.
+fn edge_int<R: Rng>(rng: &mut R, bits: u32, signed: bool) -> u128 {
+    let mask = u128::MAX >> (128 - bits);
+    let value = match rng.gen_range(0..4) {
+        0 => rng.gen(),
+        1 => {
+            let shift = 7 * rng.gen_range(1..=bits.div_ceil(7)) - u32::from(signed);
+            let boundary = 1u128.checked_shl(shift).unwrap_or(0);
+            let boundary = if signed && rng.gen() {
+                boundary.wrapping_neg()
+            } else {
+                boundary
+            };
+            boundary.wrapping_add_signed(rng.gen_range(-1..=1))
+        }
+        2 => [0, 1, mask, mask >> 1, (mask >> 1) + 1][rng.gen_range(0..5)],
+        _ => rng.gen::<u128>() >> rng.gen_range(0..128),
+    };
+    value & mask
+}
+
+/// Every unsigned integer type.
+#[derive(Debug, Clone, Copy)]
+pub struct UnsignedCase;
+
+impl RoundTripCase for UnsignedCase {
+    type Value = (u8, u16, u32, u64, u128);
+
+    fn name(&self) -> &'static str {
+        "unsigned"
+    }
+
+    fn generate(&self, rng: &mut StdRng, _generator: &mut SpanGenerator) -> Self::Value {
+        (
+            edge_int(rng, u8::BITS, false) as u8,
+            edge_int(rng, u16::BITS, false) as u16,
+            edge_int(rng, u32::BITS, false) as u32,
+            edge_int(rng, u64::BITS, false) as u64,
+            edge_int(rng, u128::BITS, false),
+        )
+    }
+}
+
+/// Every signed integer type.
+#[derive(Debug, Clone, Copy)]
+pub struct SignedCase;
+
+impl RoundTripCase for SignedCase {
+    type Value = (i8, i16, i32, i64, i128);
+
+    fn name(&self) -> &'static str {
+        "signed"
+    }
+
+    fn generate(&self, rng: &mut StdRng, _generator: &mut SpanGenerator) -> Self::Value {
+        (
+            edge_int(rng, i8::BITS, true) as i8,
+            edge_int(rng, i16::BITS, true) as i16,
+            edge_int(rng, i32::BITS, true) as i32,
+            edge_int(rng, i64::BITS, true) as i64,
+            edge_int(rng, i128::BITS, true) as i128,
+        )
+    }
+}
+
+macro_rules! bitwise_float {
+    ($name:ident, $case:ident, $case_name:literal, $float:ty, $bits:ty, $quiet_nan:literal) => {
+        #[doc = concat!("`", stringify!($float), "` compared by its bits, so NaN payloads and ")]
+        #[doc = "the sign of zero must survive the round trip."]
+        #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(pub $float);
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.to_bits() == other.0.to_bits()
+            }
+        }
+
+        #[doc = concat!("`", stringify!($float), "` including NaNs, infinities, signed zeros ")]
+        #[doc = "and subnormals."]
+        #[derive(Debug, Clone, Copy)]
+        pub struct $case;
+
+        impl RoundTripCase for $case {
+            type Value = $name;
+
+            fn name(&self) -> &'static str {
+                $case_name
+            }
+
+            fn generate(&self, rng: &mut StdRng, _generator: &mut SpanGenerator) -> $name {
+                let special = [
+                    0.0,
+                    -0.0,
+                    <$float>::INFINITY,
+                    <$float>::NEG_INFINITY,
+                    <$float>::NAN,
+                    -<$float>::NAN,
+                    // A quiet NaN with a payload and a signalling NaN.
+                    <$float>::from_bits($quiet_nan | 1),
+                    <$float>::from_bits(<$float>::INFINITY.to_bits() | 1),
+                    <$float>::from_bits(1),
+                    <$float>::MIN_POSITIVE,
+                    <$float>::MAX,
+                    <$float>::MIN,
+                    <$float>::EPSILON,
+                ];
+                if rng.gen() {
+                    $name(special[rng.gen_range(0..special.len())])
+                } else {
+                    $name(<$float>::from_bits(rng.gen::<$bits>()))
+                }
+            }
+        }
+    };
+}
+
+bitwise_float!(BitwiseF32, F32Case, "f32", f32, u32, 0x7fc0_0000);
+bitwise_float!(BitwiseF64, F64Case, "f64", f64, u64, 0x7ff8_0000_0000_0000);
+
+/// `char` values of every UTF-8 length.
+#[derive(Debug, Clone, Copy)]
+pub struct CharCase;
+
+impl RoundTripCase for CharCase {
+    type Value = char;
+
+    fn name(&self) -> &'static str {
+        "char"
+    }
+
+    fn generate(&self, rng: &mut StdRng, _generator: &mut SpanGenerator) -> char {
+        if rng.gen() {
+            EDGE_CHARS[rng.gen_range(0..EDGE_CHARS.len())]
+        } else {
+            rng.gen()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnitStruct;
+
+/// Every zero-sized type serde knows, which postcard encodes as nothing at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZeroSized {
+    pub unit: (),
+    pub unit_struct: UnitStruct,
+    pub phantom: PhantomData<u64>,
+    pub empty: [u8; 0],
+}
+
+/// Batches of [`ZeroSized`] values, encoded as just their length.
+#[derive(Debug, Clone, Copy)]
+pub struct ZeroSizedCase;
+
+impl RoundTripCase for ZeroSizedCase {
+    type Value = ZeroSized;
+
+    fn name(&self) -> &'static str {
+        "zero-sized"
+    }
+
+    fn generate(&self, _rng: &mut StdRng, _generator: &mut SpanGenerator) -> ZeroSized {
+        ZeroSized {
+            unit: (),
+            unit_struct: UnitStruct,
+            phantom: PhantomData,
+            empty: [],
+        }
+    }
+}
+
+/// Every kind of enum variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Variants {
+    Unit,
+    Newtype(u32),
+    Tuple(i16, String),
+    Struct { id: u64, name: String },
+}
+
+impl_random_value!(enum Variants {
+    Unit,
+    Newtype(u32),
+    Tuple(i16, String),
+    Struct { id, name },
+});
+
+/// A byte buffer serialized with `serialize_bytes`, like `&[u8]` or `serde_bytes`, instead of
+/// as a sequence of `u8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+    }
+}
+
+impl RandomValue for Bytes {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Bytes(Vec::random_value(rng, budget))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postcard::to_allocvec;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::canonical::check_canonical_of;
+    use crate::case::CaseRun;
+    use crate::generators::{LengthStrategy, TimestampStrategy, TraceIdStrategy};
+    use crate::mutate::{fuzz_encoded, DecodeBounds};
+
+    #[test]
+    fn zero_sized_values_take_no_bytes() {
+        let zero_sized = ZeroSizedCase.generate(
+            &mut StdRng::seed_from_u64(0),
+            &mut SpanGenerator::new(
+                LengthStrategy::Uniform,
+                TimestampStrategy::Uniform,
+                TraceIdStrategy::Uniform,
+            ),
+        );
+        assert!(to_allocvec(&zero_sized).unwrap().is_empty());
+        assert_eq!(to_allocvec(&vec![zero_sized; 300]).unwrap(), [0xac, 0x02]);
+        assert!(ZeroSizedCase.empty_values());
+    }
+
+    #[test]
+    fn zero_sized_batches_survive_mutation() {
+        let batch = vec![
+            ZeroSized {
+                unit: (),
+                unit_struct: UnitStruct,
+                phantom: PhantomData,
+                empty: [],
+            };
+            5
+        ];
+        let stats = fuzz_encoded::<ZeroSized, _>(
+            &mut StdRng::seed_from_u64(1),
+            &to_allocvec(&batch).unwrap(),
+            300,
+            false,
+            check_canonical_of::<Vec<ZeroSized>>,
+            DecodeBounds {
+                nested_sequences: 0,
+                empty_values: true,
+            },
+        )
+        .unwrap();
+        assert!(stats.skipped > 0, "{stats:?}");
+        assert_eq!(stats.rejected + stats.accepted + stats.skipped, 300);
+    }
+
+    /// What `--case data-model --mode mutate --seed 1` runs, on shorter batches.
+    #[test]
+    fn group_passes_mutate_mode() {
+        let registry = Registry::builtin();
+        let options = CaseRun {
+            batch_len: 0..=64,
+            count_allocs: false,
+            shrink: false,
+            diagnose: false,
+            mutations: 50,
+            require_canonical: false,
+        };
+        let cases = registry.resolve(GROUP).unwrap();
+        assert!(cases.iter().any(|case| case.name() == "zero-sized"));
+        for case in cases {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut generator = SpanGenerator::new(
+                LengthStrategy::Mixed,
+                TimestampStrategy::Mixed,
+                TraceIdStrategy::Mixed,
+            );
+            for _ in 0..3 {
+                if let Err(failure) = case.run(&mut rng, &mut generator, &options) {
+                    panic!("case `{}` failed: {}", case.name(), failure.error);
+                }
+            }
+        }
+    }
+}
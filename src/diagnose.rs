@@ -0,0 +1,536 @@
+//! Explains round-trip failures caused by serde features postcard cannot support.
+//!
+//! postcard is not self-describing: fields are encoded by position without names, and the
+//! decoder must know the type of every value up front. Serde attributes that skip fields,
+//! serialize maps of unknown length or need to look ahead at the data break that silently or
+//! with unhelpful errors. [`diagnose`] finds them by serializing values through a [`Probe`],
+//! [`classify`] maps postcard's errors to them.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+/// A serde feature that is incompatible with postcard.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Diagnostic {
+    /// A struct field was skipped while serializing, e.g. by `skip_serializing_if`.
+    SkippedField { path: String },
+    /// A sequence or map was serialized without a length, e.g. by `flatten`.
+    UnknownLength { path: String },
+    /// Deserializing needs `deserialize_any`, e.g. for `untagged` or internally tagged enums.
+    DeserializeAny,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::SkippedField { path } => write!(
+                f,
+                "field `{path}` uses skip_serializing_if: encoding is not positional, the \
+                 decoder reads the next field in its place"
+            ),
+            Diagnostic::UnknownLength { path } => write!(
+                f,
+                "`{path}` is serialized without a length, e.g. because it uses flatten: \
+                 postcard needs the length of sequences and maps up front"
+            ),
+            Diagnostic::DeserializeAny => f.write_str(
+                "the Deserialize impl calls deserialize_any, e.g. for an untagged or internally \
+                 tagged enum or a flattened field: postcard is not self-describing",
+            ),
+        }
+    }
+}
+
+/// The diagnostic behind a postcard error, if it is one of the known incompatibilities.
+pub fn classify(error: &postcard::Error) -> Option<Diagnostic> {
+    match error {
+        postcard::Error::WontImplement => Some(Diagnostic::DeserializeAny),
+        postcard::Error::SerializeSeqLengthUnknown => Some(Diagnostic::UnknownLength {
+            path: "value".to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Serializes `values` through a [`Probe`] and returns the incompatibilities found.
+pub fn diagnose<T: Serialize>(values: &[T]) -> BTreeSet<Diagnostic> {
+    let mut probe = Probe::default();
+    for value in values {
+        // The probe itself never fails, errors come from `Serialize` impls and are reported
+        // by the round trip.
+        let _ = value.serialize(&mut probe);
+        probe.path.clear();
+    }
+    probe.found
+}
+
+/// A serializer that produces nothing and records the incompatibilities it sees.
+///
+/// Like postcard, it is not human-readable, so types pick their binary representation.
+#[derive(Debug, Default)]
+pub struct Probe {
+    /// The name of the outermost struct and the field names leading to the value being
+    /// serialized, nested structs push an empty segment.
+    path: Vec<&'static str>,
+    found: BTreeSet<Diagnostic>,
+}
+
+impl Probe {
+    fn path(&self) -> String {
+        let segments: Vec<&str> = self
+            .path
+            .iter()
+            .copied()
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        if segments.is_empty() {
+            "value".to_string()
+        } else {
+            segments.join(".")
+        }
+    }
+
+    fn unknown_length(&mut self, len: Option<usize>) {
+        if len.is_none() {
+            let path = self.path();
+            self.found.insert(Diagnostic::UnknownLength { path });
+        }
+    }
+
+    fn field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ProbeError> {
+        self.path.push(key);
+        let result = value.serialize(&mut *self);
+        self.path.pop();
+        result
+    }
+
+    fn push_container(&mut self, name: &'static str) {
+        let segment = if self.path.is_empty() { name } else { "" };
+        self.path.push(segment);
+    }
+
+    fn skip_field(&mut self, key: &'static str) {
+        self.path.push(key);
+        let path = self.path();
+        self.path.pop();
+        self.found.insert(Diagnostic::SkippedField { path });
+    }
+}
+
+#[derive(Debug)]
+pub struct ProbeError(String);
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl ser::Error for ProbeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        ProbeError(message.to_string())
+    }
+}
+
+macro_rules! ignore_scalars {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, _value: $ty) -> Result<(), ProbeError> {
+                Ok(())
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    ignore_scalars!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_i128(i128),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_unit_struct(&'static str),
+    );
+
+    fn serialize_none(self) -> Result<(), ProbeError> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), ProbeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+    ) -> Result<(), ProbeError> {
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), ProbeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), ProbeError> {
+        self.field(variant, value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, ProbeError> {
+        self.unknown_length(len);
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, ProbeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, _len: usize) -> Result<Self, ProbeError> {
+        self.push_container(name);
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, ProbeError> {
+        self.path.push(variant);
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, ProbeError> {
+        self.unknown_length(len);
+        Ok(self)
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self, ProbeError> {
+        self.push_container(name);
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, ProbeError> {
+        self.path.push(variant);
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeSeq for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        self.path.pop();
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        self.path.pop();
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ProbeError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ProbeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ProbeError> {
+        self.field(key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), ProbeError> {
+        Probe::skip_field(self, key);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        self.path.pop();
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Probe {
+    type Ok = ();
+    type Error = ProbeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ProbeError> {
+        self.field(key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), ProbeError> {
+        Probe::skip_field(self, key);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), ProbeError> {
+        self.path.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::test_spans;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Plain {
+        id: u32,
+        note: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Skipping {
+        id: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Extra {
+        note: u32,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Flattened {
+        id: u32,
+        #[serde(flatten)]
+        extra: Extra,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Outer {
+        inner: Flattened,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(untagged)]
+    enum Untagged {
+        Number(u32),
+        Text(String),
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(tag = "type")]
+    enum InternallyTagged {
+        Point { x: u32 },
+        Empty,
+    }
+
+    /// The diagnostic of the postcard error decoding what `value` encodes to.
+    #[track_caller]
+    fn classify_round_trip<T>(value: &T) -> Option<Diagnostic>
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        let encoded = postcard::to_allocvec(value).unwrap();
+        classify(&postcard::from_bytes::<T>(&encoded).err().unwrap())
+    }
+
+    #[test]
+    fn plain_struct_has_no_diagnostic() {
+        let values = [
+            Plain { id: 1, note: None },
+            Plain {
+                id: 2,
+                note: Some("x".to_string()),
+            },
+        ];
+        assert_eq!(diagnose(&values), BTreeSet::new());
+        assert_eq!(diagnose(&test_spans(3)), BTreeSet::new());
+        let encoded = postcard::to_allocvec(&values[0]).unwrap();
+        assert_eq!(
+            postcard::from_bytes(&encoded),
+            Ok(Plain { id: 1, note: None })
+        );
+        assert_eq!(classify(&postcard::Error::DeserializeUnexpectedEnd), None);
+    }
+
+    #[test]
+    fn skipped_field() {
+        let present = Skipping {
+            id: 1,
+            note: Some("x".to_string()),
+        };
+        assert_eq!(diagnose(&[present]), BTreeSet::new());
+        let skipped = Skipping { id: 1, note: None };
+        assert_eq!(
+            diagnose(&[skipped]),
+            BTreeSet::from([Diagnostic::SkippedField {
+                path: "Skipping.note".to_string(),
+            }])
+        );
+    }
+
+    #[test]
+    fn flattened_field_has_unknown_length() {
+        let flattened = Flattened {
+            id: 1,
+            extra: Extra { note: 2 },
+        };
+        assert_eq!(
+            postcard::to_allocvec(&flattened),
+            Err(postcard::Error::SerializeSeqLengthUnknown)
+        );
+        assert_eq!(
+            classify(&postcard::Error::SerializeSeqLengthUnknown),
+            Some(Diagnostic::UnknownLength {
+                path: "value".to_string(),
+            })
+        );
+        let outer = Outer { inner: flattened };
+        assert_eq!(
+            diagnose(&[outer]),
+            BTreeSet::from([Diagnostic::UnknownLength {
+                path: "Outer.inner".to_string(),
+            }])
+        );
+    }
+
+    #[test]
+    fn untagged_enum_needs_deserialize_any() {
+        assert_eq!(diagnose(&[Untagged::Number(1)]), BTreeSet::new());
+        assert_eq!(
+            classify_round_trip(&Untagged::Number(1)),
+            Some(Diagnostic::DeserializeAny)
+        );
+        assert_eq!(
+            classify_round_trip(&Untagged::Text("x".to_string())),
+            Some(Diagnostic::DeserializeAny)
+        );
+    }
+
+    #[test]
+    fn internally_tagged_enum_needs_deserialize_any() {
+        assert_eq!(
+            classify_round_trip(&InternallyTagged::Point { x: 1 }),
+            Some(Diagnostic::DeserializeAny)
+        );
+        assert_eq!(
+            classify_round_trip(&InternallyTagged::Empty),
+            Some(Diagnostic::DeserializeAny)
+        );
+    }
+}
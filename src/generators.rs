@@ -0,0 +1,542 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use rand::Rng;
+
+use crate::{DateTime, TraceId};
+
+/// Fixed "now" of the `near-now` timestamps, 2023-11-14T22:13:20Z.
+///
+/// Not the wall clock, so a seed generates the same batch on every run.
+const REFERENCE_NOW_NANOS: i64 = 1_700_000_000_000_000_000;
+const NANOS_PER_HOUR: i64 = 60 * 60 * 1_000_000_000;
+/// `near-now` timestamps are at most this many hours away from `REFERENCE_NOW_NANOS`.
+const NEAR_NOW_HOURS: i64 = 48;
+
+const EDGE_TIMESTAMPS: [i64; 7] = [0, 1, -1, i64::MIN, i64::MIN + 1, i64::MAX - 1, i64::MAX];
+
+/// How `span_timestamp` values are drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampStrategy {
+    /// Uniform over all of `i64`.
+    Uniform,
+    /// Values next to the length boundaries of the zigzag varint, e.g. ±63/64 or ±8191/8192,
+    /// and next to `i64::MIN` and `i64::MAX`.
+    VarintBoundary,
+    /// Realistic nanosecond timestamps within hours of a fixed "now".
+    NearNow,
+    /// `0`, `±1`, `i64::MIN`, `i64::MAX` and their neighbours.
+    Edge,
+    /// Picks one of the strategies above for every value.
+    Mixed,
+}
+
+impl TimestampStrategy {
+    const ALL: [TimestampStrategy; 5] = [
+        TimestampStrategy::Uniform,
+        TimestampStrategy::VarintBoundary,
+        TimestampStrategy::NearNow,
+        TimestampStrategy::Edge,
+        TimestampStrategy::Mixed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TimestampStrategy::Uniform => "uniform",
+            TimestampStrategy::VarintBoundary => "varint-boundary",
+            TimestampStrategy::NearNow => "near-now",
+            TimestampStrategy::Edge => "edge",
+            TimestampStrategy::Mixed => "mixed",
+        }
+    }
+
+    /// Draws a timestamp, returning it with the strategy that produced it.
+    pub fn generate<R: Rng>(self, rng: &mut R) -> (DateTime, TimestampStrategy) {
+        let nanos = match self {
+            TimestampStrategy::Uniform => rng.gen(),
+            TimestampStrategy::VarintBoundary => {
+                // Zigzag maps 2^(7k - 1) - 1 and -2^(7k - 1) to the largest values fitting in
+                // k bytes, their neighbours need one more byte. The 10 bytes of the longest
+                // varint end at `i64::MAX` and `i64::MIN`.
+                let (high, low) = match rng.gen_range(1..=10) {
+                    10 => (i64::MAX, i64::MIN),
+                    bytes => {
+                        let boundary = 1i64 << (7 * bytes - 1);
+                        (boundary - 1, -boundary)
+                    }
+                };
+                let nanos = if rng.gen() { high } else { low };
+                nanos.saturating_add(rng.gen_range(-1..=1))
+            }
+            TimestampStrategy::NearNow => {
+                let max_offset = NEAR_NOW_HOURS * NANOS_PER_HOUR;
+                REFERENCE_NOW_NANOS + rng.gen_range(-max_offset..=max_offset)
+            }
+            TimestampStrategy::Edge => EDGE_TIMESTAMPS[rng.gen_range(0..EDGE_TIMESTAMPS.len())],
+            TimestampStrategy::Mixed => {
+                // Every strategy but `Mixed`, which is last.
+                let strategy =
+                    TimestampStrategy::ALL[rng.gen_range(0..TimestampStrategy::ALL.len() - 1)];
+                return strategy.generate(rng);
+            }
+        };
+        (DateTime::from_timestamp_nanos(nanos), self)
+    }
+}
+
+impl FromStr for TimestampStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        TimestampStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = TimestampStrategy::ALL
+                    .map(|strategy| strategy.name())
+                    .into();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for TimestampStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// `boundary` lengths are within one of `1 << (7 * k)` for a `k` up to this, i.e. next to the
+/// lengths where postcard's varint length prefix grows a byte.
+const MAX_BOUNDARY_PREFIX_BYTES: u32 = 2;
+
+/// How the number of spans of a batch is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthStrategy {
+    /// Always the same length, regardless of `--min-len` and `--max-len`.
+    Fixed(usize),
+    /// Uniform over `--min-len..=--max-len`.
+    Uniform,
+    /// `0`, `1`, `127`, `128`, `16383`, `16384` and their neighbours, regardless of
+    /// `--min-len` and `--max-len`.
+    Boundary,
+    /// Log-uniform over `--min-len..=--max-len`, so short and huge batches are equally likely.
+    LogUniform,
+    /// Picks one of `uniform`, `boundary` and `log-uniform` for every batch.
+    Mixed,
+}
+
+impl LengthStrategy {
+    /// The strategies `mixed` picks from, `Fixed` takes a parameter and is not among them.
+    const ALL: [LengthStrategy; 4] = [
+        LengthStrategy::Uniform,
+        LengthStrategy::Boundary,
+        LengthStrategy::LogUniform,
+        LengthStrategy::Mixed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            LengthStrategy::Fixed(_) => "fixed",
+            LengthStrategy::Uniform => "uniform",
+            LengthStrategy::Boundary => "boundary",
+            LengthStrategy::LogUniform => "log-uniform",
+            LengthStrategy::Mixed => "mixed",
+        }
+    }
+
+    /// Draws a batch length, returning it with the strategy that produced it.
+    pub fn generate<R: Rng>(
+        self,
+        rng: &mut R,
+        range: RangeInclusive<usize>,
+    ) -> (usize, LengthStrategy) {
+        let len = match self {
+            LengthStrategy::Fixed(len) => len,
+            LengthStrategy::Uniform => rng.gen_range(range),
+            LengthStrategy::Boundary => {
+                let boundary = 1usize << (7 * rng.gen_range(0..=MAX_BOUNDARY_PREFIX_BYTES));
+                boundary.saturating_add_signed(rng.gen_range(-1..=1))
+            }
+            LengthStrategy::LogUniform => {
+                let (min, max) = (*range.start() as f64, *range.end() as f64);
+                let len = rng.gen_range((min + 1.0).ln()..=(max + 1.0).ln()).exp() - 1.0;
+                (len.round() as usize).clamp(*range.start(), *range.end())
+            }
+            LengthStrategy::Mixed => {
+                // Every strategy but `Mixed`, which is last.
+                let strategy = LengthStrategy::ALL[rng.gen_range(0..LengthStrategy::ALL.len() - 1)];
+                return strategy.generate(rng, range);
+            }
+        };
+        (len, self)
+    }
+}
+
+impl FromStr for LengthStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Some(len) = name.strip_prefix("fixed:") {
+            return len
+                .parse()
+                .map(LengthStrategy::Fixed)
+                .map_err(|error| format!("invalid fixed length: {error}"));
+        }
+        LengthStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = LengthStrategy::ALL.map(|strategy| strategy.name()).into();
+                format!("expected `fixed:<N>` or one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for LengthStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LengthStrategy::Fixed(len) => write!(f, "fixed:{len}"),
+            strategy => f.write_str(strategy.name()),
+        }
+    }
+}
+
+/// Number of trace IDs in the pool of the `duplicates` strategy.
+const DUPLICATE_POOL_SIZE: usize = 4;
+
+/// How `trace_id` values are drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceIdStrategy {
+    /// 16 uniform random bytes.
+    Uniform,
+    /// The all-zero ID.
+    Zero,
+    /// The all-0xFF ID.
+    Max,
+    /// Consecutive IDs counting up from a random start, wrapping around.
+    Sequential,
+    /// Every byte drawn from `0x00`, `0x01`, `0x80` and `0xFF`.
+    LowEntropy,
+    /// W3C trace context style IDs converted from 64-bit IDs, the upper 8 bytes are zero.
+    W3c,
+    /// IDs whose base64 encoding is all `+` and `/`, up to the last data character.
+    Base64Heavy,
+    /// The previous ID with a single bit flipped.
+    OneBitApart,
+    /// IDs drawn from a small pool, so a batch contains duplicates.
+    Duplicates,
+    /// Picks one of the strategies above for every value.
+    Mixed,
+}
+
+impl TraceIdStrategy {
+    const ALL: [TraceIdStrategy; 10] = [
+        TraceIdStrategy::Uniform,
+        TraceIdStrategy::Zero,
+        TraceIdStrategy::Max,
+        TraceIdStrategy::Sequential,
+        TraceIdStrategy::LowEntropy,
+        TraceIdStrategy::W3c,
+        TraceIdStrategy::Base64Heavy,
+        TraceIdStrategy::OneBitApart,
+        TraceIdStrategy::Duplicates,
+        TraceIdStrategy::Mixed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TraceIdStrategy::Uniform => "uniform",
+            TraceIdStrategy::Zero => "zero",
+            TraceIdStrategy::Max => "max",
+            TraceIdStrategy::Sequential => "sequential",
+            TraceIdStrategy::LowEntropy => "low-entropy",
+            TraceIdStrategy::W3c => "w3c",
+            TraceIdStrategy::Base64Heavy => "base64-heavy",
+            TraceIdStrategy::OneBitApart => "one-bit-apart",
+            TraceIdStrategy::Duplicates => "duplicates",
+            TraceIdStrategy::Mixed => "mixed",
+        }
+    }
+
+    /// Draws a trace ID, returning it with the strategy that produced it.
+    ///
+    /// `state` carries the counter, previous ID and pool of the stateful strategies.
+    pub fn generate<R: Rng>(
+        self,
+        rng: &mut R,
+        state: &mut TraceIdState,
+    ) -> (TraceId, TraceIdStrategy) {
+        let bytes = match self {
+            TraceIdStrategy::Uniform => return (TraceId::random(rng), self),
+            TraceIdStrategy::Zero => [0u8; 16],
+            TraceIdStrategy::Max => [0xffu8; 16],
+            TraceIdStrategy::Sequential => {
+                let counter = state.counter.get_or_insert_with(|| rng.gen());
+                *counter = counter.wrapping_add(1);
+                counter.to_be_bytes()
+            }
+            TraceIdStrategy::LowEntropy => {
+                let mut bytes = [0u8; 16];
+                bytes.fill_with(|| [0x00, 0x01, 0x80, 0xff][rng.gen_range(0..4)]);
+                bytes
+            }
+            TraceIdStrategy::W3c => u128::from(rng.gen::<u64>()).to_be_bytes(),
+            TraceIdStrategy::Base64Heavy => {
+                // 21 sextets of 62 (`+`) or 63 (`/`) carry 126 bits, the 22nd sextet carries
+                // the last 2 bits, which are `0b11` for both.
+                let bits = (0..21).fold(0u128, |bits, _| (bits << 6) | rng.gen_range(62..=63));
+                ((bits << 2) | 0b11).to_be_bytes()
+            }
+            TraceIdStrategy::OneBitApart => {
+                let previous = state.previous.unwrap_or_else(|| TraceId::random(rng));
+                let bit = rng.gen_range(0..128);
+                let bytes = (u128::from_be_bytes(previous.0) ^ (1 << bit)).to_be_bytes();
+                state.previous = Some(TraceId::new(bytes));
+                bytes
+            }
+            TraceIdStrategy::Duplicates => {
+                if state.pool.is_empty() {
+                    state.pool = (0..DUPLICATE_POOL_SIZE)
+                        .map(|_| TraceId::random(rng))
+                        .collect();
+                }
+                state.pool[rng.gen_range(0..state.pool.len())].0
+            }
+            TraceIdStrategy::Mixed => {
+                // Every strategy but `Mixed`, which is last.
+                let strategy =
+                    TraceIdStrategy::ALL[rng.gen_range(0..TraceIdStrategy::ALL.len() - 1)];
+                return strategy.generate(rng, state);
+            }
+        };
+        (TraceId::new(bytes), self)
+    }
+}
+
+impl FromStr for TraceIdStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        TraceIdStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = TraceIdStrategy::ALL.map(|strategy| strategy.name()).into();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for TraceIdStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// State of the stateful trace ID strategies, kept for the span batch being generated.
+#[derive(Debug, Clone, Default)]
+pub struct TraceIdState {
+    counter: Option<u128>,
+    previous: Option<TraceId>,
+    pool: Vec<TraceId>,
+}
+
+/// How many values every strategy produced, reported in the run summary.
+#[derive(Debug, Clone, Default)]
+pub struct GeneratorStats {
+    pub timestamps: BTreeMap<&'static str, u64>,
+    pub trace_ids: BTreeMap<&'static str, u64>,
+    pub lengths: BTreeMap<&'static str, u64>,
+}
+
+impl GeneratorStats {
+    pub fn merge(&mut self, other: &GeneratorStats) {
+        for (bucket, count) in &other.timestamps {
+            *self.timestamps.entry(bucket).or_default() += count;
+        }
+        for (bucket, count) in &other.trace_ids {
+            *self.trace_ids.entry(bucket).or_default() += count;
+        }
+        for (bucket, count) in &other.lengths {
+            *self.lengths.entry(bucket).or_default() += count;
+        }
+    }
+}
+
+/// Draws the fields of random spans with the configured strategies.
+#[derive(Debug, Clone)]
+pub struct SpanGenerator {
+    pub lengths: LengthStrategy,
+    pub timestamps: TimestampStrategy,
+    pub trace_ids: TraceIdStrategy,
+    pub stats: GeneratorStats,
+    trace_id_state: TraceIdState,
+}
+
+impl SpanGenerator {
+    pub fn new(
+        lengths: LengthStrategy,
+        timestamps: TimestampStrategy,
+        trace_ids: TraceIdStrategy,
+    ) -> Self {
+        SpanGenerator {
+            lengths,
+            timestamps,
+            trace_ids,
+            stats: GeneratorStats::default(),
+            trace_id_state: TraceIdState::default(),
+        }
+    }
+
+    /// Number of spans of the next batch.
+    pub fn batch_len<R: Rng>(&mut self, rng: &mut R, range: RangeInclusive<usize>) -> usize {
+        let (len, bucket) = self.lengths.generate(rng, range);
+        *self.stats.lengths.entry(bucket.name()).or_default() += 1;
+        len
+    }
+
+    pub fn trace_id<R: Rng>(&mut self, rng: &mut R) -> TraceId {
+        let (trace_id, bucket) = self.trace_ids.generate(rng, &mut self.trace_id_state);
+        *self.stats.trace_ids.entry(bucket.name()).or_default() += 1;
+        trace_id
+    }
+
+    pub fn timestamp<R: Rng>(&mut self, rng: &mut R) -> DateTime {
+        let (timestamp, bucket) = self.timestamps.generate(rng);
+        *self.stats.timestamps.entry(bucket.name()).or_default() += 1;
+        timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn varint_boundary_timestamps() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let drawn: BTreeSet<i64> = (0..5000)
+            .map(|_| {
+                let (timestamp, strategy) = TimestampStrategy::VarintBoundary.generate(&mut rng);
+                assert_eq!(strategy, TimestampStrategy::VarintBoundary);
+                timestamp.into_timestamp_nanos()
+            })
+            .collect();
+        for nanos in [
+            63,
+            64,
+            -64,
+            -65,
+            8191,
+            8192,
+            -8192,
+            -8193,
+            i64::MAX,
+            i64::MAX - 1,
+            i64::MIN,
+            i64::MIN + 1,
+        ] {
+            assert!(drawn.contains(&nanos), "{nanos} not drawn");
+        }
+        // Every value is within one of a boundary, so its zigzag value has a multiple of 7
+        // bits, or one more, or it is one of the longest varints.
+        for nanos in drawn {
+            let zigzag = ((nanos << 1) ^ (nanos >> 63)) as u64;
+            let bits = 64 - zigzag.leading_zeros();
+            assert!(
+                bits % 7 <= 1 || bits >= 63,
+                "{nanos} is far from a boundary"
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_timestamps_report_their_strategy() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut generator = SpanGenerator::new(
+            LengthStrategy::Uniform,
+            TimestampStrategy::Mixed,
+            TraceIdStrategy::Uniform,
+        );
+        for _ in 0..200 {
+            generator.timestamp(&mut rng);
+        }
+        let buckets: Vec<_> = generator.stats.timestamps.keys().copied().collect();
+        assert_eq!(buckets, ["edge", "near-now", "uniform", "varint-boundary"]);
+        assert_eq!(generator.stats.timestamps.values().sum::<u64>(), 200);
+    }
+
+    #[track_caller]
+    fn draw_trace_ids(strategy: TraceIdStrategy, count: usize, seed: u64) -> Vec<TraceId> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut state = TraceIdState::default();
+        (0..count)
+            .map(|_| {
+                let (trace_id, drawn) = strategy.generate(&mut rng, &mut state);
+                assert_eq!(drawn, strategy);
+                trace_id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_and_max_trace_ids() {
+        for trace_id in draw_trace_ids(TraceIdStrategy::Zero, 10, 3) {
+            assert_eq!(trace_id.as_bytes(), [0; 16]);
+        }
+        for trace_id in draw_trace_ids(TraceIdStrategy::Max, 10, 3) {
+            assert_eq!(trace_id.as_bytes(), [0xff; 16]);
+        }
+    }
+
+    #[test]
+    fn one_bit_apart_trace_ids() {
+        let trace_ids = draw_trace_ids(TraceIdStrategy::OneBitApart, 100, 4);
+        for pair in trace_ids.windows(2) {
+            let xor = u128::from_be_bytes(pair[0].as_bytes().try_into().unwrap())
+                ^ u128::from_be_bytes(pair[1].as_bytes().try_into().unwrap());
+            assert_eq!(xor.count_ones(), 1, "{pair:?}");
+        }
+    }
+
+    #[test]
+    fn base64_heavy_trace_ids_round_trip() {
+        for trace_id in draw_trace_ids(TraceIdStrategy::Base64Heavy, 100, 5) {
+            let mut buffer = [0; TraceId::BASE64_LENGTH];
+            let base64 = trace_id.encode_base64(&mut buffer);
+            assert!(
+                base64[..21]
+                    .bytes()
+                    .all(|byte| byte == b'+' || byte == b'/'),
+                "{base64}"
+            );
+            assert!(base64.ends_with("w=="), "{base64}");
+
+            let json = serde_json::to_string(&trace_id).unwrap();
+            assert_eq!(json, format!("\"{base64}\""));
+            assert_eq!(serde_json::from_str::<TraceId>(&json).unwrap(), trace_id);
+            let encoded = postcard::to_allocvec(&trace_id).unwrap();
+            assert_eq!(postcard::from_bytes::<TraceId>(&encoded), Ok(trace_id));
+        }
+    }
+
+    #[test]
+    fn boundary_lengths() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let drawn: BTreeSet<usize> = (0..500)
+            .map(|_| LengthStrategy::Boundary.generate(&mut rng, 5..=10).0)
+            .collect();
+        assert_eq!(
+            drawn,
+            BTreeSet::from([0, 1, 2, 127, 128, 129, 16383, 16384, 16385])
+        );
+    }
+}
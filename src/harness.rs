@@ -0,0 +1,426 @@
+//! The randomized check loop of the `force_check_postcard` binary.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use postcard::to_allocvec;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::batch::Batch;
+use crate::canonical::{check_canonical, check_variants};
+use crate::case::{CaseRun, DynRoundTripCase, Registry, RoundTripCase, SpanCase};
+use crate::check::{check_all, CheckError};
+use crate::cli::{Args, Mode};
+use crate::concat::{check_concatenated, ConcatStats, MAX_MESSAGES};
+use crate::corpus::CorpusEntry;
+use crate::diagnose::{classify, diagnose};
+use crate::generators::{GeneratorStats, SpanGenerator};
+use crate::mutate::{abandoned_decoders, fuzz_encoded, DecodeBounds, MutationStats};
+use crate::roundtrip::check_batch;
+use crate::shrink::shrink_spans;
+use crate::size::check_sizes;
+use crate::stream::{check_stream, StreamStats, MAX_FRAMES};
+use crate::trace::explain;
+use crate::{counting_alloc, random_spans, Span};
+
+/// Derives the seed of a single iteration from the master seed.
+///
+/// Uses the splitmix64 finalizer so neighbouring iterations get unrelated seeds.
+fn iteration_seed(master_seed: u64, iteration: u64) -> u64 {
+    let mut z = master_seed.wrapping_add(iteration.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Counters reported at the end of a run.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub iterations: u64,
+    /// Spans, or values of the other cases, that were checked.
+    pub values: u64,
+    pub bytes: u64,
+    pub failures: u64,
+    pub mutations: MutationStats,
+    pub stream: StreamStats,
+    pub concat: ConcatStats,
+    /// Allocations of encoding and decoding the measured batches.
+    pub encode_allocations: u64,
+    pub decode_allocations: u64,
+    /// The part of the allocations above made by the values, see
+    /// [`counting_alloc::measure_values`].
+    pub encode_value_allocations: u64,
+    pub decode_value_allocations: u64,
+    /// Batches whose allocations were counted.
+    pub measured_batches: u64,
+    pub generated: GeneratorStats,
+    /// Failed batches per case, for cases other than `span`.
+    pub failed_cases: BTreeMap<&'static str, u64>,
+}
+
+impl Summary {
+    pub fn merge(&mut self, other: Summary) {
+        self.iterations += other.iterations;
+        self.values += other.values;
+        self.bytes += other.bytes;
+        self.failures += other.failures;
+        self.mutations.merge(&other.mutations);
+        self.stream.merge(&other.stream);
+        self.concat.merge(&other.concat);
+        self.encode_allocations += other.encode_allocations;
+        self.decode_allocations += other.decode_allocations;
+        self.encode_value_allocations += other.encode_value_allocations;
+        self.decode_value_allocations += other.decode_value_allocations;
+        self.measured_batches += other.measured_batches;
+        self.generated.merge(&other.generated);
+        for (case, count) in other.failed_cases {
+            *self.failed_cases.entry(case).or_default() += count;
+        }
+    }
+
+    /// Average allocations of encoding and of decoding a span or value, without the sequences
+    /// around it.
+    pub fn allocations_per_value(&self) -> (f64, f64) {
+        let values = self.values.max(1) as f64;
+        (
+            self.encode_value_allocations as f64 / values,
+            self.decode_value_allocations as f64 / values,
+        )
+    }
+
+    /// Average allocations of encoding and of decoding the sequences of a batch, the
+    /// allocations of the values not counted.
+    pub fn container_allocations_per_batch(&self) -> (f64, f64) {
+        let batches = self.measured_batches.max(1) as f64;
+        (
+            self.encode_allocations
+                .saturating_sub(self.encode_value_allocations) as f64
+                / batches,
+            self.decode_allocations
+                .saturating_sub(self.decode_value_allocations) as f64
+                / batches,
+        )
+    }
+}
+
+/// Prints `error`, records the batch in the corpus and, unless disabled, prints the minimal
+/// batch that still fails `check`.
+///
+/// `check` must be the check that failed with `error`, it is repeated on single spans and while
+/// shrinking. Batches of other shapes than flat keep their shape, only their spans are shrunk.
+fn report_failure<C>(args: &Args, seed: u64, batch: &Batch, error: &CheckError, check: C)
+where
+    C: Fn(&Batch) -> Result<Vec<u8>, CheckError>,
+{
+    let check = |spans: &[Span]| check(&batch.with_spans(spans.to_vec()));
+    eprintln!(
+        "seed {seed} failed: {error}\n  replay with `{}`",
+        args.replay_flags(seed)
+    );
+    let spans: Vec<Span> = batch.spans().into_iter().cloned().collect();
+    let mut diagnostics = diagnose(&spans);
+    diagnostics.extend(error.postcard_error().and_then(classify));
+    for diagnostic in &diagnostics {
+        eprintln!("  {diagnostic}");
+    }
+    // The first span that fails on its own, the whole batch may only fail together.
+    let culprit = spans
+        .iter()
+        .find(|span| check(std::slice::from_ref(*span)).is_err())
+        .or(spans.first());
+    if let Some(span) = culprit {
+        print_indented(&explain(span));
+    }
+    if let Some(corpus) = &args.corpus {
+        match CorpusEntry::new(seed, batch, error).write_to_dir(corpus) {
+            Ok(path) => eprintln!("  recorded in {}", path.display()),
+            Err(error) => eprintln!("  failed to record in {}: {error}", corpus.display()),
+        }
+    }
+    if args.no_shrink {
+        return;
+    }
+    let minimal = shrink_spans(&spans, |candidate| check(candidate).is_err());
+    let minimal_error = check(&minimal).expect_err("shrinking must preserve the failure");
+    let outline = match batch {
+        Batch::Flat(_) => String::new(),
+        _ => format!(", {}", batch.with_spans(minimal.clone()).outline()),
+    };
+    eprintln!(
+        "minimal failing batch ({} of {} spans{outline}): {minimal:?}\n  {minimal_error}",
+        minimal.len(),
+        spans.len()
+    );
+}
+
+fn print_indented(text: &str) {
+    for line in text.lines() {
+        eprintln!("  {line}");
+    }
+}
+
+/// Runs the checks of `args.mode` on the batch of `seed`, reporting any failure.
+///
+/// Returns whether all checks passed.
+///
+/// # Panics
+///
+/// If `registry` has no case named `args.case`.
+pub fn run_seed(args: &Args, registry: &Registry, seed: u64, summary: &mut Summary) -> bool {
+    if args.case != RoundTripCase::name(&SpanCase) || args.mode == Mode::Diagnose {
+        let cases = registry
+            .resolve(&args.case)
+            .unwrap_or_else(|| panic!("no round-trip case named `{}`", args.case));
+        // Every case starts from the seed, so `--case <name>` replays a case of a group.
+        let mut passed = true;
+        for case in cases {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut generator = SpanGenerator::new(args.lengths, args.timestamps, args.trace_ids);
+            passed &= run_case(args, case, seed, &mut rng, &mut generator, summary);
+        }
+        return passed;
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut generator = SpanGenerator::new(args.lengths, args.timestamps, args.trace_ids);
+    let spans = random_spans(&mut rng, args.batch_len(), &mut generator);
+    summary.generated.merge(&generator.stats);
+    let batch = Batch::from_spans(&mut rng, args.shape, spans);
+    let check = |batch: &Batch| match args.mode {
+        // The checks get an RNG of their own, so a failure repeats while shrinking.
+        Mode::RoundTrip => check_all(&mut StdRng::seed_from_u64(seed), batch),
+        _ => {
+            let encoded = check_batch(batch)?;
+            check_sizes(batch, &encoded)?;
+            Ok(encoded)
+        }
+    };
+    let encoded = match check(&batch) {
+        Ok(encoded) => encoded,
+        Err(error) => {
+            report_failure(args, seed, &batch, &error, check);
+            return false;
+        }
+    };
+    summary.values += batch.spans().len() as u64;
+    summary.bytes += encoded.len() as u64;
+    if args.count_allocs {
+        let (_, encode) = counting_alloc::measure(|| to_allocvec(&batch));
+        let (_, decode) = counting_alloc::measure(|| Batch::decode(args.shape, &encoded));
+        summary.encode_allocations += encode.allocations;
+        summary.decode_allocations += decode.allocations;
+        let (encode, decode) = counting_alloc::measure_values(batch.spans());
+        summary.encode_value_allocations += encode.allocations;
+        summary.decode_value_allocations += decode.allocations;
+        summary.measured_batches += 1;
+    }
+    let checked = match args.mode {
+        // Flat batches already passed all checks above. The legacy encoding only ever
+        // existed for `Vec<Span>`.
+        Mode::RoundTrip => Ok(MutationStats::default()),
+        Mode::Mutate => fuzz_encoded::<Span, _>(
+            &mut rng,
+            &encoded,
+            args.mutations,
+            args.require_canonical,
+            check_canonical,
+            DecodeBounds::default(),
+        )
+        .map_err(|error| error.to_string()),
+        Mode::Canonical => {
+            check_variants(&mut rng, &encoded, args.mutations, args.require_canonical)
+                .map_err(|error| error.to_string())
+        }
+        Mode::Diagnose => unreachable!("the diagnose mode runs every case through `run_case`"),
+        Mode::Stream => {
+            let Batch::Flat(spans) = batch else {
+                unreachable!("the stream mode only supports flat batches")
+            };
+            let frames = rng.gen_range(1..=MAX_FRAMES);
+            let batches = more_batches(&mut rng, args, summary, spans, frames);
+            check_stream(&mut rng, &batches)
+                .map(|stats| {
+                    summary.stream.merge(&stats);
+                    MutationStats::default()
+                })
+                .map_err(|error| error.to_string())
+        }
+        Mode::Concat => {
+            let Batch::Flat(spans) = batch else {
+                unreachable!("the concat mode only supports flat batches")
+            };
+            let messages = rng.gen_range(1..=MAX_MESSAGES);
+            let batches = more_batches(&mut rng, args, summary, spans, messages);
+            check_concatenated(&batches)
+                .map(|stats| {
+                    summary.concat.merge(&stats);
+                    MutationStats::default()
+                })
+                .map_err(|error| error.to_string())
+        }
+    };
+    match checked {
+        Ok(stats) => summary.mutations.merge(&stats),
+        Err(error) => {
+            eprintln!(
+                "seed {seed} failed: {error}\n  replay with `{}`",
+                args.replay_flags(seed)
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/// Generates more batches after `spans` until there are `count` of them.
+///
+/// The stats of `spans` are already merged, the others get a generator of their own.
+fn more_batches(
+    rng: &mut StdRng,
+    args: &Args,
+    summary: &mut Summary,
+    spans: Vec<Span>,
+    count: usize,
+) -> Vec<Vec<Span>> {
+    let mut generator = SpanGenerator::new(args.lengths, args.timestamps, args.trace_ids);
+    let mut batches = vec![spans];
+    while batches.len() < count {
+        batches.push(random_spans(rng, args.batch_len(), &mut generator));
+    }
+    summary.generated.merge(&generator.stats);
+    batches
+}
+
+/// Round trips a batch of a case other than `span`, and mutates its encoding in the mutate
+/// mode.
+fn run_case(
+    args: &Args,
+    case: &dyn DynRoundTripCase,
+    seed: u64,
+    rng: &mut StdRng,
+    generator: &mut SpanGenerator,
+    summary: &mut Summary,
+) -> bool {
+    let options = CaseRun {
+        batch_len: args.batch_len(),
+        count_allocs: args.count_allocs,
+        shrink: !args.no_shrink,
+        diagnose: args.mode == Mode::Diagnose,
+        mutations: if args.mode == Mode::Mutate {
+            args.mutations
+        } else {
+            0
+        },
+        require_canonical: args.require_canonical,
+    };
+    let outcome = case.run(rng, generator, &options);
+    summary.generated.merge(&generator.stats);
+    match outcome {
+        Ok(outcome) => {
+            summary.values += outcome.values as u64;
+            summary.bytes += outcome.bytes as u64;
+            summary.encode_allocations += outcome.encode_allocations;
+            summary.decode_allocations += outcome.decode_allocations;
+            summary.encode_value_allocations += outcome.encode_value_allocations;
+            summary.decode_value_allocations += outcome.decode_value_allocations;
+            summary.mutations.merge(&outcome.mutations);
+            summary.measured_batches += u64::from(args.count_allocs);
+            true
+        }
+        Err(failure) => {
+            let name = case.name();
+            *summary.failed_cases.entry(name).or_default() += 1;
+            let replay = Args {
+                case: name.to_string(),
+                ..args.clone()
+            };
+            eprintln!(
+                "seed {seed} failed for case `{name}`: {}\n  replay with `{}`",
+                failure.error,
+                replay.replay_flags(seed)
+            );
+            for diagnostic in &failure.diagnostics {
+                eprintln!("  {diagnostic}");
+            }
+            if let Some(trace) = failure.trace {
+                print_indented(&trace);
+            }
+            if let Some(minimal) = failure.minimal {
+                eprintln!("{minimal}");
+            }
+            false
+        }
+    }
+}
+
+/// Runs iterations on the current thread until the shared budget is exhausted.
+fn worker(
+    args: &Args,
+    registry: &Registry,
+    master_seed: u64,
+    next_iteration: &AtomicU64,
+    stop: &AtomicBool,
+    deadline: Option<Instant>,
+) -> Summary {
+    let mut summary = Summary::default();
+    while !stop.load(Ordering::Relaxed) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        let iteration = next_iteration.fetch_add(1, Ordering::Relaxed);
+        if args
+            .iterations
+            .is_some_and(|iterations| iteration >= iterations)
+        {
+            break;
+        }
+        let seed = iteration_seed(master_seed, iteration);
+        summary.iterations += 1;
+        if !run_seed(args, registry, seed, &mut summary) {
+            summary.failures += 1;
+            eprintln!("iteration {iteration} failed");
+            if !args.keep_going {
+                stop.store(true, Ordering::Relaxed);
+            } else if abandoned_decoders() > 0 {
+                // Every further hang would leave another thread spinning.
+                eprintln!("stopping despite --keep-going, a hung decoding thread is still running");
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    summary
+}
+
+/// Runs iterations on `args.threads` threads until `args.iterations` or `args.duration` is
+/// exhausted, or until the first failure unless `args.keep_going` is set. A decoding hang stops
+/// the run either way.
+///
+/// # Panics
+///
+/// If `registry` has no case named `args.case`.
+pub fn run(args: &Args, registry: &Registry, master_seed: u64) -> Summary {
+    let deadline = args.duration.map(|duration| Instant::now() + duration);
+    let next_iteration = AtomicU64::new(0);
+    let stop = AtomicBool::new(false);
+    let mut summary = Summary::default();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..args.threads.get())
+            .map(|_| {
+                scope.spawn(|| {
+                    worker(
+                        args,
+                        registry,
+                        master_seed,
+                        &next_iteration,
+                        &stop,
+                        deadline,
+                    )
+                })
+            })
+            .collect();
+        for handle in workers {
+            summary.merge(handle.join().expect("worker thread panicked"));
+        }
+    });
+    summary
+}
@@ -0,0 +1,451 @@
+//! Stress tests postcard round trips of `Span` batches.
+//!
+//! The types are the ones our services encode. [`check_roundtrip`] runs the round-trip check on
+//! a batch of any serde type, so other crates can use it in their own test suites. The
+//! [`harness`] drives the randomized checks of the `force_check_postcard` binary.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use base64::display::Base64Display;
+use base64::engine::GeneralPurpose;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use generators::SpanGenerator;
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub mod batch;
+pub mod canonical;
+pub mod case;
+pub mod check;
+pub mod cli;
+pub mod codec;
+pub mod concat;
+pub mod corpus;
+pub mod counting_alloc;
+pub mod data_model;
+pub mod diagnose;
+pub mod generators;
+pub mod harness;
+pub mod migrate;
+pub mod mutate;
+pub mod random_value;
+pub mod roundtrip;
+pub mod shrink;
+pub mod size;
+pub mod span_batch;
+pub mod stream;
+pub mod trace;
+mod varint;
+
+pub use roundtrip::{check_roundtrip, RoundTripError};
+
+// Used by `impl_random_value!` in other crates.
+#[doc(hidden)]
+pub use rand;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Span {
+    pub trace_id: TraceId,
+    #[serde(with = "serde_datetime")]
+    pub span_timestamp: DateTime,
+}
+mod serde_datetime {
+    use super::DateTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S>(datetime: &DateTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_i64(datetime.into_timestamp_nanos())
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<DateTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let datetime_i64: i64 = Deserialize::deserialize(deserializer)?;
+        Ok(DateTime::from_timestamp_nanos(datetime_i64))
+    }
+}
+#[derive(Clone, Default, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DateTime {
+    // Timestamp in nanoseconds.
+    pub(crate) timestamp_nanos: i64,
+}
+impl DateTime {
+    /// Create new from UNIX timestamp in nanoseconds.
+    pub const fn from_timestamp_nanos(nanoseconds: i64) -> Self {
+        Self {
+            timestamp_nanos: nanoseconds,
+        }
+    }
+
+    /// Convert to UNIX timestamp in nanoseconds.
+    pub const fn into_timestamp_nanos(self) -> i64 {
+        self.timestamp_nanos
+    }
+}
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TraceId([u8; 16]);
+
+impl TraceId {
+    pub const BASE64_LENGTH: usize = 24;
+
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn base64_display(&self) -> Base64Display<'_, '_, GeneralPurpose> {
+        Base64Display::new(&self.0, &BASE64_STANDARD)
+    }
+
+    /// Encodes the trace ID as base64 into `buffer`, without allocating.
+    pub fn encode_base64<'a>(&self, buffer: &'a mut [u8; TraceId::BASE64_LENGTH]) -> &'a str {
+        let len = BASE64_STANDARD
+            .encode_slice(self.0, buffer)
+            .expect("16 bytes always fit into 24 base64 characters");
+        std::str::from_utf8(&buffer[..len]).expect("base64 is ASCII")
+    }
+}
+
+impl Serialize for TraceId {
+    /// Human-readable formats get the base64 string, binary formats the 16 raw bytes.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut buffer = [0u8; TraceId::BASE64_LENGTH];
+            serializer.serialize_str(self.encode_base64(&mut buffer))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TraceId {
+    /// Binary formats accept both the 16 raw bytes and the legacy base64 string, which
+    /// postcard encodes the same way as bytes: a length prefix followed by the content.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(TraceIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(TraceIdVisitor)
+        }
+    }
+}
+
+struct TraceIdVisitor;
+
+impl<'de> de::Visitor<'de> for TraceIdVisitor {
+    type Value = TraceId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("16 raw bytes or a base64 encoded trace ID")
+    }
+
+    fn visit_str<E: de::Error>(self, b64trace_id: &str) -> Result<TraceId, E> {
+        TraceId::from_base64(b64trace_id).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<TraceId, E> {
+        if let Ok(trace_id) = <[u8; 16]>::try_from(bytes) {
+            return Ok(TraceId(trace_id));
+        }
+        if bytes.len() != TraceId::BASE64_LENGTH {
+            return Err(E::invalid_length(bytes.len(), &self));
+        }
+        let b64trace_id = std::str::from_utf8(bytes)
+            .map_err(|_| E::invalid_value(de::Unexpected::Bytes(bytes), &self))?;
+        self.visit_str(b64trace_id)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<TraceId, A::Error> {
+        let mut trace_id = [0u8; 16];
+        for (position, byte) in trace_id.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(position, &self))?;
+        }
+        Ok(TraceId(trace_id))
+    }
+}
+
+/// Why a string is not the canonical base64 encoding of a `TraceId`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceIdError {
+    /// The string is not `TraceId::BASE64_LENGTH` bytes long.
+    WrongLength(usize),
+    /// The byte at `position` is not part of the standard base64 alphabet.
+    InvalidCharacter { position: usize, byte: u8 },
+    /// The padding at `position` is misplaced or missing, or the last data character has
+    /// non-zero trailing bits.
+    NonCanonicalPadding { position: usize },
+}
+
+impl fmt::Display for TraceIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceIdError::WrongLength(len) => write!(
+                f,
+                "base64 trace ID must be {} bytes long, got {len}",
+                TraceId::BASE64_LENGTH
+            ),
+            TraceIdError::InvalidCharacter { position, byte } => write!(
+                f,
+                "invalid character {:?} at position {position} of base64 trace ID",
+                char::from(*byte)
+            ),
+            TraceIdError::NonCanonicalPadding { position } => write!(
+                f,
+                "non-canonical padding at position {position} of base64 trace ID"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TraceIdError {}
+
+impl TraceId {
+    /// Number of base64 characters carrying data, the rest is `=` padding.
+    const BASE64_DATA_LENGTH: usize = 22;
+
+    /// Strictly decodes the canonical, padded standard base64 encoding of a trace ID.
+    ///
+    /// Exactly one string is accepted per trace ID: the trailing bits of the last data
+    /// character must be zero and the padding must be `==`.
+    pub fn from_base64(b64trace_id: &str) -> Result<Self, TraceIdError> {
+        let b64trace_id = b64trace_id.as_bytes();
+        if b64trace_id.len() != TraceId::BASE64_LENGTH {
+            return Err(TraceIdError::WrongLength(b64trace_id.len()));
+        }
+        let mut bits: u128 = 0;
+        for (position, &byte) in b64trace_id.iter().enumerate() {
+            let is_padding = position >= TraceId::BASE64_DATA_LENGTH;
+            let sextet = match (byte, is_padding) {
+                (b'=', true) => continue,
+                (b'=', false) => return Err(TraceIdError::NonCanonicalPadding { position }),
+                _ => {
+                    base64_sextet(byte).ok_or(TraceIdError::InvalidCharacter { position, byte })?
+                }
+            };
+            if is_padding {
+                return Err(TraceIdError::NonCanonicalPadding { position });
+            }
+            if position == TraceId::BASE64_DATA_LENGTH - 1 {
+                // 22 sextets carry 132 bits, the last 4 bits must be zero.
+                if sextet & 0x0f != 0 {
+                    return Err(TraceIdError::NonCanonicalPadding { position });
+                }
+                bits = (bits << 2) | u128::from(sextet >> 4);
+            } else {
+                bits = (bits << 6) | u128::from(sextet);
+            }
+        }
+        Ok(TraceId(bits.to_be_bytes()))
+    }
+}
+
+fn base64_sextet(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+impl Span {
+    pub fn random<R: Rng>(rng: &mut R, generator: &mut SpanGenerator) -> Self {
+        Span {
+            trace_id: generator.trace_id(rng),
+            span_timestamp: generator.timestamp(rng),
+        }
+    }
+}
+
+impl TraceId {
+    pub fn random<R: Rng>(rng: &mut R) -> Self {
+        let mut id = [0u8; 16];
+        rng.fill(&mut id);
+        TraceId(id)
+    }
+}
+
+/// Generates a batch with the length and span fields drawn by `generator`.
+pub fn random_spans<R: Rng>(
+    rng: &mut R,
+    batch_len: RangeInclusive<usize>,
+    generator: &mut SpanGenerator,
+) -> Vec<Span> {
+    let length = generator.batch_len(rng, batch_len);
+    (0..length).map(|_| Span::random(rng, generator)).collect()
+}
+
+/// Batch lengths next to the boundaries of one, two and three byte length prefixes.
+#[cfg(test)]
+pub(crate) const BOUNDARY_LENS: [usize; 6] = [0, 1, 127, 128, 16383, 16384];
+
+/// A span whose trace ID repeats `trace_byte`.
+#[cfg(test)]
+pub(crate) fn test_span(trace_byte: u8, nanos: i64) -> Span {
+    Span {
+        trace_id: TraceId([trace_byte; 16]),
+        span_timestamp: DateTime::from_timestamp_nanos(nanos),
+    }
+}
+
+/// `len` spans with distinct trace IDs, up to 256, and timestamps from -64 upwards.
+#[cfg(test)]
+pub(crate) fn test_spans(len: usize) -> Vec<Span> {
+    (0..len)
+        .map(|index| test_span(index as u8, index as i64 - 64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use postcard::{from_bytes, to_allocvec};
+
+    use super::*;
+
+    /// Distinct bytes, so a misplaced sextet changes the result.
+    const COUNTING: TraceId = TraceId([
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ]);
+
+    #[test]
+    fn from_base64_known_encodings() {
+        let cases = [
+            ("AAAAAAAAAAAAAAAAAAAAAA==", TraceId([0; 16])),
+            ("/////////////////////w==", TraceId([0xff; 16])),
+            ("ABEiM0RVZneImaq7zN3u/w==", COUNTING),
+        ];
+        for (b64trace_id, trace_id) in cases {
+            assert_eq!(TraceId::from_base64(b64trace_id), Ok(trace_id));
+            assert_eq!(trace_id.base64_display().to_string(), b64trace_id);
+        }
+    }
+
+    #[test]
+    fn from_base64_round_trip() {
+        let mut rng = rand::rngs::mock::StepRng::new(0x0123_4567_89ab_cdef, 0x1111_1111_1111);
+        for _ in 0..100 {
+            let trace_id = TraceId::random(&mut rng);
+            let mut buffer = [0u8; TraceId::BASE64_LENGTH];
+            let b64trace_id = trace_id.encode_base64(&mut buffer);
+            assert_eq!(b64trace_id, BASE64_STANDARD.encode(trace_id.0));
+            assert_eq!(TraceId::from_base64(b64trace_id), Ok(trace_id));
+        }
+    }
+
+    #[test]
+    fn from_base64_wrong_length() {
+        for b64trace_id in [
+            "",
+            "A",
+            "AAAAAAAAAAAAAAAAAAAAAA",
+            "AAAAAAAAAAAAAAAAAAAAAA=",
+            "AAAAAAAAAAAAAAAAAAAAAA===",
+            " AAAAAAAAAAAAAAAAAAAAAA==",
+            "AAAAAAAAAAAAAAAAAAAAAA==\n",
+        ] {
+            assert_eq!(
+                TraceId::from_base64(b64trace_id),
+                Err(TraceIdError::WrongLength(b64trace_id.len()))
+            );
+        }
+    }
+
+    #[test]
+    fn from_base64_invalid_characters() {
+        let cases = [
+            // The URL-safe alphabet.
+            ("-AAAAAAAAAAAAAAAAAAAAA==", 0, b'-'),
+            ("AAAAAAAAAA_AAAAAAAAAAA==", 10, b'_'),
+            ("AAAAAAAAAAAAAAAAAAAA A==", 20, b' '),
+            ("AAAAAAAAAAAAAAAAAAAAA.==", 21, b'.'),
+            // Not ASCII, two bytes in UTF-8.
+            ("AAAAAAAAAAAAAAAAAAAAAAé", 22, 0xc3),
+        ];
+        for (b64trace_id, position, byte) in cases {
+            assert_eq!(
+                TraceId::from_base64(b64trace_id),
+                Err(TraceIdError::InvalidCharacter { position, byte }),
+                "{b64trace_id}"
+            );
+        }
+    }
+
+    #[test]
+    fn from_base64_non_canonical() {
+        let cases = [
+            // Non-zero trailing bits, which lenient decoders drop.
+            ("/////////////////////x==", 21),
+            ("AAAAAAAAAAAAAAAAAAAAAB==", 21),
+            ("AAAAAAAAAAAAAAAAAAAAAP==", 21),
+            // Padding in place of data.
+            ("AAAAAAAAAAAAAAAAAAAAA===", 21),
+            ("=AAAAAAAAAAAAAAAAAAAAA==", 0),
+            // Data in place of padding.
+            ("AAAAAAAAAAAAAAAAAAAAAAA=", 22),
+            ("AAAAAAAAAAAAAAAAAAAAAA=A", 23),
+            ("AAAAAAAAAAAAAAAAAAAAAAAA", 22),
+        ];
+        for (b64trace_id, position) in cases {
+            assert_eq!(
+                TraceId::from_base64(b64trace_id),
+                Err(TraceIdError::NonCanonicalPadding { position }),
+                "{b64trace_id}"
+            );
+        }
+    }
+
+    #[test]
+    fn binary_formats_accept_raw_and_legacy_trace_ids() {
+        let raw = to_allocvec(&COUNTING).unwrap();
+        assert_eq!(raw.len(), 17);
+        assert_eq!(raw[0], 16);
+        assert_eq!(from_bytes::<TraceId>(&raw), Ok(COUNTING));
+
+        let legacy = to_allocvec("ABEiM0RVZneImaq7zN3u/w==").unwrap();
+        assert_eq!(from_bytes::<TraceId>(&legacy), Ok(COUNTING));
+    }
+
+    #[test]
+    fn binary_formats_reject_malformed_trace_ids() {
+        let malformed: [&[u8]; 5] = [
+            &[0],
+            &[15; 16],
+            &[17; 18],
+            b"\x18AAAAAAAAAAAAAAAAAAAAAB==",
+            b"\x18AAAAAAAAAAAAAAAAAAAAA\xff==",
+        ];
+        for bytes in malformed {
+            assert!(from_bytes::<TraceId>(bytes).is_err(), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn human_readable_formats_use_base64() {
+        let json = serde_json::to_string(&COUNTING).unwrap();
+        assert_eq!(json, r#""ABEiM0RVZneImaq7zN3u/w==""#);
+        assert_eq!(serde_json::from_str::<TraceId>(&json).unwrap(), COUNTING);
+        assert!(serde_json::from_str::<TraceId>(r#""ABEiM0RVZneImaq7zN3u/x==""#).is_err());
+        assert!(serde_json::from_str::<TraceId>(r#""ABEiM0RVZneImaq7zN3u/w""#).is_err());
+    }
+}
@@ -1,141 +1,184 @@
-use base64::display::Base64Display;
-use base64::engine::GeneralPurpose;
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
-use rand::{rngs::ThreadRng, Rng};
-use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub struct Span {
-    pub trace_id: TraceId,
-    #[serde(with = "serde_datetime")]
-    pub span_timestamp: DateTime,
-}
-mod serde_datetime {
-    use super::DateTime;
-    use serde::{Deserialize, Deserializer, Serializer};
-
-    pub(crate) fn serialize<S>(datetime: &DateTime, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_i64(datetime.into_timestamp_nanos())
-    }
-
-    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<DateTime, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let datetime_i64: i64 = Deserialize::deserialize(deserializer)?;
-        Ok(DateTime::from_timestamp_nanos(datetime_i64))
-    }
-}
-#[derive(Clone, Default, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub struct DateTime {
-    // Timestamp in nanoseconds.
-    pub(crate) timestamp_nanos: i64,
-}
-impl DateTime {
-    /// Create new from UNIX timestamp in nanoseconds.
-    pub const fn from_timestamp_nanos(nanoseconds: i64) -> Self {
-        Self {
-            timestamp_nanos: nanoseconds,
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use force_check_postcard::case::Registry;
+use force_check_postcard::cli::{Args, Mode, USAGE};
+use force_check_postcard::corpus;
+use force_check_postcard::counting_alloc::CountingAllocator;
+use force_check_postcard::harness::{self, Summary};
+use force_check_postcard::migrate::migrate_file;
+use rand::Rng;
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Re-runs every entry of the corpus in `dir`.
+fn replay_corpus(dir: &Path) -> ExitCode {
+    let entries = match corpus::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            eprintln!("failed to read corpus {}: {error}", dir.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut failures = 0;
+    for (path, entry) in &entries {
+        if let Err(error) = entry.replay() {
+            failures += 1;
+            eprintln!(
+                "{} (seed {}, recorded with postcard {}) failed: {error}",
+                path.display(),
+                entry.seed,
+                entry.postcard_version
+            );
         }
     }
-
-    /// Convert to UNIX timestamp in nanoseconds.
-    pub const fn into_timestamp_nanos(self) -> i64 {
-        self.timestamp_nanos
+    println!(
+        "{} corpus entries replayed, {failures} failures",
+        entries.len()
+    );
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub struct TraceId([u8; 16]);
-
-impl TraceId {
-    pub const BASE64_LENGTH: usize = 24;
 
-    pub fn new(bytes: [u8; 16]) -> Self {
-        Self(bytes)
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("error: {error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    if args.help {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
     }
-
-    pub fn as_bytes(&self) -> &[u8] {
-        &self.0
+    if let Some(dir) = &args.replay_corpus {
+        return replay_corpus(dir);
     }
-
-    pub fn to_vec(&self) -> Vec<u8> {
-        self.0.to_vec()
+    if let Some((input, output)) = &args.migrate {
+        return match migrate_file(input, output) {
+            Ok((input_len, output_len)) => {
+                println!(
+                    "migrated {} ({input_len} bytes) to {} ({output_len} bytes)",
+                    input.display(),
+                    output.display()
+                );
+                ExitCode::SUCCESS
+            }
+            Err(error) => {
+                eprintln!("failed to migrate {}: {error}", input.display());
+                ExitCode::FAILURE
+            }
+        };
     }
-
-    pub fn base64_display(&self) -> Base64Display<'_, '_, GeneralPurpose> {
-        Base64Display::new(&self.0, &BASE64_STANDARD)
+    let registry = Registry::builtin();
+    if registry.resolve(&args.case).is_none() {
+        let names: Vec<_> = registry.names().chain(registry.group_names()).collect();
+        eprintln!(
+            "error: unknown case `{}`, expected one of {}\n\n{USAGE}",
+            args.case,
+            names.join(", ")
+        );
+        return ExitCode::from(2);
     }
-}
-
-impl Serialize for TraceId {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let b64trace_id = BASE64_STANDARD.encode(self.0);
-        serializer.serialize_str(&b64trace_id)
+    if let Some(seed) = args.replay {
+        let mut summary = Summary::default();
+        return if harness::run_seed(&args, &registry, seed, &mut summary) {
+            println!("seed {seed} passed");
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
     }
-}
-
-impl<'de> Deserialize<'de> for TraceId {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let b64trace_id = String::deserialize(deserializer)?;
 
-        if b64trace_id.len() != TraceId::BASE64_LENGTH {
-            let message = format!(
-                "base64 trace ID must be {} bytes long, got {}",
-                TraceId::BASE64_LENGTH,
-                b64trace_id.len()
+    let master_seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    println!("master seed {master_seed}");
+    let started = Instant::now();
+    let summary = harness::run(&args, &registry, master_seed);
+
+    println!(
+        "{} iterations, {} `{}` values checked, {} bytes encoded, {} failures in {:.1?}",
+        summary.iterations,
+        summary.values,
+        args.case,
+        summary.bytes,
+        summary.failures,
+        started.elapsed()
+    );
+    if matches!(args.mode, Mode::Mutate | Mode::Canonical) {
+        let mutations = &summary.mutations;
+        println!(
+            "{} altered encodings rejected, {} accepted",
+            mutations.rejected, mutations.accepted
+        );
+        for (kind, count) in &mutations.canonical.non_canonical {
+            println!("  {count} accepted with non-canonical {kind}");
+        }
+        if mutations.skipped > 0 {
+            println!(
+                "{} skipped, their length prefix claims too many values taking no bytes",
+                mutations.skipped
             );
-            return Err(de::Error::custom(message));
         }
-        let mut trace_id = [0u8; 16];
-        BASE64_STANDARD
-            // Using the unchecked version here because otherwise the engine gets the wrong size
-            // estimate and fails.
-            .decode_slice_unchecked(b64trace_id.as_bytes(), &mut trace_id)
-            .map_err(|error| {
-                let message = format!("failed to decode base64 trace ID: {:?}", error);
-                de::Error::custom(message)
-            })?;
-        Ok(TraceId(trace_id))
     }
-}
-
-impl Span {
-    pub fn random(rng: &mut ThreadRng) -> Self {
-        Span {
-            trace_id: TraceId::random(rng),
-            span_timestamp: DateTime::from_timestamp_nanos(rng.gen_range(0..=i64::MAX)),
+    if args.mode == Mode::Stream {
+        let stream = &summary.stream;
+        println!(
+            "{} frames sent in {} chunks, {} corrupted, {} damaged pieces rejected, {} accepted",
+            stream.frames,
+            stream.chunks,
+            stream.corrupted,
+            stream.damaged_rejected,
+            stream.damaged_accepted
+        );
+    }
+    if args.mode == Mode::Concat {
+        let concat = &summary.concat;
+        println!(
+            "{} batches decoded from {} concatenated buffers",
+            concat.messages, concat.buffers
+        );
+    }
+    // Cases that never draw e.g. a timestamp leave its buckets empty.
+    for (generated, buckets) in [
+        ("batch lengths", &summary.generated.lengths),
+        ("timestamps", &summary.generated.timestamps),
+        ("trace IDs", &summary.generated.trace_ids),
+    ] {
+        if !buckets.is_empty() {
+            println!("{generated} generated:");
+            for (bucket, count) in buckets {
+                println!("  {count} {bucket}");
+            }
         }
     }
-}
-
-impl TraceId {
-    pub fn random(rng: &mut ThreadRng) -> Self {
-        let mut id = [0u8; 16];
-        rng.fill(&mut id);
-        TraceId(id)
+    if !summary.failed_cases.is_empty() {
+        println!("failures by case:");
+        for (case, count) in &summary.failed_cases {
+            println!("  {count} {case}");
+        }
     }
-}
-
-fn random_spans() -> Vec<Span> {
-    let mut rng = rand::thread_rng();
-    let length = rng.gen_range(1..=10000);
-    (0..length).map(|_| Span::random(&mut rng)).collect()
-}
-use postcard::{from_bytes, to_allocvec};
-fn main() {
-    loop {
-        let spans = random_spans();
-
-        let output: Vec<u8> = to_allocvec(&spans).unwrap();
-
-        let out: Vec<Span> = from_bytes(&output).unwrap();
-        assert_eq!(spans, out);
+    let mut passed = summary.failures == 0;
+    if args.count_allocs {
+        let (encode, decode) = summary.container_allocations_per_batch();
+        println!("{encode:.2} allocations per batch encoding, {decode:.2} decoding the sequences");
+        let (encode, decode) = summary.allocations_per_value();
+        println!("{encode:.4} allocations per value encoding, {decode:.4} decoding the values");
+        if let Some(max) = args.max_allocs_per_value {
+            if encode > max || decode > max {
+                eprintln!("more than {max} allocations per value");
+                passed = false;
+            }
+        }
+    }
+    if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
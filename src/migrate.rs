@@ -0,0 +1,212 @@
+//! Conversion of postcard data written before `TraceId` switched to raw bytes in binary formats.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{from_bytes, to_allocvec};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+use crate::{Span, TraceId};
+
+/// Serializes a span the way it was encoded before, with the trace ID as a base64 string.
+struct LegacySpan<'a>(&'a Span);
+
+impl Serialize for LegacySpan<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut buffer = [0u8; TraceId::BASE64_LENGTH];
+        let mut span = serializer.serialize_struct("Span", 2)?;
+        span.serialize_field("trace_id", self.0.trace_id.encode_base64(&mut buffer))?;
+        span.serialize_field(
+            "span_timestamp",
+            &self.0.span_timestamp.into_timestamp_nanos(),
+        )?;
+        span.end()
+    }
+}
+
+/// Encodes `spans` in the legacy format with base64 trace IDs.
+pub fn to_legacy_allocvec(spans: &[Span]) -> postcard::Result<Vec<u8>> {
+    let legacy: Vec<LegacySpan> = spans.iter().map(LegacySpan).collect();
+    to_allocvec(&legacy)
+}
+
+/// Why the legacy encoding of a batch does not decode back to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegacyError {
+    /// `to_legacy_allocvec` rejected the batch.
+    Serialize(postcard::Error),
+    /// `from_bytes` rejected the legacy encoding.
+    Deserialize {
+        error: postcard::Error,
+        encoded: Vec<u8>,
+    },
+    /// The legacy encoding decodes to different spans.
+    Mismatch { encoded: Vec<u8> },
+}
+
+impl fmt::Display for LegacyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = match self {
+            LegacyError::Serialize(error) => {
+                return write!(f, "failed to serialize the legacy encoding: {error}")
+            }
+            LegacyError::Deserialize { error, encoded } => {
+                write!(f, "failed to deserialize the legacy encoding: {error}")?;
+                encoded
+            }
+            LegacyError::Mismatch { encoded } => {
+                write!(f, "the legacy encoding decodes to different spans")?;
+                encoded
+            }
+        };
+        write!(
+            f,
+            "\n  legacy encoding ({} bytes, base64): {}",
+            encoded.len(),
+            Base64Display::new(encoded, &BASE64_STANDARD)
+        )
+    }
+}
+
+impl std::error::Error for LegacyError {}
+
+/// Checks that the legacy encoding of `spans` still decodes to `spans`.
+pub fn check_legacy_compat(spans: &[Span]) -> Result<(), LegacyError> {
+    let encoded = to_legacy_allocvec(spans).map_err(LegacyError::Serialize)?;
+    let decoded: Vec<Span> = match from_bytes(&encoded) {
+        Ok(decoded) => decoded,
+        Err(error) => return Err(LegacyError::Deserialize { error, encoded }),
+    };
+    if decoded != spans {
+        return Err(LegacyError::Mismatch { encoded });
+    }
+    Ok(())
+}
+
+/// Re-encodes a postcard `Vec<Span>` in either format with raw trace IDs.
+pub fn migrate(encoded: &[u8]) -> postcard::Result<Vec<u8>> {
+    let spans: Vec<Span> = from_bytes(encoded)?;
+    to_allocvec(&spans)
+}
+
+/// Migrates the `Vec<Span>` stored in `input` and writes it to `output`.
+///
+/// Returns the sizes of the input and output in bytes.
+pub fn migrate_file(input: &Path, output: &Path) -> io::Result<(usize, usize)> {
+    let encoded = fs::read(input)?;
+    let migrated = migrate(&encoded).map_err(|error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {error}", input.display()),
+        )
+    })?;
+    fs::write(output, &migrated)?;
+    Ok((encoded.len(), migrated.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_span, test_spans, BOUNDARY_LENS};
+
+    use super::*;
+
+    #[test]
+    fn known_legacy_encoding() {
+        let mut expected = vec![1, 24];
+        expected.extend_from_slice(b"AAAAAAAAAAAAAAAAAAAAAA==");
+        // 64 zigzag encodes to 128.
+        expected.extend_from_slice(&[0x80, 0x01]);
+        assert_eq!(to_legacy_allocvec(&[test_span(0, 64)]), Ok(expected));
+        assert_eq!(to_legacy_allocvec(&[]), Ok(vec![0]));
+    }
+
+    #[test]
+    fn legacy_compat_at_length_boundaries() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            assert_eq!(check_legacy_compat(&spans), Ok(()), "{len} spans");
+        }
+        assert_eq!(
+            check_legacy_compat(&[test_span(0xff, i64::MIN), test_span(0x80, i64::MAX)]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn migrate_legacy_encoding() {
+        let spans = vec![test_span(1, -1), test_span(0xfe, 1 << 40)];
+        let legacy = to_legacy_allocvec(&spans).unwrap();
+        let raw = to_allocvec(&spans).unwrap();
+        assert_eq!(legacy.len() - raw.len(), 2 * (24 - 16));
+        assert_eq!(migrate(&legacy), Ok(raw.clone()));
+        // Migrating twice changes nothing.
+        assert_eq!(migrate(&raw), Ok(raw));
+    }
+
+    #[test]
+    fn migrate_mixed_encoding() {
+        let spans = [test_span(2, 2), test_span(3, 3)];
+        let mut mixed = vec![2];
+        mixed.extend_from_slice(&to_legacy_allocvec(&spans[..1]).unwrap()[1..]);
+        mixed.extend_from_slice(&to_allocvec(&spans[1..]).unwrap()[1..]);
+        assert_eq!(migrate(&mixed), Ok(to_allocvec(&spans.to_vec()).unwrap()));
+    }
+
+    #[test]
+    fn migrate_malformed() {
+        let legacy = to_legacy_allocvec(&[test_span(0, 0)]).unwrap();
+        assert!(migrate(&[]).is_err());
+        assert!(migrate(&legacy[..legacy.len() - 1]).is_err());
+        assert!(migrate(&legacy[..10]).is_err());
+        // Non-zero trailing bits in the last data character.
+        let mut trailing_bits = legacy.clone();
+        trailing_bits[2 + 21] = b'B';
+        assert!(migrate(&trailing_bits).is_err());
+        // A 20 byte trace ID.
+        assert!(
+            migrate(&[1, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn legacy_error_dumps_the_encoding() {
+        let error = LegacyError::Mismatch {
+            encoded: vec![0, 1, 2],
+        };
+        assert_eq!(
+            error.to_string(),
+            "the legacy encoding decodes to different spans\n  \
+             legacy encoding (3 bytes, base64): AAEC"
+        );
+    }
+
+    #[test]
+    fn migrate_file_writes_raw_encoding() {
+        let dir = std::env::temp_dir().join(format!("migrate-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("legacy.postcard");
+        let output = dir.join("raw.postcard");
+        let garbage = dir.join("garbage.postcard");
+        let spans = vec![test_span(9, 9); 3];
+        fs::write(&input, to_legacy_allocvec(&spans).unwrap()).unwrap();
+        fs::write(&garbage, [3, 0xff]).unwrap();
+
+        let migrated = migrate_file(&input, &output);
+        let written = fs::read(&output);
+        let error = migrate_file(&garbage, &dir.join("unused.postcard")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(migrated.unwrap(), (1 + 3 * 26, 1 + 3 * 18));
+        assert_eq!(written.unwrap(), to_allocvec(&spans).unwrap());
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error
+            .to_string()
+            .starts_with(&garbage.display().to_string()));
+    }
+}
@@ -0,0 +1,476 @@
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{from_bytes, to_allocvec};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::canonical::{CanonicalOutcome, CanonicalStats, NonCanonical};
+use crate::counting_alloc::{self, AllocStats};
+use crate::varint;
+
+/// Decoding a mutated buffer not finishing within this time is reported as a hang.
+const MAX_DECODE_TIME: Duration = Duration::from_secs(1);
+/// serde preallocates at most 1 MiB for a sequence, whatever its length prefix claims. The
+/// batch and every sequence a value nests can be partially decoded at once.
+const MAX_PREALLOC_BYTES: usize = 1024 * 1024;
+/// Bytes a decoded batch may occupy per input byte, with room for `Vec` growth.
+const MAX_BYTES_PER_INPUT_BYTE: usize = 64;
+/// Most values taking no bytes that a mutated batch is decoded with, see
+/// [`DecodeBounds::empty_values`].
+const MAX_EMPTY_VALUES: u64 = 1 << 16;
+
+/// Decoding threads that did not finish in time, see [`abandoned_decoders`].
+static ABANDONED_DECODERS: AtomicUsize = AtomicUsize::new(0);
+
+/// A single change applied to an encoded sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    BitFlip {
+        offset: usize,
+        bit: u8,
+    },
+    Insert {
+        offset: usize,
+        byte: u8,
+    },
+    Delete {
+        offset: usize,
+    },
+    Truncate {
+        len: usize,
+    },
+    /// Replaces the varint length prefix of the sequence.
+    LengthPrefix {
+        len: u64,
+    },
+}
+
+impl Mutation {
+    pub fn random<R: Rng>(rng: &mut R, encoded: &[u8]) -> Self {
+        if encoded.is_empty() {
+            return Mutation::Insert {
+                offset: 0,
+                byte: rng.gen(),
+            };
+        }
+        match rng.gen_range(0..5) {
+            0 => Mutation::BitFlip {
+                offset: rng.gen_range(0..encoded.len()),
+                bit: rng.gen_range(0..8),
+            },
+            1 => Mutation::Insert {
+                offset: rng.gen_range(0..=encoded.len()),
+                byte: rng.gen(),
+            },
+            2 => Mutation::Delete {
+                offset: rng.gen_range(0..encoded.len()),
+            },
+            3 => Mutation::Truncate {
+                len: rng.gen_range(0..encoded.len()),
+            },
+            _ => {
+                let (len, _) = varint::read(encoded).unwrap_or((0, 0));
+                let len = match rng.gen_range(0..4) {
+                    0 => len.wrapping_add(1),
+                    1 => len.wrapping_sub(1),
+                    2 => u64::MAX >> rng.gen_range(0..64),
+                    _ => rng.gen(),
+                };
+                Mutation::LengthPrefix { len }
+            }
+        }
+    }
+
+    pub fn apply(&self, encoded: &[u8]) -> Vec<u8> {
+        let mut mutated = encoded.to_vec();
+        match *self {
+            Mutation::BitFlip { offset, bit } => mutated[offset] ^= 1 << bit,
+            Mutation::Insert { offset, byte } => mutated.insert(offset, byte),
+            Mutation::Delete { offset } => {
+                mutated.remove(offset);
+            }
+            Mutation::Truncate { len } => mutated.truncate(len),
+            Mutation::LengthPrefix { len } => {
+                let (_, prefix_len) = varint::read(encoded).unwrap_or((0, 0));
+                mutated.splice(..prefix_len, varint::write(len));
+            }
+        }
+        mutated
+    }
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mutation::BitFlip { offset, bit } => write!(f, "flip bit {bit} of byte {offset}"),
+            Mutation::Insert { offset, byte } => write!(f, "insert {byte:#04x} at {offset}"),
+            Mutation::Delete { offset } => write!(f, "delete byte {offset}"),
+            Mutation::Truncate { len } => write!(f, "truncate to {len} bytes"),
+            Mutation::LengthPrefix { len } => write!(f, "set length prefix to {len}"),
+        }
+    }
+}
+
+/// How `from_bytes` handled a mutated buffer that did not trip any check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationOutcome {
+    Rejected,
+    /// Decoded successfully, with the result of the canonical-encoding check.
+    Accepted(CanonicalOutcome),
+    /// Not decoded, the length prefix claims more than `MAX_EMPTY_VALUES` values that take no
+    /// bytes.
+    Skipped,
+}
+
+/// A mutated buffer that made `from_bytes` misbehave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutationError {
+    pub mutation: Mutation,
+    pub mutated: Vec<u8>,
+    pub kind: MutationErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationErrorKind {
+    Panicked(String),
+    /// Decoding did not finish within the given time, its thread is left running, see
+    /// [`abandoned_decoders`].
+    Hung(Duration),
+    OverAllocated {
+        peak_bytes: usize,
+        limit: usize,
+    },
+    /// The decoded value does not survive another round trip.
+    InconsistentReencode(String),
+    /// The mutated bytes were accepted although they are not a canonical encoding.
+    NonCanonical(NonCanonical),
+}
+
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            MutationErrorKind::Panicked(message) => write!(f, "decoding panicked: {message}")?,
+            MutationErrorKind::Hung(limit) => {
+                write!(f, "decoding did not finish within {limit:.1?}")?
+            }
+            MutationErrorKind::OverAllocated { peak_bytes, limit } => write!(
+                f,
+                "decoding allocated {peak_bytes} bytes, limit is {limit} bytes"
+            )?,
+            MutationErrorKind::InconsistentReencode(message) => write!(
+                f,
+                "decoded value does not re-encode consistently: {message}"
+            )?,
+            MutationErrorKind::NonCanonical(non_canonical) => {
+                write!(f, "accepted a non-canonical encoding: {non_canonical}")?
+            }
+        }
+        write!(
+            f,
+            "\n  mutation: {}\n  mutated ({} bytes, base64): {}",
+            self.mutation,
+            self.mutated.len(),
+            Base64Display::new(&self.mutated, &BASE64_STANDARD)
+        )
+    }
+}
+
+impl std::error::Error for MutationError {}
+
+/// Checks whether accepted bytes are a canonical encoding, e.g.
+/// [`check_canonical`](crate::canonical::check_canonical) for a `Vec<Span>`.
+pub type CanonicalCheck = fn(&[u8]) -> CanonicalOutcome;
+
+/// What decoding a mutated `Vec<T>` may cost beyond the size of the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeBounds {
+    /// How deep sequences and maps nest in a `T`, each level may preallocate its own buffer.
+    pub nested_sequences: usize,
+    /// Whether a `T` can take no bytes at all, like a zero-sized type.
+    ///
+    /// postcard then decodes as many values as the length prefix claims without running out of
+    /// input, which takes forever for a random prefix. Such batches are skipped instead of
+    /// reported as hangs.
+    pub empty_values: bool,
+}
+
+/// Applies `mutation` to the encoded `Vec<T>` `encoded` and checks how `from_bytes` copes.
+///
+/// With `require_canonical`, accepting bytes that `canonical` finds are not a canonical
+/// encoding is an error.
+pub fn check_mutation<T>(
+    encoded: &[u8],
+    mutation: Mutation,
+    require_canonical: bool,
+    canonical: CanonicalCheck,
+    bounds: DecodeBounds,
+) -> Result<MutationOutcome, MutationError>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Send + 'static,
+{
+    let mutated = mutation.apply(encoded);
+    let error = |kind| MutationError {
+        mutation,
+        mutated: mutated.clone(),
+        kind,
+    };
+    if bounds.empty_values && varint::read(&mutated).is_some_and(|(len, _)| len > MAX_EMPTY_VALUES)
+    {
+        return Ok(MutationOutcome::Skipped);
+    }
+
+    let (decoded, stats) = match decode_watched::<T>(&mutated) {
+        Ok(decoded) => decoded,
+        Err(RecvTimeoutError::Timeout) => {
+            return Err(error(MutationErrorKind::Hung(MAX_DECODE_TIME)))
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(error(MutationErrorKind::Panicked(
+                "the decoding thread exited without a result".to_string(),
+            )))
+        }
+    };
+    let decoded =
+        decoded.map_err(|payload| error(MutationErrorKind::Panicked(panic_message(&*payload))))?;
+    let limit = allocation_limit(mutated.len(), bounds.nested_sequences);
+    if stats.peak_bytes > limit {
+        return Err(error(MutationErrorKind::OverAllocated {
+            peak_bytes: stats.peak_bytes,
+            limit,
+        }));
+    }
+
+    let Ok(decoded) = decoded else {
+        return Ok(MutationOutcome::Rejected);
+    };
+    let reencoded = to_allocvec(&decoded).map_err(|serialize_error| {
+        error(MutationErrorKind::InconsistentReencode(format!(
+            "failed to serialize: {serialize_error}"
+        )))
+    })?;
+    match from_bytes::<Vec<T>>(&reencoded) {
+        Ok(redecoded) if redecoded == decoded => {}
+        Ok(_) => {
+            return Err(error(MutationErrorKind::InconsistentReencode(
+                "decodes to a different value".to_string(),
+            )))
+        }
+        Err(deserialize_error) => {
+            return Err(error(MutationErrorKind::InconsistentReencode(format!(
+                "failed to deserialize: {deserialize_error}"
+            ))))
+        }
+    }
+    match canonical(&mutated) {
+        CanonicalOutcome::NonCanonical(non_canonical) if require_canonical => {
+            Err(error(MutationErrorKind::NonCanonical(non_canonical)))
+        }
+        canonical => Ok(MutationOutcome::Accepted(canonical)),
+    }
+}
+
+/// The result of decoding `bytes` and its allocations.
+type Watched<T> = (thread::Result<postcard::Result<Vec<T>>>, AllocStats);
+
+/// Decodes `bytes` on a thread of its own, so a decoder that never returns cannot block the
+/// caller for longer than `MAX_DECODE_TIME`.
+///
+/// A hung thread is left running, there is no way to stop it. It is counted by
+/// [`abandoned_decoders`].
+fn decode_watched<T>(bytes: &[u8]) -> Result<Watched<T>, RecvTimeoutError>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let bytes = bytes.to_vec();
+    thread::Builder::new()
+        .name("decode".to_string())
+        .spawn(move || {
+            let decoded =
+                counting_alloc::measure(|| catch_quietly(|| from_bytes::<Vec<T>>(&bytes)));
+            // The receiver is gone if decoding took too long.
+            let _ = sender.send(decoded);
+        })
+        .expect("failed to spawn a decoding thread");
+    let watched = receiver.recv_timeout(MAX_DECODE_TIME);
+    if matches!(watched, Err(RecvTimeoutError::Timeout)) {
+        ABANDONED_DECODERS.fetch_add(1, Ordering::Relaxed);
+    }
+    watched
+}
+
+/// How many decoding threads did not finish in time so far.
+///
+/// They may still be running and take a core each, so runs should stop once there is one.
+pub fn abandoned_decoders() -> usize {
+    ABANDONED_DECODERS.load(Ordering::Relaxed)
+}
+
+fn allocation_limit(input_len: usize, nested_sequences: usize) -> usize {
+    MAX_PREALLOC_BYTES * (1 + nested_sequences) + MAX_BYTES_PER_INPUT_BYTE * input_len
+}
+
+thread_local! {
+    /// Whether the panic hook stays silent for panics of this thread.
+    static QUIET_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+static INSTALL_QUIET_HOOK: Once = Once::new();
+
+/// Like `panic::catch_unwind`, but the panic hook does not print the message and backtrace of
+/// panics inside `f`, which are expected and reported by the caller.
+///
+/// Panics of other threads, and of this thread outside of `f`, are still printed.
+pub(crate) fn catch_quietly<T>(f: impl FnOnce() -> T) -> thread::Result<T> {
+    INSTALL_QUIET_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.try_with(Cell::get).unwrap_or(false) {
+                hook(info);
+            }
+        }));
+    });
+    let quiet = QUIET_PANICS.with(|quiet| quiet.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    QUIET_PANICS.with(|q| q.set(quiet));
+    result
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "<non-string panic payload>".to_string())
+}
+
+/// Counters of decoding altered encodings of a batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MutationStats {
+    pub rejected: u64,
+    pub accepted: u64,
+    /// Batches of values taking no bytes with a huge length prefix, see [`DecodeBounds`].
+    pub skipped: u64,
+    pub canonical: CanonicalStats,
+}
+
+impl MutationStats {
+    pub fn merge(&mut self, other: &MutationStats) {
+        self.rejected += other.rejected;
+        self.accepted += other.accepted;
+        self.skipped += other.skipped;
+        self.canonical.merge(&other.canonical);
+    }
+}
+
+/// Applies `count` random mutations to the encoded `Vec<T>` `encoded`, one at a time, see
+/// [`check_mutation`].
+///
+/// Stops at the first mutated buffer that trips a check.
+pub fn fuzz_encoded<T, R>(
+    rng: &mut R,
+    encoded: &[u8],
+    count: usize,
+    require_canonical: bool,
+    canonical: CanonicalCheck,
+    bounds: DecodeBounds,
+) -> Result<MutationStats, MutationError>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Send + 'static,
+    R: Rng,
+{
+    let mut stats = MutationStats::default();
+    for _ in 0..count {
+        let mutation = Mutation::random(rng, encoded);
+        let outcome = check_mutation::<T>(encoded, mutation, require_canonical, canonical, bounds)?;
+        match outcome {
+            MutationOutcome::Rejected => stats.rejected += 1,
+            MutationOutcome::Accepted(canonical) => {
+                stats.accepted += 1;
+                stats.canonical.record(&canonical);
+            }
+            MutationOutcome::Skipped => stats.skipped += 1,
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canonical::check_canonical_of;
+
+    use super::*;
+
+    const EMPTY_VALUES: DecodeBounds = DecodeBounds {
+        nested_sequences: 0,
+        empty_values: true,
+    };
+
+    #[test]
+    fn huge_prefix_of_empty_values_is_skipped() {
+        let encoded = to_allocvec(&vec![(); 3]).unwrap();
+        let outcome = check_mutation::<()>(
+            &encoded,
+            Mutation::LengthPrefix { len: u64::MAX },
+            false,
+            check_canonical_of::<Vec<()>>,
+            EMPTY_VALUES,
+        );
+        assert_eq!(outcome, Ok(MutationOutcome::Skipped));
+    }
+
+    #[test]
+    fn bounded_prefix_of_empty_values_is_decoded() {
+        let encoded = to_allocvec(&vec![(); 3]).unwrap();
+        let outcome = check_mutation::<()>(
+            &encoded,
+            Mutation::LengthPrefix {
+                len: MAX_EMPTY_VALUES,
+            },
+            false,
+            check_canonical_of::<Vec<()>>,
+            EMPTY_VALUES,
+        );
+        assert_eq!(
+            outcome,
+            Ok(MutationOutcome::Accepted(CanonicalOutcome::Canonical))
+        );
+    }
+
+    #[test]
+    fn huge_prefix_of_other_values_is_rejected() {
+        let encoded = to_allocvec(&vec![7u8; 3]).unwrap();
+        let outcome = check_mutation::<u8>(
+            &encoded,
+            Mutation::LengthPrefix { len: u64::MAX },
+            false,
+            check_canonical_of::<Vec<u8>>,
+            DecodeBounds::default(),
+        );
+        assert_eq!(outcome, Ok(MutationOutcome::Rejected));
+    }
+
+    #[test]
+    fn mutations_apply_to_the_length_prefix() {
+        let encoded = [3, 7, 7, 7];
+        assert_eq!(
+            Mutation::LengthPrefix { len: 128 }.apply(&encoded),
+            [0x80, 0x01, 7, 7, 7]
+        );
+        assert_eq!(Mutation::Truncate { len: 1 }.apply(&encoded), [3]);
+        assert_eq!(
+            Mutation::BitFlip { offset: 0, bit: 7 }.apply(&encoded),
+            [0x83, 7, 7, 7]
+        );
+    }
+}
@@ -0,0 +1,453 @@
+//! Random values of arbitrary types, bounded by a size and depth [`Budget`].
+//!
+//! Implemented for primitives, `String`, the std collections, tuples, arrays and this crate's
+//! types. [`impl_random_value!`](crate::impl_random_value) implements it field by field for
+//! user structs and enums, and [`RandomCase`] turns any such type into a `RoundTripCase`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::case::RoundTripCase;
+use crate::generators::{SpanGenerator, TimestampStrategy, TraceIdState, TraceIdStrategy};
+use crate::{DateTime, Span, TraceId};
+
+/// How large a random value may get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum number of elements of a collection or characters of a string.
+    pub size: usize,
+    /// Levels of collections and options below this one. At depth 0 collections are empty and
+    /// options are `None`.
+    pub depth: usize,
+}
+
+impl Budget {
+    pub const DEFAULT: Budget = Budget { size: 16, depth: 4 };
+
+    pub const fn new(size: usize, depth: usize) -> Self {
+        Budget { size, depth }
+    }
+
+    /// The budget of the elements of a collection or option.
+    pub fn descend(self) -> Budget {
+        Budget {
+            depth: self.depth.saturating_sub(1),
+            ..self
+        }
+    }
+
+    /// Draws the length of a collection.
+    pub fn len<R: Rng>(self, rng: &mut R) -> usize {
+        if self.depth == 0 {
+            0
+        } else {
+            rng.gen_range(0..=self.size)
+        }
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::DEFAULT
+    }
+}
+
+/// A type that can draw random values of itself.
+///
+/// Recursive types must recurse through an option or a collection, which stop at depth 0.
+pub trait RandomValue: Sized {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self;
+}
+
+macro_rules! impl_standard {
+    ($($ty:ty),*) => {
+        $(
+            impl RandomValue for $ty {
+                fn random_value<R: Rng>(rng: &mut R, _budget: Budget) -> Self {
+                    rng.gen()
+                }
+            }
+        )*
+    };
+}
+
+impl_standard!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl RandomValue for () {
+    fn random_value<R: Rng>(_rng: &mut R, _budget: Budget) -> Self {}
+}
+
+impl RandomValue for f32 {
+    /// Any bit pattern but NaN, which never compares equal to itself.
+    fn random_value<R: Rng>(rng: &mut R, _budget: Budget) -> Self {
+        let value = f32::from_bits(rng.gen());
+        if value.is_nan() {
+            0.0
+        } else {
+            value
+        }
+    }
+}
+
+impl RandomValue for f64 {
+    /// Any bit pattern but NaN, which never compares equal to itself.
+    fn random_value<R: Rng>(rng: &mut R, _budget: Budget) -> Self {
+        let value = f64::from_bits(rng.gen());
+        if value.is_nan() {
+            0.0
+        } else {
+            value
+        }
+    }
+}
+
+impl RandomValue for String {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        let len = budget.len(rng);
+        (0..len).map(|_| rng.gen::<char>()).collect()
+    }
+}
+
+impl<T: RandomValue> RandomValue for Box<T> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Box::new(T::random_value(rng, budget))
+    }
+}
+
+impl<T: RandomValue> RandomValue for Option<T> {
+    /// `None` at depth 0 and for about a quarter of the values above.
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        (budget.depth > 0 && rng.gen_ratio(3, 4)).then(|| T::random_value(rng, budget.descend()))
+    }
+}
+
+impl<T: RandomValue> RandomValue for Vec<T> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        let len = budget.len(rng);
+        (0..len)
+            .map(|_| T::random_value(rng, budget.descend()))
+            .collect()
+    }
+}
+
+impl<T: RandomValue, const N: usize> RandomValue for [T; N] {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        std::array::from_fn(|_| T::random_value(rng, budget.descend()))
+    }
+}
+
+impl<T: RandomValue + Ord> RandomValue for BTreeSet<T> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Vec::random_value(rng, budget).into_iter().collect()
+    }
+}
+
+impl<T: RandomValue + Eq + Hash> RandomValue for HashSet<T> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Vec::random_value(rng, budget).into_iter().collect()
+    }
+}
+
+impl<K: RandomValue + Ord, V: RandomValue> RandomValue for BTreeMap<K, V> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Vec::random_value(rng, budget).into_iter().collect()
+    }
+}
+
+impl<K: RandomValue + Eq + Hash, V: RandomValue> RandomValue for HashMap<K, V> {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Vec::random_value(rng, budget).into_iter().collect()
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: RandomValue),+> RandomValue for ($($name,)+) {
+            /// The elements descend the budget like those of arrays, so a type recursing through
+            /// tuples still reaches depth 0.
+            fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+                ($($name::random_value(rng, budget.descend()),)+)
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+
+impl RandomValue for TraceId {
+    /// Drawn with the `mixed` trace ID strategy.
+    fn random_value<R: Rng>(rng: &mut R, _budget: Budget) -> Self {
+        TraceIdStrategy::Mixed
+            .generate(rng, &mut TraceIdState::default())
+            .0
+    }
+}
+
+impl RandomValue for DateTime {
+    /// Drawn with the `mixed` timestamp strategy.
+    fn random_value<R: Rng>(rng: &mut R, _budget: Budget) -> Self {
+        TimestampStrategy::Mixed.generate(rng).0
+    }
+}
+
+impl RandomValue for Span {
+    fn random_value<R: Rng>(rng: &mut R, budget: Budget) -> Self {
+        Span {
+            trace_id: TraceId::random_value(rng, budget),
+            span_timestamp: DateTime::random_value(rng, budget),
+        }
+    }
+}
+
+/// Implements [`RandomValue`] for a struct or enum by drawing every field in turn.
+///
+/// Named fields are listed by name, tuple fields by type. Enums pick a variant uniformly, its
+/// fields descend the budget like the elements of tuples, so recursive enums reach depth 0.
+///
+/// ```
+/// # use force_check_postcard::impl_random_value;
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+/// impl_random_value!(Point { x, y });
+///
+/// struct Meters(f64);
+/// impl_random_value!(Meters(f64));
+///
+/// enum Shape {
+///     Empty,
+///     Circle(Meters),
+///     Polygon { points: Vec<Point> },
+/// }
+/// impl_random_value!(enum Shape { Empty, Circle(Meters), Polygon { points } });
+/// ```
+#[macro_export]
+macro_rules! impl_random_value {
+    (enum $name:ident {
+        $($variant:ident $(($($ty:ty),* $(,)?))? $({ $($field:ident),* $(,)? })?),+ $(,)?
+    }) => {
+        impl $crate::random_value::RandomValue for $name {
+            // The index is not read after the last variant.
+            #[allow(unused_assignments)]
+            fn random_value<R: $crate::rand::Rng>(
+                rng: &mut R,
+                budget: $crate::random_value::Budget,
+            ) -> Self {
+                const VARIANTS: usize = [$(stringify!($variant)),+].len();
+                let mut index = rng.gen_range(0..VARIANTS);
+                $(
+                    if index == 0 {
+                        return $name::$variant
+                            $((
+                                $(<$ty as $crate::random_value::RandomValue>::random_value(
+                                    rng, budget.descend(),
+                                )),*
+                            ))?
+                            $({
+                                $($field: $crate::random_value::RandomValue::random_value(
+                                    rng, budget.descend(),
+                                )),*
+                            })?;
+                    }
+                    index -= 1;
+                )+
+                unreachable!("the index is drawn below the number of variants")
+            }
+        }
+    };
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::random_value::RandomValue for $name {
+            fn random_value<R: $crate::rand::Rng>(
+                rng: &mut R,
+                budget: $crate::random_value::Budget,
+            ) -> Self {
+                $name {
+                    $($field: $crate::random_value::RandomValue::random_value(rng, budget)),*
+                }
+            }
+        }
+    };
+    ($name:ident ($($ty:ty),* $(,)?)) => {
+        impl $crate::random_value::RandomValue for $name {
+            fn random_value<R: $crate::rand::Rng>(
+                rng: &mut R,
+                budget: $crate::random_value::Budget,
+            ) -> Self {
+                $name($(<$ty as $crate::random_value::RandomValue>::random_value(rng, budget)),*)
+            }
+        }
+    };
+}
+
+/// A `RoundTripCase` of any type implementing [`RandomValue`].
+pub struct RandomCase<T> {
+    name: &'static str,
+    budget: Budget,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> RandomCase<T> {
+    pub fn new(name: &'static str, budget: Budget) -> Self {
+        RandomCase {
+            name,
+            budget,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for RandomCase<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RandomCase")
+            .field("name", &self.name)
+            .field("budget", &self.budget)
+            .finish()
+    }
+}
+
+impl<T> RoundTripCase for RandomCase<T>
+where
+    T: RandomValue + Serialize + DeserializeOwned + PartialEq + Clone + fmt::Debug + Send + 'static,
+{
+    type Value = T;
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn generate(&self, rng: &mut StdRng, _generator: &mut SpanGenerator) -> T {
+        T::random_value(rng, self.budget)
+    }
+
+    /// The budget is sized for the type, so its depth is how deep collections nest.
+    fn nested_sequences(&self) -> usize {
+        self.budget.depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// A tree recursing through a `Vec`, for checking that generation ends.
+    #[derive(Debug)]
+    struct Tree {
+        children: Vec<Tree>,
+    }
+    crate::impl_random_value!(Tree { children });
+
+    impl Tree {
+        fn depth(&self) -> usize {
+            self.children
+                .iter()
+                .map(Tree::depth)
+                .max()
+                .map_or(0, |depth| depth + 1)
+        }
+
+        fn nodes(&self) -> usize {
+            1 + self.children.iter().map(Tree::nodes).sum::<usize>()
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Choice {
+        Unit,
+        Tuple(u8, Option<u8>),
+        Named { values: Vec<u8> },
+        Nested(Vec<Choice>),
+    }
+    crate::impl_random_value!(enum Choice {
+        Unit,
+        Tuple(u8, Option<u8>),
+        Named { values },
+        Nested(Vec<Choice>),
+    });
+
+    #[test]
+    fn depth_zero_is_empty() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let budget = Budget::new(8, 0);
+        for _ in 0..100 {
+            assert_eq!(budget.len(&mut rng), 0);
+            assert_eq!(String::random_value(&mut rng, budget), "");
+            assert!(Vec::<u8>::random_value(&mut rng, budget).is_empty());
+            assert_eq!(Option::<u8>::random_value(&mut rng, budget), None);
+        }
+    }
+
+    #[test]
+    fn collections_stay_within_size() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let budget = Budget::new(5, 1);
+        let mut longest = 0;
+        for _ in 0..200 {
+            let values = Vec::<Vec<u8>>::random_value(&mut rng, budget);
+            assert!(values.len() <= 5);
+            assert!(values.iter().all(Vec::is_empty), "{values:?}");
+            let string = String::random_value(&mut rng, budget);
+            assert!(string.chars().count() <= 5);
+            longest = longest.max(values.len());
+        }
+        assert_eq!(longest, 5);
+    }
+
+    #[test]
+    fn recursive_types_end_within_budget() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let budget = Budget::new(3, 4);
+        let mut deepest = 0;
+        for _ in 0..200 {
+            let tree = Tree::random_value(&mut rng, budget);
+            assert!(tree.depth() <= 4, "{tree:?}");
+            // At most 3 children per node on each of 4 levels below the root.
+            assert!(tree.nodes() <= 1 + 3 + 9 + 27 + 81, "{tree:?}");
+            deepest = deepest.max(tree.depth());
+        }
+        assert_eq!(deepest, 4);
+    }
+
+    #[test]
+    fn enums_reach_every_variant() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut seen = [false; 4];
+        for _ in 0..200 {
+            let index = match Choice::random_value(&mut rng, Budget::DEFAULT) {
+                Choice::Unit => 0,
+                Choice::Tuple(..) => 1,
+                Choice::Named { .. } => 2,
+                Choice::Nested(_) => 3,
+            };
+            seen[index] = true;
+        }
+        assert_eq!(seen, [true; 4]);
+    }
+
+    #[test]
+    fn enum_fields_descend_the_budget() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..200 {
+            match Choice::random_value(&mut rng, Budget::new(4, 1)) {
+                Choice::Unit => {}
+                Choice::Tuple(_, option) => assert_eq!(option, None),
+                Choice::Named { values } => assert!(values.is_empty()),
+                Choice::Nested(choices) => assert_eq!(choices, []),
+            }
+        }
+    }
+}
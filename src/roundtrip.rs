@@ -0,0 +1,298 @@
+use std::fmt;
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{from_bytes, to_allocvec};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::batch::Batch;
+use crate::{varint, Span};
+
+/// Why a batch of values, spans unless stated otherwise, did not survive a postcard round trip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoundTripError<T = Span> {
+    /// `to_allocvec` rejected the batch.
+    Serialize(postcard::Error),
+    /// `from_bytes` rejected the bytes produced by `to_allocvec`.
+    Deserialize {
+        error: postcard::Error,
+        encoded: Vec<u8>,
+    },
+    /// The decoded batch has a different number of values.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+        encoded: Vec<u8>,
+    },
+    /// The decoded nested batch has sequences of different lengths, or a different variant.
+    ShapeMismatch {
+        expected: String,
+        actual: String,
+        encoded: Vec<u8>,
+    },
+    /// The decoded batch differs at `index`, counting the spans of all inner batches.
+    ValueMismatch {
+        index: usize,
+        expected: T,
+        actual: T,
+        /// Offset in `encoded` where the value at `index` starts, if the values before it could
+        /// be encoded on their own.
+        byte_offset: Option<usize>,
+        encoded: Vec<u8>,
+    },
+}
+
+impl<T: fmt::Debug> RoundTripError<T> {
+    /// The postcard bytes the batch was encoded to, if serialization succeeded.
+    pub fn encoded(&self) -> Option<&[u8]> {
+        match self {
+            RoundTripError::Serialize(_) => None,
+            RoundTripError::Deserialize { encoded, .. }
+            | RoundTripError::LengthMismatch { encoded, .. }
+            | RoundTripError::ShapeMismatch { encoded, .. }
+            | RoundTripError::ValueMismatch { encoded, .. } => Some(encoded),
+        }
+    }
+
+    /// The error postcard returned, if encoding or decoding failed.
+    pub fn postcard_error(&self) -> Option<&postcard::Error> {
+        match self {
+            RoundTripError::Serialize(error) | RoundTripError::Deserialize { error, .. } => {
+                Some(error)
+            }
+            _ => None,
+        }
+    }
+
+    /// Describes the failure without the dump of the encoded bytes.
+    pub fn message(&self) -> String {
+        match self {
+            RoundTripError::Serialize(error) => format!("failed to serialize: {error}"),
+            RoundTripError::Deserialize { error, .. } => format!("failed to deserialize: {error}"),
+            RoundTripError::LengthMismatch {
+                expected, actual, ..
+            } => format!("decoded {actual} values, expected {expected}"),
+            RoundTripError::ShapeMismatch {
+                expected, actual, ..
+            } => format!("decoded {actual}, expected {expected}"),
+            RoundTripError::ValueMismatch {
+                index,
+                expected,
+                actual,
+                byte_offset,
+                ..
+            } => {
+                let at = byte_offset
+                    .map(|offset| format!(" (at byte offset {offset})"))
+                    .unwrap_or_default();
+                format!(
+                    "value {index}{at} differs\n  expected: {expected:?}\n  actual:   {actual:?}"
+                )
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Display for RoundTripError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message())?;
+        if let Some(encoded) = self.encoded() {
+            write!(
+                f,
+                "\n  encoded ({} bytes, base64): {}",
+                encoded.len(),
+                Base64Display::new(encoded, &BASE64_STANDARD)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for RoundTripError<T> {}
+
+/// Encodes `values` with postcard, decodes them again as a `Vec<T>` and compares the result.
+///
+/// Returns the encoded bytes on success.
+pub fn check_roundtrip<T>(values: &[T]) -> Result<Vec<u8>, RoundTripError<T>>
+where
+    T: Serialize + DeserializeOwned + PartialEq + Clone,
+{
+    check_roundtrip_by(values, T::eq)
+}
+
+/// Like [`check_roundtrip`], comparing the decoded values with `equal` instead of `==`.
+pub fn check_roundtrip_by<T, F>(values: &[T], mut equal: F) -> Result<Vec<u8>, RoundTripError<T>>
+where
+    T: Serialize + DeserializeOwned + Clone,
+    F: FnMut(&T, &T) -> bool,
+{
+    let encoded = to_allocvec(values).map_err(RoundTripError::Serialize)?;
+    let decoded: Vec<T> = match from_bytes(&encoded) {
+        Ok(decoded) => decoded,
+        Err(error) => return Err(RoundTripError::Deserialize { error, encoded }),
+    };
+    if decoded.len() != values.len() {
+        return Err(RoundTripError::LengthMismatch {
+            expected: values.len(),
+            actual: decoded.len(),
+            encoded,
+        });
+    }
+    if let Some(index) = values
+        .iter()
+        .zip(&decoded)
+        .position(|(expected, actual)| !equal(expected, actual))
+    {
+        return Err(RoundTripError::ValueMismatch {
+            index,
+            expected: values[index].clone(),
+            actual: decoded[index].clone(),
+            byte_offset: element_offset(values, index),
+            encoded,
+        });
+    }
+    Ok(encoded)
+}
+
+/// Like [`check_roundtrip`], for a batch of any shape.
+pub fn check_batch(batch: &Batch) -> Result<Vec<u8>, RoundTripError> {
+    if let Batch::Flat(spans) = batch {
+        return check_roundtrip(spans);
+    }
+    let encoded = to_allocvec(batch).map_err(RoundTripError::Serialize)?;
+    let decoded = match Batch::decode(batch.shape(), &encoded) {
+        Ok(decoded) => decoded,
+        Err(error) => return Err(RoundTripError::Deserialize { error, encoded }),
+    };
+    if decoded.outline() != batch.outline() {
+        return Err(RoundTripError::ShapeMismatch {
+            expected: batch.outline(),
+            actual: decoded.outline(),
+            encoded,
+        });
+    }
+    let (spans, decoded_spans) = (batch.spans(), decoded.spans());
+    if let Some(index) = spans
+        .iter()
+        .zip(&decoded_spans)
+        .position(|(expected, actual)| expected != actual)
+    {
+        return Err(RoundTripError::ValueMismatch {
+            index,
+            expected: spans[index].clone(),
+            actual: decoded_spans[index].clone(),
+            byte_offset: batch_offset(batch, index),
+            encoded,
+        });
+    }
+    Ok(encoded)
+}
+
+/// Offset of the span at `index`, counting the spans of all inner batches, in the postcard
+/// encoding of `batch`.
+///
+/// Returns `None` if the spans before it cannot be encoded.
+fn batch_offset(batch: &Batch, mut index: usize) -> Option<usize> {
+    match batch {
+        Batch::Flat(spans) => element_offset(spans, index),
+        Batch::Nested(nested) => {
+            let mut offset = varint::encoded_len(nested.len() as u64);
+            for spans in nested {
+                if index < spans.len() {
+                    return Some(offset + element_offset(spans, index)?);
+                }
+                index -= spans.len();
+                offset += to_allocvec(spans).ok()?.len();
+            }
+            Some(offset)
+        }
+        // The `Some` tag is a single byte.
+        Batch::Optional(Some(spans)) => Some(1 + element_offset(spans, index)?),
+        Batch::Optional(None) => Some(0),
+    }
+}
+
+/// Offset of the value at `index` in the postcard encoding of `values`.
+///
+/// Returns `None` if the values before it cannot be encoded.
+fn element_offset<T: Serialize>(values: &[T], index: usize) -> Option<usize> {
+    // The prefix is encoded with its own, shorter or equal, length prefix.
+    let prefix = to_allocvec(&values[..index]).ok()?;
+    Some(
+        prefix.len() - varint::encoded_len(index as u64) + varint::encoded_len(values.len() as u64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::ser::Error as _;
+    use serde::Serializer;
+
+    use super::*;
+    use crate::test_spans;
+
+    /// A value whose `Broken` variant fails to serialize.
+    enum Value {
+        Fine(u8),
+        Broken,
+    }
+
+    impl Serialize for Value {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                Value::Fine(value) => serializer.serialize_u8(*value),
+                Value::Broken => Err(S::Error::custom("broken")),
+            }
+        }
+    }
+
+    #[track_caller]
+    fn assert_span_at(encoded: &[u8], offset: usize, span: &Span) {
+        let (decoded, _) = postcard::take_from_bytes::<Span>(&encoded[offset..]).unwrap();
+        assert_eq!(&decoded, span, "at byte {offset}");
+    }
+
+    #[test]
+    fn offset_after_unencodable_prefix() {
+        let values = [Value::Fine(1), Value::Broken, Value::Fine(2)];
+        assert_eq!(element_offset(&values, 0), Some(1));
+        assert_eq!(element_offset(&values, 1), Some(2));
+        assert_eq!(element_offset(&values, 2), None);
+    }
+
+    #[test]
+    fn offsets_of_known_batch() {
+        // A span is the 16 trace ID bytes with their length and the timestamp, which takes one
+        // varint byte from -64 to 63 and two from 64 on.
+        let spans = test_spans(200);
+        let encoded = to_allocvec(&spans).unwrap();
+        for (index, offset) in [(0, 2), (1, 20), (127, 2 + 127 * 18), (128, 2 + 128 * 18)] {
+            assert_eq!(element_offset(&spans, index), Some(offset), "span {index}");
+        }
+        assert_eq!(element_offset(&spans, 129), Some(2 + 128 * 18 + 19));
+        for index in [0, 1, 127, 128, 129, 199] {
+            let offset = element_offset(&spans, index).unwrap();
+            assert_span_at(&encoded, offset, &spans[index]);
+        }
+        // The length prefix of 128 spans is two bytes, that of the first 127 only one.
+        assert_eq!(element_offset(&spans[..128], 127), Some(2 + 127 * 18));
+        assert_eq!(element_offset(&spans[..127], 126), Some(1 + 126 * 18));
+    }
+
+    #[test]
+    fn offsets_of_nested_and_optional_batches() {
+        let spans = test_spans(5);
+        let batches = [
+            Batch::Nested(vec![spans[..2].to_vec(), Vec::new(), spans[2..].to_vec()]),
+            Batch::Optional(Some(spans.clone())),
+        ];
+        for batch in batches {
+            let encoded = to_allocvec(&batch).unwrap();
+            for (index, span) in batch.spans().into_iter().enumerate() {
+                let offset = batch_offset(&batch, index).unwrap();
+                assert_span_at(&encoded, offset, span);
+            }
+        }
+    }
+}
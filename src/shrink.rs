@@ -0,0 +1,266 @@
+use crate::{DateTime, Span, TraceId};
+
+/// Delta-debugs `values` down to a minimal batch for which `fails` still returns `true`.
+///
+/// First removes chunks of values, halving the chunk size until single values are tried,
+/// then replaces every remaining value with the first of its `simplify` candidates that still
+/// fails. Repeats until no step makes progress.
+///
+/// `simplify` must only return values that are strictly simpler than its argument, so
+/// shrinking terminates.
+pub fn shrink_values<T, F, S>(values: &[T], mut fails: F, mut simplify: S) -> Vec<T>
+where
+    T: Clone,
+    F: FnMut(&[T]) -> bool,
+    S: FnMut(&T) -> Vec<T>,
+{
+    let mut values = values.to_vec();
+    loop {
+        let removed = remove_chunks(&mut values, &mut fails);
+        let simplified = simplify_values(&mut values, &mut fails, &mut simplify);
+        if !removed && !simplified {
+            return values;
+        }
+    }
+}
+
+/// Shrinks a failing batch of spans, simplifying every `TraceId` toward zero bytes and every
+/// `DateTime` toward 0.
+pub fn shrink_spans<F>(spans: &[Span], fails: F) -> Vec<Span>
+where
+    F: FnMut(&[Span]) -> bool,
+{
+    shrink_values(spans, fails, simplify_span)
+}
+
+fn remove_chunks<T, F>(values: &mut Vec<T>, fails: &mut F) -> bool
+where
+    T: Clone,
+    F: FnMut(&[T]) -> bool,
+{
+    let mut progress = false;
+    let mut chunk_len = values.len().div_ceil(2).max(1);
+    while !values.is_empty() {
+        let mut start = 0;
+        let mut removed_any = false;
+        while start < values.len() {
+            let end = (start + chunk_len).min(values.len());
+            let candidate: Vec<T> = values[..start]
+                .iter()
+                .chain(&values[end..])
+                .cloned()
+                .collect();
+            if fails(&candidate) {
+                *values = candidate;
+                removed_any = true;
+            } else {
+                start = end;
+            }
+        }
+        progress |= removed_any;
+        if chunk_len == 1 {
+            if !removed_any {
+                break;
+            }
+        } else {
+            chunk_len = chunk_len.div_ceil(2);
+        }
+    }
+    progress
+}
+
+fn simplify_values<T, F, S>(values: &mut [T], fails: &mut F, simplify: &mut S) -> bool
+where
+    T: Clone,
+    F: FnMut(&[T]) -> bool,
+    S: FnMut(&T) -> Vec<T>,
+{
+    let mut progress = false;
+    for index in 0..values.len() {
+        while let Some(candidate) = simplify(&values[index]).into_iter().find(|candidate| {
+            let original = std::mem::replace(&mut values[index], candidate.clone());
+            let still_fails = fails(values);
+            values[index] = original;
+            still_fails
+        }) {
+            values[index] = candidate;
+            progress = true;
+        }
+    }
+    progress
+}
+
+/// Simpler spans to try in order: the simpler trace IDs, then the simpler timestamps.
+pub fn simplify_span(span: &Span) -> Vec<Span> {
+    let trace_ids = simplify_trace_id(span.trace_id)
+        .into_iter()
+        .map(|trace_id| Span {
+            trace_id,
+            ..span.clone()
+        });
+    let timestamps = simplify_datetime(span.span_timestamp)
+        .into_iter()
+        .map(|span_timestamp| Span {
+            span_timestamp,
+            ..span.clone()
+        });
+    trace_ids.chain(timestamps).collect()
+}
+
+/// Simpler trace IDs to try in order: all bytes cleared, then each non-zero byte cleared.
+pub fn simplify_trace_id(trace_id: TraceId) -> Vec<TraceId> {
+    if trace_id.0 == [0u8; 16] {
+        return Vec::new();
+    }
+    let single_bytes = (0..16)
+        .filter(|&position| trace_id.0[position] != 0)
+        .map(|position| {
+            let mut bytes = trace_id.0;
+            bytes[position] = 0;
+            TraceId(bytes)
+        });
+    std::iter::once(TraceId([0u8; 16]))
+        .chain(single_bytes)
+        .collect()
+}
+
+/// Simpler timestamps to try in order: 0, then half the distance to 0.
+pub fn simplify_datetime(datetime: DateTime) -> Vec<DateTime> {
+    let nanos = datetime.into_timestamp_nanos();
+    if nanos == 0 {
+        return Vec::new();
+    }
+    let mut candidates = vec![DateTime::from_timestamp_nanos(0)];
+    if nanos / 2 != 0 {
+        candidates.push(DateTime::from_timestamp_nanos(nanos / 2));
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_span;
+
+    #[test]
+    fn remove_chunks_keeps_the_failing_values() {
+        let mut values: Vec<u32> = (0..100).collect();
+        let progress = remove_chunks(&mut values, &mut |values: &[u32]| {
+            values.contains(&17) && values.contains(&83)
+        });
+        assert!(progress);
+        assert_eq!(values, [17, 83]);
+    }
+
+    #[test]
+    fn remove_chunks_of_a_passing_batch() {
+        let mut values = vec![1, 2, 3];
+        assert!(!remove_chunks(&mut values, &mut |_: &[i32]| false));
+        assert_eq!(values, [1, 2, 3]);
+    }
+
+    #[test]
+    fn remove_chunks_down_to_empty() {
+        let mut values = vec![1, 2, 3];
+        assert!(remove_chunks(&mut values, &mut |_: &[i32]| true));
+        assert!(values.is_empty());
+
+        let mut empty: Vec<i32> = Vec::new();
+        assert!(!remove_chunks(&mut empty, &mut |_: &[i32]| true));
+    }
+
+    #[test]
+    fn remove_chunks_of_a_single_value() {
+        let mut values = vec![7];
+        assert!(!remove_chunks(&mut values, &mut |values: &[i32]| values == [7]));
+        assert_eq!(values, [7]);
+    }
+
+    #[test]
+    fn shrink_values_simplifies_the_remaining_values() {
+        // Fails as long as some value is at least 10, simplified by halving. Removing the first
+        // half leaves 700, which halves down to 10.
+        let values = [3u32, 1000, 5, 700];
+        let shrunk = shrink_values(
+            &values,
+            |values| values.iter().any(|&value| value >= 10),
+            |&value| {
+                if value == 0 {
+                    Vec::new()
+                } else {
+                    vec![0, value / 2]
+                }
+            },
+        );
+        assert_eq!(shrunk, [10]);
+    }
+
+    #[test]
+    fn shrink_values_without_simpler_values() {
+        let shrunk = shrink_values(
+            &[4, 8, 15, 16, 23, 42],
+            |values| values.len() >= 2,
+            |_| Vec::new(),
+        );
+        assert_eq!(shrunk.len(), 2);
+    }
+
+    #[test]
+    fn shrink_spans_simplifies_trace_ids_and_timestamps() {
+        let spans = [
+            test_span(1, 5),
+            test_span(0xff, 1_000_000),
+            test_span(2, -7),
+        ];
+        let shrunk = shrink_spans(&spans, |spans| {
+            spans
+                .iter()
+                .any(|span| span.span_timestamp.into_timestamp_nanos() >= 1000)
+        });
+        assert_eq!(shrunk, [test_span(0, 1953)]);
+    }
+
+    #[test]
+    fn simplify_trace_id_clears_bytes() {
+        assert!(simplify_trace_id(TraceId([0; 16])).is_empty());
+
+        let mut bytes = [0; 16];
+        bytes[3] = 9;
+        bytes[15] = 1;
+        let mut without_3 = bytes;
+        without_3[3] = 0;
+        let mut without_15 = bytes;
+        without_15[15] = 0;
+        assert_eq!(
+            simplify_trace_id(TraceId(bytes)),
+            [TraceId([0; 16]), TraceId(without_3), TraceId(without_15)]
+        );
+    }
+
+    #[test]
+    fn simplify_datetime_halves_toward_zero() {
+        let nanos = |candidates: Vec<DateTime>| -> Vec<i64> {
+            candidates
+                .into_iter()
+                .map(DateTime::into_timestamp_nanos)
+                .collect()
+        };
+        assert!(simplify_datetime(DateTime::from_timestamp_nanos(0)).is_empty());
+        assert_eq!(
+            nanos(simplify_datetime(DateTime::from_timestamp_nanos(1))),
+            [0]
+        );
+        assert_eq!(
+            nanos(simplify_datetime(DateTime::from_timestamp_nanos(-1))),
+            [0]
+        );
+        assert_eq!(
+            nanos(simplify_datetime(DateTime::from_timestamp_nanos(-9))),
+            [0, -4]
+        );
+        assert_eq!(
+            nanos(simplify_datetime(DateTime::from_timestamp_nanos(i64::MAX))),
+            [0, i64::MAX / 2]
+        );
+    }
+}
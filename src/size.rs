@@ -0,0 +1,608 @@
+//! The size of postcard encodings without encoding, and worst-case sizes of this crate's types.
+//!
+//! [`serialized_size`] counts bytes with its own serializer following postcard's wire format,
+//! so checking it against `to_allocvec` checks both. The [`MaxSize`] constants are what buffers
+//! for a single value can be sized by.
+
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+use crate::batch::Batch;
+use crate::counting_alloc;
+use crate::{varint, DateTime, Span, TraceId};
+
+/// The largest postcard encoding of any value of a type, like postcard's `MaxSize` derive.
+pub trait MaxSize {
+    const POSTCARD_MAX_SIZE: usize;
+}
+
+impl MaxSize for TraceId {
+    /// A length prefix of one byte and the 16 raw bytes, every trace ID has this size.
+    ///
+    /// The legacy base64 form, a length prefix and 24 characters, takes 25 bytes. It is still
+    /// decoded, but only ever encoded by `migrate::to_legacy_allocvec`, so it does not count.
+    const POSTCARD_MAX_SIZE: usize = 1 + 16;
+}
+
+impl MaxSize for DateTime {
+    /// The zigzag varint of the nanoseconds, as `Span::span_timestamp` encodes it.
+    const POSTCARD_MAX_SIZE: usize = varint::MAX_LEN;
+}
+
+impl MaxSize for Span {
+    const POSTCARD_MAX_SIZE: usize = TraceId::POSTCARD_MAX_SIZE + DateTime::POSTCARD_MAX_SIZE;
+}
+
+/// The largest postcard encoding of a `Vec<Span>` of `len` spans.
+pub fn max_batch_size(len: usize) -> usize {
+    varint::encoded_len(len as u64) + len * Span::POSTCARD_MAX_SIZE
+}
+
+/// The number of bytes `postcard::to_allocvec(value)` produces, without allocating.
+pub fn serialized_size<T: Serialize + ?Sized>(value: &T) -> postcard::Result<usize> {
+    let mut counter = SizeCounter::default();
+    value.serialize(&mut counter)?;
+    Ok(counter.size)
+}
+
+/// A serializer that adds up the bytes postcard would write.
+#[derive(Debug, Default)]
+pub struct SizeCounter {
+    pub size: usize,
+}
+
+impl SizeCounter {
+    fn add(&mut self, bytes: usize) -> postcard::Result<()> {
+        self.size += bytes;
+        Ok(())
+    }
+
+    fn add_varint(&mut self, value: u128) -> postcard::Result<()> {
+        let bits = u128::BITS - value.leading_zeros();
+        self.add((bits.max(1) as usize).div_ceil(7))
+    }
+
+    fn add_len(&mut self, len: Option<usize>) -> postcard::Result<()> {
+        let len = len.ok_or(postcard::Error::SerializeSeqLengthUnknown)?;
+        self.add_varint(len as u128)
+    }
+}
+
+/// Counts the bytes of a formatted string without storing it.
+struct FormattedLen(usize);
+
+impl fmt::Write for FormattedLen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+macro_rules! count_varints {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method(self, value: $ty) -> postcard::Result<()> {
+                self.add_varint(value.into())
+            }
+        )*
+    };
+}
+
+macro_rules! count_zigzag_varints {
+    ($($method:ident($ty:ty => $unsigned:ty)),* $(,)?) => {
+        $(
+            fn $method(self, value: $ty) -> postcard::Result<()> {
+                let zigzag = ((value << 1) ^ (value >> (<$ty>::BITS - 1))) as $unsigned;
+                self.add_varint(zigzag.into())
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut SizeCounter {
+    type Ok = ();
+    type Error = postcard::Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    count_varints!(
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_u128(u128),
+    );
+
+    count_zigzag_varints!(
+        serialize_i16(i16 => u16),
+        serialize_i32(i32 => u32),
+        serialize_i64(i64 => u64),
+        serialize_i128(i128 => u128),
+    );
+
+    fn serialize_bool(self, _value: bool) -> postcard::Result<()> {
+        self.add(1)
+    }
+
+    fn serialize_i8(self, _value: i8) -> postcard::Result<()> {
+        self.add(1)
+    }
+
+    fn serialize_u8(self, _value: u8) -> postcard::Result<()> {
+        self.add(1)
+    }
+
+    fn serialize_f32(self, _value: f32) -> postcard::Result<()> {
+        self.add(4)
+    }
+
+    fn serialize_f64(self, _value: f64) -> postcard::Result<()> {
+        self.add(8)
+    }
+
+    /// Encoded as its UTF-8 string.
+    fn serialize_char(self, value: char) -> postcard::Result<()> {
+        self.add(1 + value.len_utf8())
+    }
+
+    fn serialize_str(self, value: &str) -> postcard::Result<()> {
+        self.serialize_bytes(value.as_bytes())
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> postcard::Result<()> {
+        self.add_varint(value.len() as u128)?;
+        self.add(value.len())
+    }
+
+    fn serialize_none(self) -> postcard::Result<()> {
+        self.add(1)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> postcard::Result<()> {
+        self.add(1)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> postcard::Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> postcard::Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+    ) -> postcard::Result<()> {
+        self.add_varint(index.into())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> postcard::Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> postcard::Result<()> {
+        self.add_varint(index.into())?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> postcard::Result<Self> {
+        self.add_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> postcard::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> postcard::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> postcard::Result<Self> {
+        self.add_varint(index.into())?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> postcard::Result<Self> {
+        self.add_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> postcard::Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> postcard::Result<Self> {
+        self.add_varint(index.into())?;
+        Ok(self)
+    }
+
+    /// Formats `value` once to count its length, instead of into a `String`.
+    fn collect_str<T: fmt::Display + ?Sized>(self, value: &T) -> postcard::Result<()> {
+        let mut len = FormattedLen(0);
+        fmt::write(&mut len, format_args!("{value}"))
+            .map_err(|_| postcard::Error::CollectStrError)?;
+        self.add_varint(len.0 as u128)?;
+        self.add(len.0)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+macro_rules! count_elements {
+    ($($trait:ident::$method:ident),* $(,)?) => {
+        $(
+            impl ser::$trait for &mut SizeCounter {
+                type Ok = ();
+                type Error = postcard::Error;
+
+                fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> postcard::Result<()> {
+                    value.serialize(&mut **self)
+                }
+
+                fn end(self) -> postcard::Result<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+count_elements!(
+    SerializeSeq::serialize_element,
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field,
+    SerializeTupleVariant::serialize_field,
+);
+
+impl ser::SerializeMap for &mut SizeCounter {
+    type Ok = ();
+    type Error = postcard::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> postcard::Result<()> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> postcard::Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> postcard::Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! count_fields {
+    ($($trait:ident),* $(,)?) => {
+        $(
+            impl ser::$trait for &mut SizeCounter {
+                type Ok = ();
+                type Error = postcard::Error;
+
+                fn serialize_field<T: Serialize + ?Sized>(
+                    &mut self,
+                    _key: &'static str,
+                    value: &T,
+                ) -> postcard::Result<()> {
+                    value.serialize(&mut **self)
+                }
+
+                fn end(self) -> postcard::Result<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+count_fields!(SerializeStruct, SerializeStructVariant);
+
+/// A computed size or size constant that does not hold for an encoded batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeError {
+    Failed(postcard::Error),
+    /// `serialized_size` differs from the length of `to_allocvec`.
+    Mismatch {
+        computed: usize,
+        actual: usize,
+    },
+    Allocated(u64),
+    /// A span, or a part of it, is larger than its `POSTCARD_MAX_SIZE`.
+    TooLarge {
+        index: usize,
+        part: &'static str,
+        size: usize,
+        max: usize,
+    },
+    /// The encoded `Vec<Span>` is larger than [`max_batch_size`].
+    BatchTooLarge {
+        len: usize,
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeError::Failed(error) => write!(f, "serialized_size failed: {error}"),
+            SizeError::Mismatch { computed, actual } => write!(
+                f,
+                "serialized_size computed {computed} bytes, to_allocvec encoded {actual} bytes"
+            ),
+            SizeError::Allocated(allocations) => {
+                write!(f, "serialized_size made {allocations} allocations")
+            }
+            SizeError::TooLarge {
+                index,
+                part,
+                size,
+                max,
+            } => write!(
+                f,
+                "the {part} of span {index} takes {size} bytes, POSTCARD_MAX_SIZE is {max}"
+            ),
+            SizeError::BatchTooLarge { len, size, max } => write!(
+                f,
+                "the batch of {len} spans takes {size} bytes, max_batch_size is {max}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SizeError {}
+
+/// Checks `serialized_size` of `batch` against its `encoded` bytes and every span against the
+/// [`MaxSize`] constants.
+pub fn check_sizes(batch: &Batch, encoded: &[u8]) -> Result<(), SizeError> {
+    let (computed, stats) = counting_alloc::measure(|| serialized_size(batch));
+    let computed = computed.map_err(SizeError::Failed)?;
+    if computed != encoded.len() {
+        return Err(SizeError::Mismatch {
+            computed,
+            actual: encoded.len(),
+        });
+    }
+    if stats.allocations > 0 {
+        return Err(SizeError::Allocated(stats.allocations));
+    }
+    for (index, span) in batch.spans().into_iter().enumerate() {
+        let timestamp = span.span_timestamp.into_timestamp_nanos();
+        let parts = [
+            (
+                "trace ID",
+                serialized_size(&span.trace_id),
+                TraceId::POSTCARD_MAX_SIZE,
+            ),
+            (
+                "timestamp",
+                serialized_size(&timestamp),
+                DateTime::POSTCARD_MAX_SIZE,
+            ),
+            ("whole", serialized_size(span), Span::POSTCARD_MAX_SIZE),
+        ];
+        for (part, size, max) in parts {
+            let size = size.map_err(SizeError::Failed)?;
+            if size > max {
+                return Err(SizeError::TooLarge {
+                    index,
+                    part,
+                    size,
+                    max,
+                });
+            }
+        }
+    }
+    if let Batch::Flat(spans) = batch {
+        let max = max_batch_size(spans.len());
+        if encoded.len() > max {
+            return Err(SizeError::BatchTooLarge {
+                len: spans.len(),
+                size: encoded.len(),
+                max,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use postcard::to_allocvec;
+    use serde::{Serialize, Serializer};
+
+    use super::*;
+    use crate::BOUNDARY_LENS;
+
+    /// Checks `serialized_size` against `to_allocvec` and the size worked out by hand.
+    #[track_caller]
+    fn assert_size<T: Serialize + ?Sized>(value: &T, expected: usize) {
+        let encoded = to_allocvec(value).unwrap();
+        assert_eq!(encoded.len(), expected, "to_allocvec");
+        assert_eq!(serialized_size(value).unwrap(), expected, "serialized_size");
+    }
+
+    #[derive(Serialize)]
+    enum Enum {
+        Unit,
+        Newtype(u8),
+        Tuple(u8, u16),
+        Struct { a: u32, b: Option<u8> },
+    }
+
+    /// A unit variant with an index that takes two varint bytes.
+    struct HighVariant;
+
+    impl Serialize for HighVariant {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_unit_variant("HighVariant", 200, "V")
+        }
+    }
+
+    /// Serializes with `collect_str`, like types that only implement `Display`.
+    struct Collected<T>(T);
+
+    impl<T: fmt::Display> Serialize for Collected<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(&self.0)
+        }
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        for (value, len) in [
+            (0u64, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (u64::MAX, 10),
+        ] {
+            assert_size(&value, len);
+        }
+        assert_size(&u16::MAX, 3);
+        assert_size(&u32::MAX, 5);
+        assert_size(&u128::MAX, 19);
+    }
+
+    #[test]
+    fn zigzag_varints() {
+        // Zigzag maps -64 to 127 and 64 to 128.
+        for (value, len) in [(0i64, 1), (-1, 1), (-64, 1), (64, 2), (-65, 2)] {
+            assert_size(&value, len);
+        }
+        assert_size(&i64::MIN, 10);
+        assert_size(&i64::MAX, 10);
+        assert_size(&i16::MIN, 3);
+        assert_size(&i128::MIN, 19);
+        assert_size(&i128::MAX, 19);
+        assert_size(&-1i128, 1);
+    }
+
+    #[test]
+    fn fixed_size_primitives() {
+        assert_size(&true, 1);
+        assert_size(&-1i8, 1);
+        assert_size(&u8::MAX, 1);
+        assert_size(&1.5f32, 4);
+        assert_size(&1.5f64, 8);
+        assert_size(&(), 0);
+    }
+
+    #[test]
+    fn chars_are_strings() {
+        assert_size(&'a', 2);
+        assert_size(&'é', 3);
+        assert_size(&'€', 4);
+        assert_size(&'😀', 5);
+    }
+
+    #[test]
+    fn length_prefixes() {
+        for len in BOUNDARY_LENS {
+            let prefix = varint::encoded_len(len as u64);
+            assert_size(&vec![0u8; len], prefix + len);
+            assert_size("x".repeat(len).as_str(), prefix + len);
+            assert_size(&vec![(); len], prefix);
+        }
+        let map: BTreeMap<u8, u8> = (0..200).map(|key| (key, key)).collect();
+        assert_size(&map, 2 + 200 * 2);
+    }
+
+    #[test]
+    fn options_and_tuples() {
+        assert_size(&None::<u64>, 1);
+        assert_size(&Some(u64::MAX), 11);
+        assert_size(&(1u8, 300u16, 'a'), 1 + 2 + 2);
+        assert_size(&[0u8; 4], 4);
+    }
+
+    #[test]
+    fn enums() {
+        assert_size(&Enum::Unit, 1);
+        assert_size(&Enum::Newtype(7), 2);
+        assert_size(&Enum::Tuple(7, 300), 1 + 1 + 2);
+        assert_size(&Enum::Struct { a: 128, b: Some(1) }, 1 + 2 + 2);
+        assert_size(&HighVariant, 2);
+    }
+
+    #[test]
+    fn collect_str() {
+        assert_size(&Collected(""), 1);
+        assert_size(&Collected(12345), 6);
+        assert_size(&Collected("é".repeat(64)), 2 + 128);
+    }
+
+    #[test]
+    fn unknown_sequence_length() {
+        struct Unknown;
+
+        impl Serialize for Unknown {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_seq(None)?;
+                unreachable!("postcard rejects sequences of unknown length")
+            }
+        }
+
+        assert_eq!(
+            serialized_size(&Unknown),
+            Err(postcard::Error::SerializeSeqLengthUnknown)
+        );
+        assert_eq!(
+            to_allocvec(&Unknown),
+            Err(postcard::Error::SerializeSeqLengthUnknown)
+        );
+    }
+
+    #[test]
+    fn max_sizes() {
+        let largest = Span {
+            trace_id: TraceId::new([0xff; 16]),
+            span_timestamp: DateTime::from_timestamp_nanos(i64::MIN),
+        };
+        assert_size(&largest, Span::POSTCARD_MAX_SIZE);
+        assert_size(&vec![largest; 128], max_batch_size(128));
+        let legacy = crate::migrate::to_legacy_allocvec(&[Span {
+            trace_id: TraceId::new([0; 16]),
+            span_timestamp: DateTime::default(),
+        }])
+        .unwrap();
+        // The sequence length, the 25 bytes of the legacy trace ID and the timestamp.
+        assert_eq!(legacy.len(), 1 + 25 + 1);
+    }
+}
@@ -0,0 +1,500 @@
+//! Reading and writing an encoded `Vec<Span>` one span at a time.
+//!
+//! postcard encodes a sequence as its varint length followed by the elements, so a batch can be
+//! decoded lazily without materialising the whole `Vec`, and written without having one.
+
+use std::fmt;
+use std::io::{self, BufReader, Read};
+
+use base64::display::Base64Display;
+use base64::prelude::BASE64_STANDARD;
+use postcard::{take_from_bytes, to_allocvec};
+
+use crate::codec::{from_io, to_io};
+use crate::size::MaxSize;
+use crate::Span;
+
+/// Why a batch could not be read or written span by span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanBatchError {
+    Postcard(postcard::Error),
+    /// More spans were written than the length prefix announced.
+    TooManySpans {
+        len: usize,
+    },
+    /// The writer was finished before all announced spans were written.
+    MissingSpans {
+        len: usize,
+        written: usize,
+    },
+}
+
+impl fmt::Display for SpanBatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpanBatchError::Postcard(error) => write!(f, "{error}"),
+            SpanBatchError::TooManySpans { len } => {
+                write!(f, "more than the announced {len} spans written")
+            }
+            SpanBatchError::MissingSpans { len, written } => {
+                write!(f, "only {written} of the announced {len} spans written")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpanBatchError {}
+
+impl From<postcard::Error> for SpanBatchError {
+    fn from(error: postcard::Error) -> Self {
+        SpanBatchError::Postcard(error)
+    }
+}
+
+/// Yields the spans of an encoded `Vec<Span>` from a slice, one at a time.
+pub struct SpanBatchReader<'a> {
+    rest: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> SpanBatchReader<'a> {
+    /// Reads the length prefix of the batch at the front of `bytes`.
+    pub fn new(bytes: &'a [u8]) -> postcard::Result<Self> {
+        let (remaining, rest) = take_from_bytes::<usize>(bytes)?;
+        Ok(SpanBatchReader { rest, remaining })
+    }
+
+    /// Spans not yet read.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// The bytes after the spans read so far, only the bytes after the batch once all are read.
+    pub fn rest(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl Iterator for SpanBatchReader<'_> {
+    type Item = postcard::Result<Span>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match take_from_bytes::<Span>(self.rest) {
+            Ok((span, rest)) => {
+                self.rest = rest;
+                self.remaining -= 1;
+                Some(Ok(span))
+            }
+            Err(error) => {
+                // The position of the next span is unknown after an error.
+                self.remaining = 0;
+                Some(Err(error))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Decoding may fail early, the length prefix is only an upper bound.
+        (0, Some(self.remaining))
+    }
+}
+
+/// Yields the spans of an encoded `Vec<Span>` from an `io::Read`, one at a time.
+///
+/// Read errors are reported as `DeserializeUnexpectedEnd`, like in [`from_io`].
+pub struct SpanBatchIoReader<R> {
+    reader: R,
+    remaining: usize,
+}
+
+impl<R: Read> SpanBatchIoReader<R> {
+    /// Reads the length prefix of the batch from `reader`.
+    pub fn new(mut reader: R) -> postcard::Result<Self> {
+        let (remaining, _) = from_io::<usize, _>(&mut reader, &mut [])?;
+        Ok(SpanBatchIoReader { reader, remaining })
+    }
+
+    /// Spans not yet read.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Returns the reader, positioned after the spans read so far.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for SpanBatchIoReader<R> {
+    type Item = postcard::Result<Span>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // The trace ID is read into the scratch buffer before it is copied into the span.
+        let mut scratch = [0u8; Span::POSTCARD_MAX_SIZE];
+        match from_io::<Span, _>(&mut self.reader, &mut scratch) {
+            Ok((span, _)) => {
+                self.remaining -= 1;
+                Some(Ok(span))
+            }
+            Err(error) => {
+                self.remaining = 0;
+                Some(Err(error))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+/// Writes an encoded `Vec<Span>` of a known length to an `io::Write`, one span at a time.
+///
+/// Write errors are reported as `SerializeBufferFull`, like in [`to_io`].
+pub struct SpanBatchWriter<W> {
+    writer: W,
+    len: usize,
+    written: usize,
+}
+
+impl<W: io::Write> SpanBatchWriter<W> {
+    /// Writes the length prefix of a batch of `len` spans.
+    pub fn new(mut writer: W, len: usize) -> Result<Self, SpanBatchError> {
+        to_io(&len, &mut writer)?;
+        Ok(SpanBatchWriter {
+            writer,
+            len,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, span: &Span) -> Result<(), SpanBatchError> {
+        if self.written == self.len {
+            return Err(SpanBatchError::TooManySpans { len: self.len });
+        }
+        to_io(span, &mut self.writer)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Returns the writer once all announced spans are written.
+    pub fn finish(self) -> Result<W, SpanBatchError> {
+        if self.written < self.len {
+            return Err(SpanBatchError::MissingSpans {
+                len: self.len,
+                written: self.written,
+            });
+        }
+        Ok(self.writer)
+    }
+}
+
+/// A span batch reader or writer that disagrees with `to_allocvec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanBatchCheckError {
+    /// The encoding of `to_allocvec`.
+    pub expected: Vec<u8>,
+    pub kind: SpanBatchCheckErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanBatchCheckErrorKind {
+    Serialize(postcard::Error),
+    Write(SpanBatchError),
+    /// `SpanBatchWriter` wrote different bytes.
+    WriteMismatch {
+        written: Vec<u8>,
+    },
+    Read {
+        reader: &'static str,
+        error: postcard::Error,
+    },
+    /// The span at `index` was read back differently, or the reader ended before it.
+    ReadMismatch {
+        reader: &'static str,
+        index: usize,
+    },
+    /// The reader yielded more spans than the batch holds.
+    TooManyRead {
+        reader: &'static str,
+    },
+    /// Bytes of the batch were left over after the last span.
+    Unread {
+        reader: &'static str,
+        len: usize,
+    },
+}
+
+impl fmt::Display for SpanBatchCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SpanBatchCheckErrorKind::Serialize(error) => {
+                write!(f, "failed to serialize the batch: {error}")?
+            }
+            SpanBatchCheckErrorKind::Write(error) => write!(f, "SpanBatchWriter failed: {error}")?,
+            SpanBatchCheckErrorKind::WriteMismatch { written } => write!(
+                f,
+                "SpanBatchWriter wrote different bytes than to_allocvec\n  written ({} bytes, \
+                 base64): {}",
+                written.len(),
+                Base64Display::new(written, &BASE64_STANDARD)
+            )?,
+            SpanBatchCheckErrorKind::Read { reader, error } => {
+                write!(f, "{reader} failed: {error}")?
+            }
+            SpanBatchCheckErrorKind::ReadMismatch { reader, index } => {
+                write!(f, "{reader} read span {index} differently or not at all")?
+            }
+            SpanBatchCheckErrorKind::TooManyRead { reader } => {
+                write!(f, "{reader} read more spans than the batch holds")?
+            }
+            SpanBatchCheckErrorKind::Unread { reader, len } => {
+                write!(f, "{reader} left {len} bytes unread after the last span")?
+            }
+        }
+        write!(
+            f,
+            "\n  to_allocvec ({} bytes, base64): {}",
+            self.expected.len(),
+            Base64Display::new(&self.expected, &BASE64_STANDARD)
+        )
+    }
+}
+
+impl std::error::Error for SpanBatchCheckError {}
+
+/// Checks that `SpanBatchWriter` writes `spans` byte for byte like `to_allocvec`, and that both
+/// readers yield them back from that encoding.
+pub fn check_span_batch(spans: &[Span]) -> Result<(), SpanBatchCheckError> {
+    let expected = to_allocvec(spans).map_err(|error| SpanBatchCheckError {
+        expected: Vec::new(),
+        kind: SpanBatchCheckErrorKind::Serialize(error),
+    })?;
+    let error = |kind| SpanBatchCheckError {
+        expected: expected.clone(),
+        kind,
+    };
+
+    let written = SpanBatchWriter::new(Vec::new(), spans.len())
+        .and_then(|mut writer| {
+            spans.iter().try_for_each(|span| writer.write(span))?;
+            writer.finish()
+        })
+        .map_err(|e| error(SpanBatchCheckErrorKind::Write(e)))?;
+    if written != expected {
+        return Err(error(SpanBatchCheckErrorKind::WriteMismatch { written }));
+    }
+
+    let reader = "SpanBatchReader";
+    let read = SpanBatchReader::new(&expected)
+        .map_err(|e| error(SpanBatchCheckErrorKind::Read { reader, error: e }))?;
+    let rest = compare_read(reader, spans, read, &error)?.rest();
+    if !rest.is_empty() {
+        let len = rest.len();
+        return Err(error(SpanBatchCheckErrorKind::Unread { reader, len }));
+    }
+
+    // A small buffer makes the reader refill in the middle of spans.
+    let reader = "SpanBatchIoReader";
+    let read = SpanBatchIoReader::new(BufReader::with_capacity(7, expected.as_slice()))
+        .map_err(|e| error(SpanBatchCheckErrorKind::Read { reader, error: e }))?;
+    let mut rest = compare_read(reader, spans, read, &error)?.into_inner();
+    let len = io::copy(&mut rest, &mut io::sink()).expect("reading a slice cannot fail") as usize;
+    if len != 0 {
+        return Err(error(SpanBatchCheckErrorKind::Unread { reader, len }));
+    }
+    Ok(())
+}
+
+/// Reads all spans from `read`, which must yield exactly `spans`, and returns the reader.
+fn compare_read<I>(
+    reader: &'static str,
+    spans: &[Span],
+    mut read: I,
+    error: &impl Fn(SpanBatchCheckErrorKind) -> SpanBatchCheckError,
+) -> Result<I, SpanBatchCheckError>
+where
+    I: Iterator<Item = postcard::Result<Span>>,
+{
+    for (index, expected) in spans.iter().enumerate() {
+        match read.next() {
+            Some(Ok(span)) if span == *expected => {}
+            Some(Err(e)) => return Err(error(SpanBatchCheckErrorKind::Read { reader, error: e })),
+            _ => {
+                return Err(error(SpanBatchCheckErrorKind::ReadMismatch {
+                    reader,
+                    index,
+                }))
+            }
+        }
+    }
+    if read.next().is_some() {
+        return Err(error(SpanBatchCheckErrorKind::TooManyRead { reader }));
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use postcard::Error;
+
+    use crate::migrate::to_legacy_allocvec;
+    use crate::{test_span, test_spans, BOUNDARY_LENS};
+
+    use super::*;
+
+    fn write_all(len: usize, spans: &[Span]) -> Result<Vec<u8>, SpanBatchError> {
+        let mut writer = SpanBatchWriter::new(Vec::new(), len)?;
+        spans.iter().try_for_each(|span| writer.write(span))?;
+        writer.finish()
+    }
+
+    #[test]
+    fn known_encoding() {
+        let mut encoded = vec![2];
+        for (trace_byte, timestamp) in [(1, 0x01), (2, 0x03)] {
+            encoded.push(16);
+            encoded.extend_from_slice(&[trace_byte; 16]);
+            encoded.push(timestamp);
+        }
+        let batch = vec![test_span(1, -1), test_span(2, -2)];
+        assert_eq!(write_all(2, &batch), Ok(encoded.clone()));
+
+        let mut reader = SpanBatchReader::new(&encoded).unwrap();
+        assert_eq!(reader.remaining(), 2);
+        assert_eq!(reader.size_hint(), (0, Some(2)));
+        assert_eq!(reader.next(), Some(Ok(test_span(1, -1))));
+        assert_eq!(reader.remaining(), 1);
+        assert_eq!(reader.rest(), &encoded[19..]);
+        assert_eq!(reader.next(), Some(Ok(test_span(2, -2))));
+        assert_eq!(reader.next(), None);
+        assert!(reader.rest().is_empty());
+
+        let read: postcard::Result<Vec<Span>> = SpanBatchIoReader::new(encoded.as_slice())
+            .unwrap()
+            .collect();
+        assert_eq!(read, Ok(batch));
+    }
+
+    #[test]
+    fn length_boundaries() {
+        for len in BOUNDARY_LENS {
+            let batch = test_spans(len);
+            let encoded = write_all(len, &batch).unwrap();
+            assert_eq!(encoded, to_allocvec(&batch).unwrap(), "{len} spans");
+
+            let read: postcard::Result<Vec<Span>> =
+                SpanBatchReader::new(&encoded).unwrap().collect();
+            assert_eq!(read.as_ref(), Ok(&batch), "{len} spans");
+            let read: postcard::Result<Vec<Span>> =
+                SpanBatchIoReader::new(BufReader::with_capacity(3, encoded.as_slice()))
+                    .unwrap()
+                    .collect();
+            assert_eq!(read.as_ref(), Ok(&batch), "{len} spans");
+
+            assert_eq!(check_span_batch(&batch), Ok(()), "{len} spans");
+        }
+    }
+
+    #[test]
+    fn readers_leave_the_bytes_after_the_batch() {
+        let mut encoded = to_allocvec(&test_spans(2)).unwrap();
+        encoded.extend_from_slice(&[0xaa, 0xbb]);
+
+        let mut reader = SpanBatchReader::new(&encoded).unwrap();
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.rest(), [0xaa, 0xbb]);
+
+        let mut reader = SpanBatchIoReader::new(encoded.as_slice()).unwrap();
+        assert_eq!(reader.by_ref().count(), 2);
+        assert_eq!(reader.into_inner(), [0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn readers_accept_legacy_trace_ids() {
+        let batch = test_spans(3);
+        let legacy = to_legacy_allocvec(&batch).unwrap();
+        let read: postcard::Result<Vec<Span>> = SpanBatchReader::new(&legacy).unwrap().collect();
+        assert_eq!(read.as_ref(), Ok(&batch));
+        let read: postcard::Result<Vec<Span>> =
+            SpanBatchIoReader::new(legacy.as_slice()).unwrap().collect();
+        assert_eq!(read, Ok(batch));
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(
+            SpanBatchReader::new(&[]).err(),
+            Some(Error::DeserializeUnexpectedEnd)
+        );
+        assert_eq!(
+            SpanBatchIoReader::new(io::empty()).err(),
+            Some(Error::DeserializeUnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn readers_stop_at_the_first_error() {
+        // Two spans announced, one and a half present.
+        let batch = test_spans(2);
+        let encoded = to_allocvec(&batch).unwrap();
+        let truncated = &encoded[..encoded.len() - 5];
+
+        let mut reader = SpanBatchReader::new(truncated).unwrap();
+        assert_eq!(reader.next(), Some(Ok(batch[0].clone())));
+        assert_eq!(reader.next(), Some(Err(Error::DeserializeUnexpectedEnd)));
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.next(), None);
+
+        let mut reader = SpanBatchIoReader::new(truncated).unwrap();
+        assert_eq!(reader.next(), Some(Ok(batch[0].clone())));
+        assert_eq!(reader.next(), Some(Err(Error::DeserializeUnexpectedEnd)));
+        assert_eq!(reader.next(), None);
+
+        // A trace ID of 17 bytes.
+        let mut malformed = vec![1, 17];
+        malformed.extend_from_slice(&[0; 18]);
+        let mut reader = SpanBatchReader::new(&malformed).unwrap();
+        assert!(matches!(reader.next(), Some(Err(_))));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn huge_length_prefix() {
+        let mut reader = SpanBatchReader::new(&[0xff, 0xff, 0xff, 0xff, 0x0f]).unwrap();
+        assert_eq!(reader.remaining(), u32::MAX as usize);
+        assert_eq!(reader.next(), Some(Err(Error::DeserializeUnexpectedEnd)));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn writer_checks_the_span_count() {
+        let batch = test_spans(2);
+        assert_eq!(
+            write_all(1, &batch),
+            Err(SpanBatchError::TooManySpans { len: 1 })
+        );
+        assert_eq!(
+            write_all(3, &batch),
+            Err(SpanBatchError::MissingSpans { len: 3, written: 2 })
+        );
+        assert_eq!(write_all(0, &[]), Ok(vec![0]));
+    }
+
+    #[test]
+    fn writer_reports_write_errors() {
+        let mut buffer = [0u8; 10];
+        let mut writer = SpanBatchWriter::new(&mut buffer[..], 1).unwrap();
+        assert_eq!(
+            writer.write(&test_span(0, 0)),
+            Err(SpanBatchError::Postcard(Error::SerializeBufferFull))
+        );
+        assert!(SpanBatchWriter::new(&mut [][..], 0).is_err());
+    }
+}
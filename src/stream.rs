@@ -0,0 +1,483 @@
+//! Streams of COBS-framed span batches, split into chunks the way a socket delivers them.
+//!
+//! Every batch is encoded with `to_allocvec_cobs`, which ends the frame with a zero byte, and
+//! the frames are concatenated. A [`FrameReassembler`] collects the chunks back into frames that
+//! are decoded with `from_bytes_cobs`. Corrupted or truncated frames must only lose themselves:
+//! the reassembler resynchronises at the next zero byte and every frame after it decodes again.
+
+use std::fmt;
+
+use postcard::{from_bytes_cobs, to_allocvec_cobs};
+use rand::Rng;
+
+use crate::mutate::{catch_quietly, panic_message};
+use crate::Span;
+
+/// Most batches sent in one stream.
+pub const MAX_FRAMES: usize = 8;
+/// Most frames a stream is corrupted in.
+const MAX_CORRUPTIONS: usize = 3;
+/// Largest chunk delivered at once, about an Ethernet MTU.
+const MAX_CHUNK_LEN: usize = 1500;
+
+/// Collects chunks of a stream into complete COBS frames.
+#[derive(Debug, Clone, Default)]
+pub struct FrameReassembler {
+    pending: Vec<u8>,
+}
+
+impl FrameReassembler {
+    /// Appends `chunk` and moves every frame it completes, with its zero delimiter, to `frames`.
+    ///
+    /// Empty frames, i.e. repeated delimiters, are skipped, so senders can flush the stream with
+    /// extra zeros.
+    pub fn feed(&mut self, chunk: &[u8], frames: &mut Vec<Vec<u8>>) {
+        for part in chunk.split_inclusive(|&byte| byte == 0) {
+            self.pending.extend_from_slice(part);
+            if part.last() == Some(&0) {
+                let frame = std::mem::take(&mut self.pending);
+                if frame.len() > 1 {
+                    frames.push(frame);
+                }
+            }
+        }
+    }
+
+    /// The bytes of the unfinished frame at the end of the stream so far.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+}
+
+/// Damage done to a single frame of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Replaces the byte at `offset` with `byte`, which is not zero.
+    Overwrite {
+        frame: usize,
+        offset: usize,
+        byte: u8,
+    },
+    /// Replaces the byte at `offset` with a zero, splitting the frame in two.
+    Split { frame: usize, offset: usize },
+    /// Cuts the frame to `len` bytes but keeps its delimiter, as when the sender restarts.
+    Truncate { frame: usize, len: usize },
+    /// Cuts the last frame to `len` bytes without a delimiter, as when the connection drops.
+    CutOff { len: usize },
+}
+
+impl Corruption {
+    /// Draws a corruption of `frame`, whose encoding without delimiter is `len` bytes long.
+    ///
+    /// Only the last of `frames` frames can be cut off.
+    fn random<R: Rng>(rng: &mut R, frame: usize, len: usize, frames: usize) -> Self {
+        let offset = rng.gen_range(0..len);
+        match rng.gen_range(0..4) {
+            0 => Corruption::Overwrite {
+                frame,
+                offset,
+                byte: rng.gen_range(1..=u8::MAX),
+            },
+            1 => Corruption::Split { frame, offset },
+            3 if frame == frames - 1 => Corruption::CutOff { len: offset },
+            _ => Corruption::Truncate { frame, len: offset },
+        }
+    }
+
+    fn frame(&self, frames: usize) -> usize {
+        match *self {
+            Corruption::Overwrite { frame, .. }
+            | Corruption::Split { frame, .. }
+            | Corruption::Truncate { frame, .. } => frame,
+            Corruption::CutOff { .. } => frames - 1,
+        }
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::Overwrite {
+                frame,
+                offset,
+                byte,
+            } => write!(
+                f,
+                "overwrite byte {offset} of frame {frame} with {byte:#04x}"
+            ),
+            Corruption::Split { frame, offset } => {
+                write!(f, "zero byte {offset} of frame {frame}")
+            }
+            Corruption::Truncate { frame, len } => {
+                write!(f, "truncate frame {frame} to {len} bytes")
+            }
+            Corruption::CutOff { len } => write!(f, "cut off the last frame after {len} bytes"),
+        }
+    }
+}
+
+/// Where a frame the reassembler should produce comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// The undamaged frame of a batch, which must decode to it.
+    Intact(usize),
+    /// A piece of a corrupted frame, which may decode to anything or fail.
+    Damaged(usize),
+}
+
+/// A stream of corrupted frames and the frames it should reassemble into.
+struct CorruptedStream {
+    bytes: Vec<u8>,
+    origins: Vec<Origin>,
+    /// The length of the frame left unfinished at the end of the stream.
+    pending: usize,
+}
+
+/// Concatenates `frames`, each ending in its delimiter, after applying `corruptions`.
+fn corrupt(frames: &[Vec<u8>], corruptions: &[Corruption]) -> CorruptedStream {
+    let mut stream = CorruptedStream {
+        bytes: Vec::new(),
+        origins: Vec::new(),
+        pending: 0,
+    };
+    for (index, frame) in frames.iter().enumerate() {
+        let corruption = corruptions
+            .iter()
+            .find(|corruption| corruption.frame(frames.len()) == index);
+        let Some(corruption) = corruption else {
+            stream.bytes.extend_from_slice(frame);
+            stream.origins.push(Origin::Intact(index));
+            continue;
+        };
+        let mut content = frame[..frame.len() - 1].to_vec();
+        match *corruption {
+            Corruption::Overwrite { offset, byte, .. } => content[offset] = byte,
+            Corruption::Split { offset, .. } => content[offset] = 0,
+            Corruption::Truncate { len, .. } => content.truncate(len),
+            Corruption::CutOff { len } => {
+                stream.bytes.extend_from_slice(&frame[..len]);
+                stream.pending = len;
+                continue;
+            }
+        }
+        let pieces = content
+            .split(|&byte| byte == 0)
+            .filter(|piece| !piece.is_empty());
+        stream
+            .origins
+            .extend(pieces.map(|_| Origin::Damaged(index)));
+        stream.bytes.extend_from_slice(&content);
+        stream.bytes.push(0);
+    }
+    stream
+}
+
+/// Draws the largest chunk the stream is delivered in: small reads like a UART's, MTU-sized
+/// reads, or the whole stream at once.
+fn random_max_chunk_len<R: Rng>(rng: &mut R, len: usize) -> usize {
+    match rng.gen_range(0..3) {
+        0 => 16,
+        1 => MAX_CHUNK_LEN,
+        _ => len.max(1),
+    }
+}
+
+/// Counters of streams that were reassembled and decoded.
+#[derive(Debug, Clone, Default)]
+pub struct StreamStats {
+    pub frames: u64,
+    pub chunks: u64,
+    pub corrupted: u64,
+    /// Pieces of corrupted frames that still decoded, COBS has no checksum.
+    pub damaged_accepted: u64,
+    pub damaged_rejected: u64,
+}
+
+impl StreamStats {
+    pub fn merge(&mut self, other: &StreamStats) {
+        self.frames += other.frames;
+        self.chunks += other.chunks;
+        self.corrupted += other.corrupted;
+        self.damaged_accepted += other.damaged_accepted;
+        self.damaged_rejected += other.damaged_rejected;
+    }
+}
+
+/// A stream that was not reassembled or decoded as expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamError {
+    pub corruptions: Vec<Corruption>,
+    /// Chunks were drawn uniformly from 1 to this many bytes.
+    pub max_chunk_len: usize,
+    pub kind: StreamErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamErrorKind {
+    Encode(postcard::Error),
+    /// The reassembler produced another number of frames than the stream contains.
+    FrameCount {
+        expected: usize,
+        actual: usize,
+    },
+    /// The unfinished frame at the end of the stream has an unexpected length.
+    Pending {
+        expected: usize,
+        actual: usize,
+    },
+    Panicked {
+        frame: usize,
+        message: String,
+    },
+    /// An undamaged frame failed to decode.
+    Lost {
+        batch: usize,
+        error: postcard::Error,
+    },
+    /// An undamaged frame decoded to another batch.
+    Mismatch {
+        batch: usize,
+    },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            StreamErrorKind::Encode(error) => write!(f, "failed to serialize a frame: {error}")?,
+            StreamErrorKind::FrameCount { expected, actual } => write!(
+                f,
+                "reassembled {actual} frames from a stream of {expected} frames"
+            )?,
+            StreamErrorKind::Pending { expected, actual } => write!(
+                f,
+                "{actual} bytes of an unfinished frame are pending, expected {expected}"
+            )?,
+            StreamErrorKind::Panicked { frame, message } => {
+                write!(f, "decoding frame {frame} panicked: {message}")?
+            }
+            StreamErrorKind::Lost { batch, error } => write!(
+                f,
+                "the undamaged frame of batch {batch} failed to decode: {error}"
+            )?,
+            StreamErrorKind::Mismatch { batch } => write!(
+                f,
+                "the undamaged frame of batch {batch} decodes to different spans"
+            )?,
+        }
+        let corruptions: Vec<_> = self.corruptions.iter().map(|c| c.to_string()).collect();
+        write!(
+            f,
+            "\n  corruptions: [{}]\n  chunks of up to {} bytes",
+            corruptions.join(", "),
+            self.max_chunk_len
+        )
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// Encodes `batches` as a COBS stream, corrupts up to [`MAX_CORRUPTIONS`] frames in about half
+/// of the streams, and checks that reassembling random chunks of it recovers every undamaged
+/// batch.
+pub fn check_stream<R: Rng>(
+    rng: &mut R,
+    batches: &[Vec<Span>],
+) -> Result<StreamStats, StreamError> {
+    let mut corruptions = Vec::new();
+    let frames = batches
+        .iter()
+        .map(to_allocvec_cobs)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| StreamError {
+            corruptions: Vec::new(),
+            max_chunk_len: 0,
+            kind: StreamErrorKind::Encode(error),
+        })?;
+    if rng.gen() {
+        let count = rng.gen_range(1..=MAX_CORRUPTIONS.min(frames.len()));
+        let mut indices: Vec<usize> = (0..frames.len()).collect();
+        for _ in 0..count {
+            let frame = indices.swap_remove(rng.gen_range(0..indices.len()));
+            let len = frames[frame].len() - 1;
+            corruptions.push(Corruption::random(rng, frame, len, frames.len()));
+        }
+    }
+    let stream = corrupt(&frames, &corruptions);
+    let max_chunk_len = random_max_chunk_len(rng, stream.bytes.len());
+    let error = |kind| StreamError {
+        corruptions: corruptions.clone(),
+        max_chunk_len,
+        kind,
+    };
+
+    let mut reassembler = FrameReassembler::default();
+    let mut reassembled = Vec::new();
+    let mut remaining = stream.bytes.as_slice();
+    let mut chunks = 0;
+    while !remaining.is_empty() {
+        let (chunk, rest) =
+            remaining.split_at(rng.gen_range(1..=max_chunk_len.min(remaining.len())));
+        reassembler.feed(chunk, &mut reassembled);
+        remaining = rest;
+        chunks += 1;
+    }
+    if reassembled.len() != stream.origins.len() {
+        return Err(error(StreamErrorKind::FrameCount {
+            expected: stream.origins.len(),
+            actual: reassembled.len(),
+        }));
+    }
+    if reassembler.pending().len() != stream.pending {
+        return Err(error(StreamErrorKind::Pending {
+            expected: stream.pending,
+            actual: reassembler.pending().len(),
+        }));
+    }
+
+    let mut stats = StreamStats {
+        frames: frames.len() as u64,
+        chunks,
+        corrupted: corruptions.len() as u64,
+        ..StreamStats::default()
+    };
+    for (frame, (mut bytes, origin)) in reassembled.into_iter().zip(stream.origins).enumerate() {
+        let decoded =
+            catch_quietly(|| from_bytes_cobs::<Vec<Span>>(&mut bytes)).map_err(|payload| {
+                error(StreamErrorKind::Panicked {
+                    frame,
+                    message: panic_message(&*payload),
+                })
+            })?;
+        match (origin, decoded) {
+            (Origin::Intact(batch), Ok(spans)) if spans == batches[batch] => {}
+            (Origin::Intact(batch), Ok(_)) => {
+                return Err(error(StreamErrorKind::Mismatch { batch }))
+            }
+            (Origin::Intact(batch), Err(e)) => {
+                return Err(error(StreamErrorKind::Lost { batch, error: e }))
+            }
+            (Origin::Damaged(_), Ok(_)) => stats.damaged_accepted += 1,
+            (Origin::Damaged(_), Err(_)) => stats.damaged_rejected += 1,
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_span, test_spans, BOUNDARY_LENS};
+
+    use super::*;
+
+    /// Feeds `chunks` to a new reassembler, returning the frames and the pending bytes.
+    fn reassemble(chunks: &[&[u8]]) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let mut reassembler = FrameReassembler::default();
+        let mut frames = Vec::new();
+        for chunk in chunks {
+            reassembler.feed(chunk, &mut frames);
+        }
+        (frames, reassembler.pending().to_vec())
+    }
+
+    #[test]
+    fn known_frames() {
+        // An empty batch and a batch of one span with a zero trace ID, COBS encoded.
+        let empty = to_allocvec_cobs(&Vec::<Span>::new()).unwrap();
+        assert_eq!(empty, [0x01, 0x01, 0x00]);
+        let one = to_allocvec_cobs(&vec![test_span(0, 1)]).unwrap();
+        assert_eq!(
+            one,
+            [
+                0x03, 0x01, 0x10, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+                0x01, 0x01, 0x01, 0x01, 0x02, 0x02, 0x00
+            ]
+        );
+
+        let stream = [empty.clone(), one.clone()].concat();
+        assert_eq!(reassemble(&[&stream]), (vec![empty, one], Vec::new()));
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(reassemble(&[]), (Vec::new(), Vec::new()));
+        assert_eq!(reassemble(&[&[], &[]]), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn repeated_delimiters_are_skipped() {
+        assert_eq!(reassemble(&[&[0, 0], &[0]]), (Vec::new(), Vec::new()));
+        assert_eq!(
+            reassemble(&[&[0, 5, 0, 0, 6], &[0, 0]]),
+            (vec![vec![5, 0], vec![6, 0]], Vec::new())
+        );
+    }
+
+    #[test]
+    fn frames_split_across_chunks() {
+        let frame = to_allocvec_cobs(&vec![test_span(7, -7); 3]).unwrap();
+        let (head, tail) = frame.split_at(frame.len() - 1);
+        // The delimiter on its own.
+        assert_eq!(reassemble(&[head, tail]), (vec![frame.clone()], Vec::new()));
+        // One byte at a time.
+        let bytes: Vec<&[u8]> = frame.chunks(1).collect();
+        assert_eq!(reassemble(&bytes), (vec![frame.clone()], Vec::new()));
+        // An empty chunk in between.
+        assert_eq!(
+            reassemble(&[&frame[..5], &[], &frame[5..]]),
+            (vec![frame], Vec::new())
+        );
+    }
+
+    #[test]
+    fn unfinished_frame_is_pending() {
+        let frame = to_allocvec_cobs(&vec![test_span(1, 1)]).unwrap();
+        let stream = [&frame[..], &frame[..4]].concat();
+        assert_eq!(
+            reassemble(&[&stream]),
+            (vec![frame.clone()], frame[..4].to_vec())
+        );
+
+        // The pending bytes are completed by the next delimiter.
+        let mut reassembler = FrameReassembler::default();
+        let mut frames = Vec::new();
+        reassembler.feed(&frame[..4], &mut frames);
+        assert!(frames.is_empty());
+        reassembler.feed(&frame[4..], &mut frames);
+        assert_eq!(frames, [frame]);
+        assert!(reassembler.pending().is_empty());
+    }
+
+    #[test]
+    fn frames_at_length_boundaries_decode() {
+        for len in BOUNDARY_LENS {
+            let spans = test_spans(len);
+            let frame = to_allocvec_cobs(&spans).unwrap();
+            assert_eq!(frame.iter().filter(|&&byte| byte == 0).count(), 1);
+
+            let stream = [frame.clone(), frame].concat();
+            let chunks: Vec<&[u8]> = stream.chunks(MAX_CHUNK_LEN).collect();
+            let (frames, pending) = reassemble(&chunks);
+            assert!(pending.is_empty());
+            assert_eq!(frames.len(), 2, "{len} spans");
+            for mut frame in frames {
+                assert_eq!(from_bytes_cobs::<Vec<Span>>(&mut frame), Ok(spans.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn resynchronises_after_a_corrupted_frame() {
+        let first = to_allocvec_cobs(&vec![test_span(1, 1)]).unwrap();
+        let second = to_allocvec_cobs(&vec![test_span(2, 2)]).unwrap();
+        // The first frame loses its tail but keeps its delimiter.
+        let stream = [&first[..3], &[0], &second[..]].concat();
+        let (mut frames, pending) = reassemble(&[&stream[..2], &stream[2..]]);
+        assert!(pending.is_empty());
+        assert_eq!(frames.len(), 2);
+        assert!(from_bytes_cobs::<Vec<Span>>(&mut frames[0]).is_err());
+        assert_eq!(
+            from_bytes_cobs::<Vec<Span>>(&mut frames[1]),
+            Ok(vec![test_span(2, 2)])
+        );
+    }
+}
@@ -0,0 +1,1172 @@
+//! Records the serde data-model calls a value serializes to and replays them to its
+//! `Deserialize` impl.
+//!
+//! The trace shows what custom impls like `TraceId`'s actually emit, and replaying it checks
+//! that the deserializer requests the same shapes the serializer produced, before postcard's
+//! bytes get involved.
+
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+/// A single serde data-model call.
+///
+/// Compound values start with the call that opened them, followed by their contents and
+/// [`Call::End`]. Struct fields are preceded by [`Call::Field`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Bool(bool),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    None,
+    Some,
+    Unit,
+    UnitStruct(&'static str),
+    UnitVariant {
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    },
+    NewtypeStruct(&'static str),
+    NewtypeVariant {
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    },
+    Seq(Option<usize>),
+    Tuple(usize),
+    TupleStruct {
+        name: &'static str,
+        len: usize,
+    },
+    TupleVariant {
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    },
+    Map(Option<usize>),
+    Struct {
+        name: &'static str,
+        len: usize,
+    },
+    StructVariant {
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    },
+    Field(&'static str),
+    SkipField(&'static str),
+    End,
+}
+
+impl Call {
+    /// Whether the call opens a compound value that is closed by [`Call::End`].
+    fn opens(&self) -> bool {
+        matches!(
+            self,
+            Call::Seq(_)
+                | Call::Tuple(_)
+                | Call::TupleStruct { .. }
+                | Call::TupleVariant { .. }
+                | Call::Map(_)
+                | Call::Struct { .. }
+                | Call::StructVariant { .. }
+        )
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Call::Bool(value) => write!(f, "serialize_bool({value})"),
+            Call::I8(value) => write!(f, "serialize_i8({value})"),
+            Call::I16(value) => write!(f, "serialize_i16({value})"),
+            Call::I32(value) => write!(f, "serialize_i32({value})"),
+            Call::I64(value) => write!(f, "serialize_i64({value})"),
+            Call::I128(value) => write!(f, "serialize_i128({value})"),
+            Call::U8(value) => write!(f, "serialize_u8({value})"),
+            Call::U16(value) => write!(f, "serialize_u16({value})"),
+            Call::U32(value) => write!(f, "serialize_u32({value})"),
+            Call::U64(value) => write!(f, "serialize_u64({value})"),
+            Call::U128(value) => write!(f, "serialize_u128({value})"),
+            Call::F32(value) => write!(f, "serialize_f32({value:?})"),
+            Call::F64(value) => write!(f, "serialize_f64({value:?})"),
+            Call::Char(value) => write!(f, "serialize_char({value:?})"),
+            Call::Str(value) => write!(f, "serialize_str({value:?})"),
+            Call::Bytes(bytes) => write!(f, "serialize_bytes({} bytes: {bytes:02x?})", bytes.len()),
+            Call::None => f.write_str("serialize_none()"),
+            Call::Some => f.write_str("serialize_some()"),
+            Call::Unit => f.write_str("serialize_unit()"),
+            Call::UnitStruct(name) => write!(f, "serialize_unit_struct({name:?})"),
+            Call::UnitVariant {
+                name,
+                index,
+                variant,
+            } => write!(f, "serialize_unit_variant({name:?}, {index}, {variant:?})"),
+            Call::NewtypeStruct(name) => write!(f, "serialize_newtype_struct({name:?})"),
+            Call::NewtypeVariant {
+                name,
+                index,
+                variant,
+            } => write!(
+                f,
+                "serialize_newtype_variant({name:?}, {index}, {variant:?})"
+            ),
+            Call::Seq(len) => write!(f, "serialize_seq({len:?})"),
+            Call::Tuple(len) => write!(f, "serialize_tuple({len})"),
+            Call::TupleStruct { name, len } => write!(f, "serialize_tuple_struct({name:?}, {len})"),
+            Call::TupleVariant {
+                name,
+                index,
+                variant,
+                len,
+            } => write!(
+                f,
+                "serialize_tuple_variant({name:?}, {index}, {variant:?}, {len})"
+            ),
+            Call::Map(len) => write!(f, "serialize_map({len:?})"),
+            Call::Struct { name, len } => write!(f, "serialize_struct({name:?}, {len})"),
+            Call::StructVariant {
+                name,
+                index,
+                variant,
+                len,
+            } => write!(
+                f,
+                "serialize_struct_variant({name:?}, {index}, {variant:?}, {len})"
+            ),
+            Call::Field(key) => write!(f, "field {key:?}"),
+            Call::SkipField(key) => write!(f, "skip_field {key:?}"),
+            Call::End => f.write_str("end"),
+        }
+    }
+}
+
+/// Formats `calls` one per line, indenting the contents of compound values.
+pub fn format_trace(calls: &[Call]) -> String {
+    let mut formatted = String::new();
+    let mut depth = 0usize;
+    for call in calls {
+        if *call == Call::End {
+            depth = depth.saturating_sub(1);
+        }
+        formatted.push_str(&"  ".repeat(depth));
+        formatted.push_str(&call.to_string());
+        formatted.push('\n');
+        if call.opens() {
+            depth += 1;
+        }
+    }
+    formatted
+}
+
+/// Why recording or replaying a trace failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceError {
+    /// A `Serialize` or `Deserialize` impl failed.
+    Custom(String),
+    /// The deserializer requested `requested` where the serializer emitted `recorded`, or
+    /// nothing if the trace ended.
+    Mismatch {
+        position: usize,
+        requested: String,
+        recorded: Option<Call>,
+    },
+    /// The deserializer requested a self-describing method, e.g. `deserialize_any`.
+    Unsupported {
+        position: usize,
+        requested: &'static str,
+    },
+    /// The deserializer finished before the end of the trace.
+    TrailingCalls { position: usize },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Custom(message) => f.write_str(message),
+            TraceError::Mismatch {
+                position,
+                requested,
+                recorded: Some(recorded),
+            } => write!(
+                f,
+                "call {position}: the deserializer requested {requested}, the serializer \
+                 emitted {recorded}"
+            ),
+            TraceError::Mismatch {
+                position,
+                requested,
+                recorded: None,
+            } => write!(
+                f,
+                "call {position}: the deserializer requested {requested} after the end of \
+                 the trace"
+            ),
+            TraceError::Unsupported {
+                position,
+                requested,
+            } => write!(
+                f,
+                "call {position}: the deserializer requested {requested}, which a \
+                 non-self-describing format cannot support"
+            ),
+            TraceError::TrailingCalls { position } => write!(
+                f,
+                "call {position}: the deserializer finished before the end of the trace"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl ser::Error for TraceError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        TraceError::Custom(message.to_string())
+    }
+}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        TraceError::Custom(message.to_string())
+    }
+}
+
+/// Records the serde calls of `value`.
+pub fn record<T: Serialize + ?Sized>(value: &T) -> Result<Vec<Call>, TraceError> {
+    let mut serializer = RecordingSerializer::default();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.calls)
+}
+
+/// Records the serde calls of `value` for a human-readable format, like `serde_json`.
+pub fn record_human_readable<T: Serialize + ?Sized>(value: &T) -> Result<Vec<Call>, TraceError> {
+    let mut serializer = RecordingSerializer {
+        human_readable: true,
+        ..RecordingSerializer::default()
+    };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.calls)
+}
+
+/// Replays the calls recorded from `value` to `T`'s `Deserialize` impl, checking that every
+/// requested shape matches, and returns the recorded calls.
+pub fn check_shapes<T: Serialize + DeserializeOwned>(value: &T) -> Result<Vec<Call>, TraceError> {
+    let calls = record(value)?;
+    let mut deserializer = ReplayDeserializer::new(&calls);
+    T::deserialize(&mut deserializer)?;
+    deserializer.finish()?;
+    Ok(calls)
+}
+
+/// Describes the call trace of `value` and whether its `Deserialize` impl requests the same
+/// shapes, for failure reports.
+pub fn explain<T: Serialize + DeserializeOwned>(value: &T) -> String {
+    let calls = match record(value) {
+        Ok(calls) => calls,
+        Err(error) => return format!("recording the serde calls failed: {error}"),
+    };
+    let shapes = match ReplayDeserializer::new(&calls).replay::<T>() {
+        Ok(()) => "the deserializer requests matching shapes".to_string(),
+        Err(error) => error.to_string(),
+    };
+    format!("serde calls:\n{}{shapes}", format_trace(&calls))
+}
+
+/// A serializer that records the calls it receives instead of producing bytes.
+///
+/// Like postcard, it is not human-readable by default, so types pick their binary
+/// representation.
+#[derive(Debug, Default)]
+pub struct RecordingSerializer {
+    pub calls: Vec<Call>,
+    pub human_readable: bool,
+}
+
+impl RecordingSerializer {
+    fn push(&mut self, call: Call) -> Result<(), TraceError> {
+        self.calls.push(call);
+        Ok(())
+    }
+}
+
+macro_rules! record_scalars {
+    ($($method:ident($ty:ty) => $call:ident),* $(,)?) => {
+        $(
+            fn $method(self, value: $ty) -> Result<(), TraceError> {
+                self.push(Call::$call(value.into()))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut RecordingSerializer {
+    type Ok = ();
+    type Error = TraceError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    record_scalars!(
+        serialize_bool(bool) => Bool,
+        serialize_i8(i8) => I8,
+        serialize_i16(i16) => I16,
+        serialize_i32(i32) => I32,
+        serialize_i64(i64) => I64,
+        serialize_i128(i128) => I128,
+        serialize_u8(u8) => U8,
+        serialize_u16(u16) => U16,
+        serialize_u32(u32) => U32,
+        serialize_u64(u64) => U64,
+        serialize_u128(u128) => U128,
+        serialize_f32(f32) => F32,
+        serialize_f64(f64) => F64,
+        serialize_char(char) => Char,
+        serialize_str(&str) => Str,
+        serialize_bytes(&[u8]) => Bytes,
+        serialize_unit_struct(&'static str) => UnitStruct,
+    );
+
+    fn serialize_none(self) -> Result<(), TraceError> {
+        self.push(Call::None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), TraceError> {
+        self.push(Call::Some)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), TraceError> {
+        self.push(Call::Unit)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<(), TraceError> {
+        self.push(Call::UnitVariant {
+            name,
+            index,
+            variant,
+        })
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), TraceError> {
+        self.push(Call::NewtypeStruct(name))?;
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), TraceError> {
+        self.push(Call::NewtypeVariant {
+            name,
+            index,
+            variant,
+        })?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, TraceError> {
+        self.push(Call::Seq(len))?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, TraceError> {
+        self.push(Call::Tuple(len))?;
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self, TraceError> {
+        self.push(Call::TupleStruct { name, len })?;
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, TraceError> {
+        self.push(Call::TupleVariant {
+            name,
+            index,
+            variant,
+            len,
+        })?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, TraceError> {
+        self.push(Call::Map(len))?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self, TraceError> {
+        self.push(Call::Struct { name, len })?;
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, TraceError> {
+        self.push(Call::StructVariant {
+            name,
+            index,
+            variant,
+            len,
+        })?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.human_readable
+    }
+}
+
+macro_rules! record_elements {
+    ($($trait:ident::$method:ident),* $(,)?) => {
+        $(
+            impl ser::$trait for &mut RecordingSerializer {
+                type Ok = ();
+                type Error = TraceError;
+
+                fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), TraceError> {
+                    value.serialize(&mut **self)
+                }
+
+                fn end(self) -> Result<(), TraceError> {
+                    self.push(Call::End)
+                }
+            }
+        )*
+    };
+}
+
+record_elements!(
+    SerializeSeq::serialize_element,
+    SerializeTuple::serialize_element,
+    SerializeTupleStruct::serialize_field,
+    SerializeTupleVariant::serialize_field,
+);
+
+impl ser::SerializeMap for &mut RecordingSerializer {
+    type Ok = ();
+    type Error = TraceError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), TraceError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), TraceError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), TraceError> {
+        self.push(Call::End)
+    }
+}
+
+macro_rules! record_fields {
+    ($($trait:ident),* $(,)?) => {
+        $(
+            impl ser::$trait for &mut RecordingSerializer {
+                type Ok = ();
+                type Error = TraceError;
+
+                fn serialize_field<T: Serialize + ?Sized>(
+                    &mut self,
+                    key: &'static str,
+                    value: &T,
+                ) -> Result<(), TraceError> {
+                    self.push(Call::Field(key))?;
+                    value.serialize(&mut **self)
+                }
+
+                fn skip_field(&mut self, key: &'static str) -> Result<(), TraceError> {
+                    self.push(Call::SkipField(key))
+                }
+
+                fn end(self) -> Result<(), TraceError> {
+                    self.push(Call::End)
+                }
+            }
+        )*
+    };
+}
+
+record_fields!(SerializeStruct, SerializeStructVariant);
+
+/// A deserializer that replays recorded calls and fails as soon as the `Deserialize` impl
+/// requests a different shape than the one recorded.
+///
+/// Like postcard, it is not self-describing, and not human-readable unless created with
+/// [`ReplayDeserializer::human_readable`].
+#[derive(Debug)]
+pub struct ReplayDeserializer<'de> {
+    calls: &'de [Call],
+    position: usize,
+    human_readable: bool,
+}
+
+impl<'de> ReplayDeserializer<'de> {
+    pub fn new(calls: &'de [Call]) -> Self {
+        ReplayDeserializer {
+            calls,
+            position: 0,
+            human_readable: false,
+        }
+    }
+
+    /// Replays calls recorded with [`record_human_readable`].
+    pub fn human_readable(calls: &'de [Call]) -> Self {
+        ReplayDeserializer {
+            human_readable: true,
+            ..ReplayDeserializer::new(calls)
+        }
+    }
+
+    /// Deserializes a `T` from the whole trace.
+    pub fn replay<T: de::Deserialize<'de>>(&mut self) -> Result<(), TraceError> {
+        T::deserialize(&mut *self)?;
+        self.finish()
+    }
+
+    /// Fails if the trace has calls left.
+    pub fn finish(&self) -> Result<(), TraceError> {
+        if self.position == self.calls.len() {
+            Ok(())
+        } else {
+            Err(TraceError::TrailingCalls {
+                position: self.position,
+            })
+        }
+    }
+
+    fn peek(&self) -> Option<&'de Call> {
+        self.calls.get(self.position)
+    }
+
+    /// Consumes the next call if `accept` returns a value for it.
+    fn expect<T>(
+        &mut self,
+        requested: impl fmt::Display,
+        accept: impl FnOnce(&'de Call) -> Option<T>,
+    ) -> Result<T, TraceError> {
+        let recorded = self.peek();
+        match recorded.and_then(accept) {
+            Some(value) => {
+                self.position += 1;
+                Ok(value)
+            }
+            None => Err(TraceError::Mismatch {
+                position: self.position,
+                requested: requested.to_string(),
+                recorded: recorded.cloned(),
+            }),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), TraceError> {
+        self.expect("the end of the compound value", |call| {
+            (*call == Call::End).then_some(())
+        })
+    }
+
+    fn unsupported<T>(&self, requested: &'static str) -> Result<T, TraceError> {
+        Err(TraceError::Unsupported {
+            position: self.position,
+            requested,
+        })
+    }
+
+    /// Visits a sequence with `len` elements, or elements up to the next `Call::End`.
+    fn visit_elements<V: Visitor<'de>>(
+        &mut self,
+        len: Option<usize>,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let value = visitor.visit_seq(Elements {
+            deserializer: self,
+            remaining: len,
+        })?;
+        self.expect_end()?;
+        Ok(value)
+    }
+
+    /// Visits the fields of a struct, checking their names against `fields`.
+    fn visit_fields<V: Visitor<'de>>(
+        &mut self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let value = visitor.visit_seq(Fields {
+            deserializer: self,
+            fields,
+            index: 0,
+        })?;
+        self.expect_end()?;
+        Ok(value)
+    }
+}
+
+macro_rules! replay_scalars {
+    ($($method:ident => $call:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                let value = self.expect(stringify!($method), |call| match call {
+                    Call::$call(value) => Some(*value),
+                    _ => None,
+                })?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut ReplayDeserializer<'de> {
+    type Error = TraceError;
+
+    replay_scalars!(
+        deserialize_bool => Bool => visit_bool,
+        deserialize_i8 => I8 => visit_i8,
+        deserialize_i16 => I16 => visit_i16,
+        deserialize_i32 => I32 => visit_i32,
+        deserialize_i64 => I64 => visit_i64,
+        deserialize_i128 => I128 => visit_i128,
+        deserialize_u8 => U8 => visit_u8,
+        deserialize_u16 => U16 => visit_u16,
+        deserialize_u32 => U32 => visit_u32,
+        deserialize_u64 => U64 => visit_u64,
+        deserialize_u128 => U128 => visit_u128,
+        deserialize_f32 => F32 => visit_f32,
+        deserialize_f64 => F64 => visit_f64,
+        deserialize_char => Char => visit_char,
+    );
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        self.unsupported("deserialize_any")
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let value = self.expect("deserialize_str", |call| match call {
+            Call::Str(value) => Some(value.as_str()),
+            _ => None,
+        })?;
+        visitor.visit_borrowed_str(value)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let bytes = self.expect("deserialize_bytes", |call| match call {
+            Call::Bytes(bytes) => Some(bytes.as_slice()),
+            _ => None,
+        })?;
+        visitor.visit_borrowed_bytes(bytes)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let some = self.expect("deserialize_option", |call| match call {
+            Call::None => Some(false),
+            Call::Some => Some(true),
+            _ => None,
+        })?;
+        if some {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.expect("deserialize_unit", |call| {
+            (*call == Call::Unit).then_some(())
+        })?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.expect(format!("deserialize_unit_struct({name:?})"), |call| {
+            (*call == Call::UnitStruct(name)).then_some(())
+        })?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.expect(format!("deserialize_newtype_struct({name:?})"), |call| {
+            (*call == Call::NewtypeStruct(name)).then_some(())
+        })?;
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let len = self.expect("deserialize_seq", |call| match call {
+            Call::Seq(len) => Some(*len),
+            _ => None,
+        })?;
+        self.visit_elements(len, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.expect(format!("deserialize_tuple({len})"), |call| {
+            (*call == Call::Tuple(len)).then_some(())
+        })?;
+        self.visit_elements(Some(len), visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.expect(
+            format!("deserialize_tuple_struct({name:?}, {len})"),
+            |call| (*call == Call::TupleStruct { name, len }).then_some(()),
+        )?;
+        self.visit_elements(Some(len), visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let len = self.expect("deserialize_map", |call| match call {
+            Call::Map(len) => Some(*len),
+            _ => None,
+        })?;
+        let value = visitor.visit_map(Elements {
+            deserializer: &mut *self,
+            remaining: len,
+        })?;
+        self.expect_end()?;
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.expect(
+            format!("deserialize_struct({name:?}, {} fields)", fields.len()),
+            |call| match call {
+                Call::Struct {
+                    name: recorded,
+                    len,
+                } if *recorded == name && *len == fields.len() => Some(()),
+                _ => None,
+            },
+        )?;
+        self.visit_fields(fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let variant = self.expect(format!("deserialize_enum({name:?})"), |call| match call {
+            Call::UnitVariant {
+                name: recorded,
+                index,
+                ..
+            }
+            | Call::NewtypeVariant {
+                name: recorded,
+                index,
+                ..
+            }
+            | Call::TupleVariant {
+                name: recorded,
+                index,
+                ..
+            }
+            | Call::StructVariant {
+                name: recorded,
+                index,
+                ..
+            } if *recorded == name => Some((call, *index)),
+            _ => None,
+        })?;
+        visitor.visit_enum(Variant {
+            deserializer: self,
+            call: variant.0,
+            index: variant.1,
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        self.unsupported("deserialize_identifier")
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        self.unsupported("deserialize_ignored_any")
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.human_readable
+    }
+}
+
+/// The elements of a sequence, tuple or map.
+struct Elements<'a, 'de> {
+    deserializer: &'a mut ReplayDeserializer<'de>,
+    /// `None` if the serializer did not announce a length, the elements then end at `Call::End`.
+    remaining: Option<usize>,
+}
+
+impl Elements<'_, '_> {
+    fn has_next(&mut self) -> bool {
+        match &mut self.remaining {
+            Some(0) => false,
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            }
+            None => self.deserializer.peek() != Some(&Call::End),
+        }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        if !self.has_next() {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.deserializer).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        if !self.has_next() {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.deserializer).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TraceError> {
+        seed.deserialize(&mut *self.deserializer)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+/// The fields of a struct or struct variant, each preceded by `Call::Field`.
+struct Fields<'a, 'de> {
+    deserializer: &'a mut ReplayDeserializer<'de>,
+    fields: &'static [&'static str],
+    index: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        let Some(&field) = self.fields.get(self.index) else {
+            return Ok(None);
+        };
+        self.index += 1;
+        self.deserializer
+            .expect(format!("field {field:?}"), |call| {
+                (*call == Call::Field(field)).then_some(())
+            })?;
+        seed.deserialize(&mut *self.deserializer).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.fields.len() - self.index)
+    }
+}
+
+/// An enum variant, `call` is the already consumed call that started it.
+struct Variant<'a, 'de> {
+    deserializer: &'a mut ReplayDeserializer<'de>,
+    call: &'de Call,
+    index: u32,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant<'_, 'de> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), TraceError> {
+        let index: de::value::U32Deserializer<TraceError> = self.index.into_deserializer();
+        let value = seed.deserialize(index)?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> Variant<'_, 'de> {
+    fn mismatch(&self, requested: &str) -> TraceError {
+        TraceError::Mismatch {
+            position: self.deserializer.position - 1,
+            requested: requested.to_string(),
+            recorded: Some(self.call.clone()),
+        }
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_, 'de> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        match self.call {
+            Call::UnitVariant { .. } => Ok(()),
+            _ => Err(self.mismatch("a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, TraceError> {
+        match self.call {
+            Call::NewtypeVariant { .. } => seed.deserialize(self.deserializer),
+            _ => Err(self.mismatch("a newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        match self.call {
+            Call::TupleVariant { len: recorded, .. } if *recorded == len => {
+                self.deserializer.visit_elements(Some(len), visitor)
+            }
+            _ => Err(self.mismatch(&format!("a tuple variant of {len} fields"))),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        match self.call {
+            Call::StructVariant { len, .. } if *len == fields.len() => {
+                self.deserializer.visit_fields(fields, visitor)
+            }
+            _ => Err(self.mismatch(&format!("a struct variant of {} fields", fields.len()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_span, Span, TraceId};
+
+    /// The base64 of 16 bytes of 1.
+    const ONES_BASE64: &str = "AQEBAQEBAQEBAQEBAQEBAQ==";
+
+    fn span_calls(trace_id: Call) -> Vec<Call> {
+        vec![
+            Call::Struct {
+                name: "Span",
+                len: 2,
+            },
+            Call::Field("trace_id"),
+            trace_id,
+            Call::Field("span_timestamp"),
+            Call::I64(-300),
+            Call::End,
+        ]
+    }
+
+    #[test]
+    fn span_records_bytes() {
+        let span = test_span(1, -300);
+        assert_eq!(record(&span), Ok(span_calls(Call::Bytes(vec![1; 16]))));
+        assert_eq!(record(&span.trace_id), Ok(vec![Call::Bytes(vec![1; 16])]));
+        assert_eq!(
+            check_shapes(&span),
+            Ok(span_calls(Call::Bytes(vec![1; 16])))
+        );
+    }
+
+    #[test]
+    fn span_records_str_when_human_readable() {
+        let span = test_span(1, -300);
+        let calls = record_human_readable(&span).unwrap();
+        assert_eq!(calls, span_calls(Call::Str(ONES_BASE64.to_string())));
+        assert_eq!(
+            record_human_readable(&span.trace_id),
+            Ok(vec![Call::Str(ONES_BASE64.to_string())])
+        );
+        assert_eq!(
+            ReplayDeserializer::human_readable(&calls).replay::<Span>(),
+            Ok(())
+        );
+        // A binary deserializer requests bytes where the human-readable trace has a string.
+        assert_eq!(
+            ReplayDeserializer::new(&calls).replay::<Span>(),
+            Err(TraceError::Mismatch {
+                position: 2,
+                requested: "deserialize_bytes".to_string(),
+                recorded: Some(Call::Str(ONES_BASE64.to_string())),
+            })
+        );
+    }
+
+    #[test]
+    fn trace_id_replays_from_both_forms() {
+        let trace_id = TraceId([1; 16]);
+        let bytes = record(&trace_id).unwrap();
+        let mut deserializer = ReplayDeserializer::new(&bytes);
+        assert_eq!(
+            de::Deserialize::deserialize(&mut deserializer),
+            Ok(trace_id)
+        );
+        assert_eq!(deserializer.finish(), Ok(()));
+        let str = record_human_readable(&trace_id).unwrap();
+        let mut deserializer = ReplayDeserializer::human_readable(&str);
+        assert_eq!(
+            de::Deserialize::deserialize(&mut deserializer),
+            Ok(trace_id)
+        );
+        assert_eq!(deserializer.finish(), Ok(()));
+    }
+
+    #[test]
+    fn u32_replayed_as_u64() {
+        let calls = record(&7u32).unwrap();
+        assert_eq!(calls, [Call::U32(7)]);
+        let error = ReplayDeserializer::new(&calls).replay::<u64>().unwrap_err();
+        assert_eq!(
+            error,
+            TraceError::Mismatch {
+                position: 0,
+                requested: "deserialize_u64".to_string(),
+                recorded: Some(Call::U32(7)),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "call 0: the deserializer requested deserialize_u64, the serializer emitted \
+             serialize_u32(7)"
+        );
+    }
+
+    #[test]
+    fn deserialize_any_is_unsupported() {
+        let calls = record(&7u32).unwrap();
+        assert_eq!(
+            ReplayDeserializer::new(&calls).replay::<serde_json::Value>(),
+            Err(TraceError::Unsupported {
+                position: 0,
+                requested: "deserialize_any",
+            })
+        );
+    }
+
+    #[test]
+    fn trailing_calls_are_reported() {
+        let calls = record(&(7u32, 8u32)).unwrap();
+        let trailing = &calls[1..];
+        assert_eq!(trailing, [Call::U32(7), Call::U32(8), Call::End]);
+        assert_eq!(
+            ReplayDeserializer::new(trailing).replay::<u32>(),
+            Err(TraceError::TrailingCalls { position: 1 })
+        );
+    }
+
+    #[test]
+    fn trace_of_span_batch() {
+        let calls = record(&vec![test_span(1, -300)]).unwrap();
+        assert_eq!(
+            format_trace(&calls),
+            "serialize_seq(Some(1))
+  serialize_struct(\"Span\", 2)
+    field \"trace_id\"
+    serialize_bytes(16 bytes: [01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01, 01])
+    field \"span_timestamp\"
+    serialize_i64(-300)
+  end
+end
+"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Helpers for postcard's LEB128-style varints.
+
+/// Maximum number of bytes of a varint encoded `u64`.
+pub const MAX_LEN: usize = 10;
+
+/// Reads the varint at the start of `bytes`, returning its value and encoded length.
+///
+/// Returns `None` if the varint does not terminate within `MAX_LEN` bytes or, like postcard,
+/// if its last byte sets bits beyond the 64th.
+pub fn read(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (position, &byte) in bytes.iter().enumerate().take(MAX_LEN) {
+        if position == MAX_LEN - 1 && byte > 1 {
+            return None;
+        }
+        value |= u64::from(byte & 0x7f) << (7 * position);
+        if byte & 0x80 == 0 {
+            return Some((value, position + 1));
+        }
+    }
+    None
+}
+
+pub fn write(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAX_LEN);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Number of bytes of the shortest varint encoding of `value`.
+pub fn encoded_len(value: u64) -> usize {
+    let bits = u64::BITS - value.leading_zeros();
+    (bits.max(1) as usize).div_ceil(7)
+}
+
+/// Encodes `value` with one more byte than necessary, if that still fits in `MAX_LEN`.
+pub fn write_overlong(value: u64) -> Option<Vec<u8>> {
+    let mut bytes = write(value);
+    if bytes.len() == MAX_LEN {
+        return None;
+    }
+    *bytes.last_mut()? |= 0x80;
+    bytes.push(0);
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values with the lengths of their shortest encoding, around every length boundary.
+    const LENGTHS: [(u64, usize); 12] = [
+        (0, 1),
+        (1, 1),
+        (127, 1),
+        (128, 2),
+        (16383, 2),
+        (16384, 3),
+        ((1 << 21) - 1, 3),
+        (1 << 21, 4),
+        ((1 << 56) - 1, 8),
+        ((1 << 63) - 1, 9),
+        (1 << 63, 10),
+        (u64::MAX, 10),
+    ];
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(write(0), [0x00]);
+        assert_eq!(write(1), [0x01]);
+        assert_eq!(write(127), [0x7f]);
+        assert_eq!(write(128), [0x80, 0x01]);
+        assert_eq!(write(300), [0xac, 0x02]);
+        assert_eq!(write(16383), [0xff, 0x7f]);
+        assert_eq!(write(16384), [0x80, 0x80, 0x01]);
+        assert_eq!(
+            write(u64::MAX),
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn matches_postcard() {
+        for (value, _) in LENGTHS {
+            assert_eq!(write(value), postcard::to_allocvec(&value).unwrap());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for (value, len) in LENGTHS {
+            let bytes = write(value);
+            assert_eq!(bytes.len(), len, "{value}");
+            assert_eq!(encoded_len(value), len, "{value}");
+            assert_eq!(read(&bytes), Some((value, len)), "{value}");
+        }
+    }
+
+    #[test]
+    fn read_ignores_what_follows() {
+        assert_eq!(read(&[0x80, 0x01, 0xff, 0x00]), Some((128, 2)));
+        assert_eq!(read(&[0x05, 0x80]), Some((5, 1)));
+    }
+
+    #[test]
+    fn read_malformed() {
+        assert_eq!(read(&[]), None);
+        // Unterminated.
+        assert_eq!(read(&[0x80]), None);
+        assert_eq!(read(&[0xff, 0xff]), None);
+        // Too long, even though the 11th byte would terminate it.
+        assert_eq!(read(&[0x80; 10]), None);
+        let mut eleven = [0x80; 11];
+        eleven[10] = 0x00;
+        assert_eq!(read(&eleven), None);
+        // The 10th byte may only set the 64th bit.
+        let mut overflowing = [0xff; 10];
+        overflowing[9] = 0x02;
+        assert_eq!(read(&overflowing), None);
+        overflowing[9] = 0x7f;
+        assert_eq!(read(&overflowing), None);
+        assert!(postcard::from_bytes::<u64>(&overflowing).is_err());
+    }
+
+    #[test]
+    fn read_overlong() {
+        // Accepted, but longer than `encoded_len`.
+        assert_eq!(read(&[0x80, 0x00]), Some((0, 2)));
+        assert_eq!(read(&[0xff, 0x80, 0x00]), Some((127, 3)));
+    }
+
+    #[test]
+    fn write_overlong_adds_one_byte() {
+        assert_eq!(write_overlong(0), Some(vec![0x80, 0x00]));
+        assert_eq!(write_overlong(127), Some(vec![0xff, 0x00]));
+        assert_eq!(write_overlong(128), Some(vec![0x80, 0x81, 0x00]));
+        for (value, len) in LENGTHS {
+            let Some(bytes) = write_overlong(value) else {
+                assert_eq!(len, MAX_LEN, "{value}");
+                continue;
+            };
+            assert_eq!(bytes.len(), len + 1, "{value}");
+            assert_eq!(read(&bytes), Some((value, len + 1)), "{value}");
+            assert_eq!(postcard::from_bytes::<u64>(&bytes), Ok(value), "{value}");
+        }
+    }
+
+    #[test]
+    fn write_overlong_of_ten_bytes() {
+        assert_eq!(write_overlong(1 << 63), None);
+        assert_eq!(write_overlong(u64::MAX), None);
+        assert_eq!(
+            write_overlong((1 << 63) - 1).map(|bytes| bytes.len()),
+            Some(10)
+        );
+    }
+}